@@ -42,7 +42,43 @@ fn main() {
         .optopt("i", "ignore", 
         "Path to the ignore.txt file. (Default: .\\ignore.txt)", "IGNORE_FILE")
         .optflag("", "no-config", 
-        "Don't read config files on start. Will use the given paths, or soundsense-rs defaults.");
+        "Don't read config files on start. Will use the given paths, or soundsense-rs defaults.")
+        .optflagopt("", "export-pack",
+        "Convert the soundpack's XML files into TOML (default) or JSON, then quit.", "FORMAT")
+        .optopt("", "device",
+        "Name of the audio output device to play sounds on. (Default: the system's default device)", "DEVICE")
+        .optflag("", "list-devices",
+        "List the audio output devices, then quit.")
+        .optopt("", "sample-cache",
+        "Size of the cache of decoded short sounds, in megabytes. 0 disables it. (Default: 64)", "MB")
+        .optflag("", "prewarm",
+        "Decode the soundpack's short sounds into the cache when it's loaded.")
+        .optflagopt("", "normalize",
+        "Analyse the soundpack's files and play them at the same loudness. (Default: -18 LUFS)", "LUFS")
+        .optopt("", "render",
+        "Render the whole gamelog into a WAV or FLAC file, as fast as possible, then quit.", "OUT_FILE")
+        .optopt("", "render-interval",
+        "Simulated time between two gamelog lines when rendering, in miliseconds. (Default: 1000)", "MS")
+        .optopt("", "render-tail",
+        "Maximum time rendered after the last gamelog line, in miliseconds. (Default: 10000)", "MS")
+        .optopt("", "record",
+        "Record everything played into a WAV or FLAC file.", "OUT_FILE")
+        .optopt("", "backend",
+        "Where sounds are played: device, or null (played but not heard). (Default: device)", "BACKEND")
+        .optopt("", "stereo-width",
+        "Width of the stereo image, from 0 (mono) to 200 percent. (Default: 100)", "PERCENT")
+        .optflag("", "mono",
+        "Downmix everything to mono, played on both sides.")
+        .optflag("", "swap-channels",
+        "Swap the left and right sides.")
+        .optopt("", "limiter-ceiling",
+        "Highest peak level of the output, in dBFS. (Default: -1)", "DB")
+        .optflag("", "no-limiter",
+        "Don't limit the output's peaks. Loud moments may clip.")
+        .optopt("", "max-voices",
+        "Most oneshots played at once, over every channel. 0 is unlimited. (Default: 32)", "COUNT")
+        .optopt("", "seed",
+        "Seed of the random choices, so they're the same every time. Useful when rendering.", "SEED");
 
     // If there are errors in the arguments, print the usage of SoundSense-RS and quit.
     let matches = match opts.parse(&args[1..]) {
@@ -54,6 +90,14 @@ fn main() {
         }
     };
 
+    // List the audio output devices instead of starting SoundSense-RS.
+    if matches.opt_present("list-devices") {
+        for name in sound::output_device_names().iter() {
+            println!("{}", name);
+        }
+        return
+    }
+
     // Check if there are config files available.
     // If so, read `soundsense-rs/default-paths.ini`.
     let config = if !matches.opt_present("no-config") {
@@ -158,13 +202,213 @@ fn main() {
             if path.is_file() {Some(path)} else {None}
         });
 
+    // Convert the soundpack instead of starting SoundSense-RS.
+    if matches.opt_present("export-pack") {
+        let format_name = matches.opt_str("export-pack")
+            .unwrap_or_else(|| "toml".to_string());
+        let format = match sound::PackFormat::from_name(&format_name) {
+            Some(format) => format,
+            None => {
+                error!("Unknown soundpack format: {}", format_name);
+                return
+            }
+        };
+        match soundpack_path {
+            Some(path) => match sound::export_soundpack(&path, format) {
+                Ok(count) => println!("Exported {} file(s) in {}", count, path.display()),
+                Err(e) => error!("Failed to export soundpack: {}", e),
+            }
+            None => error!("No soundpack to export."),
+        }
+        return
+    }
+
+    // If a device is given, use it.
+    // Else if the device config file contains a device, use that.
+    // Otherwise, use the system's default device.
+    let device = matches.opt_str("device")
+        .or_else(||
+            if !matches.opt_present("no-config") {
+                dirs::config_dir()
+                    .map(|mut p| {
+                        p.push("soundsense-rs/default-device.ini");
+                        debug!("Checking for default-device config in: {}", p.display());
+                        p
+                    })
+                    .and_then(|p| std::fs::read_to_string(p).ok())
+                    .and_then(|config_txt|
+                        Regex::new("device=(.+)").unwrap()
+                            .captures(&config_txt)
+                            .and_then(|c| c.get(1))
+                            .map(|m| m.as_str().trim().to_string())
+                    )
+            } else {None}
+        )
+        .map(String::into_boxed_str);
+    let normalize = match matches.opt_str("normalize").map(|t| t.parse::<f32>()) {
+        _ if !matches.opt_present("normalize") => None,
+        None => Some(sound::DEFAULT_LOUDNESS_TARGET),
+        Some(Ok(target)) => Some(target),
+        Some(Err(e)) => {
+            error!("Invalid loudness target: {}", e);
+            return
+        }
+    };
+    let sample_cache = match matches.opt_str("sample-cache").map(|s| s.parse::<usize>()) {
+        None => sound::DEFAULT_SAMPLE_CACHE_SIZE,
+        Some(Ok(size)) => size,
+        Some(Err(e)) => {
+            error!("Invalid sample cache size: {}", e);
+            return
+        }
+    };
+    let backend = match matches.opt_str("backend") {
+        None => sound::AudioBackend::default(),
+        Some(name) => match sound::AudioBackend::from_name(&name) {
+            Some(backend) => backend,
+            None => {
+                error!("Unknown audio backend: {}", name);
+                return
+            }
+        }
+    };
+    // Command line stereo settings override the ones in the stereo config file.
+    let stereo_config = if !matches.opt_present("no-config") {
+        dirs::config_dir()
+            .map(|mut p| {
+                p.push("soundsense-rs/stereo.ini");
+                debug!("Checking for stereo config in: {}", p.display());
+                p
+            })
+            .and_then(|p| std::fs::read_to_string(p).ok())
+    } else {None};
+    let stereo_value = |name: &str| stereo_config.as_ref()
+        .and_then(|config_txt|
+            Regex::new(&format!("{}=(.+)", name)).unwrap()
+                .captures(config_txt)
+                .and_then(|c| c.get(1))
+                .map(|m| m.as_str().trim().to_string())
+        );
+    let width = match matches.opt_str("stereo-width")
+        .or_else(|| stereo_value("width"))
+        .map(|w| w.parse::<f32>())
+    {
+        None => sound::DEFAULT_STEREO_WIDTH,
+        Some(Ok(width)) if (0.0..=200.0).contains(&width) => width,
+        Some(Ok(width)) => {
+            error!("Invalid stereo width: {} is not between 0 and 200", width);
+            return
+        }
+        Some(Err(e)) => {
+            error!("Invalid stereo width: {}", e);
+            return
+        }
+    };
+    let stereo = sound::StereoSettings {
+        width: width * 0.01,
+        mono: matches.opt_present("mono")
+            || stereo_value("mono").as_deref() == Some("true"),
+        swap: matches.opt_present("swap-channels")
+            || stereo_value("swap").as_deref() == Some("true"),
+    };
+    let seed = match matches.opt_str("seed").map(|s| s.parse::<u64>()) {
+        None => None,
+        Some(Ok(seed)) => Some(seed),
+        Some(Err(e)) => {
+            error!("Invalid seed: {}", e);
+            return
+        }
+    };
+    let limiter = match matches.opt_str("limiter-ceiling").map(|c| c.parse::<f32>()) {
+        _ if matches.opt_present("no-limiter") => None,
+        None => Some(sound::DEFAULT_LIMITER_CEILING),
+        Some(Ok(ceiling)) if ceiling <= 0.0 => Some(ceiling),
+        Some(Ok(ceiling)) => {
+            error!("Invalid limiter ceiling: {} is above 0 dBFS", ceiling);
+            return
+        }
+        Some(Err(e)) => {
+            error!("Invalid limiter ceiling: {}", e);
+            return
+        }
+    };
+    let max_voices = match matches.opt_str("max-voices").map(|m| m.parse::<usize>()) {
+        None => sound::DEFAULT_MAX_VOICES,
+        Some(Ok(max_voices)) => max_voices,
+        Some(Err(e)) => {
+            error!("Invalid voice limit: {}", e);
+            return
+        }
+    };
+    let mut sound_options = sound::SoundOptions {
+        normalize,
+        device,
+        sample_cache,
+        prewarm: matches.opt_present("prewarm"),
+        recorder: None,
+        backend,
+        stereo,
+        seed,
+        limiter,
+        max_voices,
+    };
+
+    // Render the gamelog instead of starting SoundSense-RS.
+    if let Some(output) = matches.opt_str("render") {
+        let mut render_options = sound::RenderOptions::default();
+        for (name, value) in [
+            ("render-interval", &mut render_options.interval),
+            ("render-tail", &mut render_options.tail),
+        ].iter_mut() {
+            match matches.opt_str(name).map(|s| s.parse::<usize>()) {
+                None => (),
+                Some(Ok(ms)) => **value = ms,
+                Some(Err(e)) => {
+                    error!("Invalid {} length: {}", name, e);
+                    return
+                }
+            }
+        }
+        let (soundpack_path, gamelog_path) = match (soundpack_path, gamelog_path) {
+            (Some(soundpack_path), Some(gamelog_path)) => (soundpack_path, gamelog_path),
+            _ => {
+                error!("Rendering needs both a soundpack and a gamelog.");
+                return
+            }
+        };
+        let output = PathBuf::from(output);
+        match sound::render(
+            &soundpack_path,
+            &gamelog_path,
+            ignore_path.as_deref(),
+            &output,
+            &sound_options,
+            render_options,
+        ) {
+            Ok(length) => println!("Rendered {:.1} seconds into {}", length as f32 / 1000.0, output.display()),
+            Err(e) => error!("Failed to render the gamelog: {}", e),
+        }
+        return
+    }
+
+    // Record everything played, while playing normally.
+    if let Some(output) = matches.opt_str("record") {
+        match sound::Recorder::start(&PathBuf::from(output)) {
+            Ok(recorder) => sound_options.recorder = Some(recorder),
+            Err(e) => {
+                error!("Failed to start recording: {}", e);
+                return
+            }
+        }
+    }
+
     let (sound_tx, sound_rx) = channel();
     let (ui_tx, ui_rx) = channel();
     
     // Build and spawn the Sound thread.
     std::thread::Builder::new()
         .name("sound_thread".to_string())
-        .spawn(move || sound::run(sound_rx, ui_tx)).unwrap();
+        .spawn(move || sound::run(sound_rx, ui_tx, sound_options)).unwrap();
     // Start the UI thread.
     ui::run(sound_tx, ui_rx, gamelog_path, soundpack_path, ignore_path);
 }
@@ -19,6 +19,16 @@ pub enum SoundMessage {
     PlayPause(Box<str>),
     /// Store the current channels volumes to a config file.
     SetCurrentVolumesAsDefault(std::fs::File),
+    /// Play sounds on the output device with this name.
+    /// None is the system's default device.
+    ChangeDevice(Option<Box<str>>),
+    /// Route a channel to the output device with this name.
+    /// None plays the channel on the same device as the others.
+    ChangeChannelDevice(Box<str>, Option<Box<str>>),
+    /// Change the stereo width, mono downmix and left/right swap of the whole mix.
+    ChangeStereo(crate::sound::StereoSettings),
+    /// Change the filters, equalizer and reverb of a channel.
+    ChangeChannelEffects(Box<str>, crate::sound::EffectSettings),
 }
 
 /// Message sent from the Sound thread to the UI thread.
@@ -35,6 +45,32 @@ pub enum UIMessage {
     LoadedVolumeSettings(Vec<(Box<str>,f32)>),
     /// The Channel IsPause had been set.
     ChannelWasPlayPaused(Box<str>, bool),
+    /// The channel's loop started playing a new sound.
+    /// Contains the channel name, and the sound's title (empty if nothing is playing).
+    NowPlaying(Box<str>, Box<str>),
+    /// The season, year, game mode or siege changed.
+    /// Contains display names and values.
+    GameContextChanged(Vec<(Box<str>, Box<str>)>),
+    /// The soundpack's variables changed.
+    /// Contains every variable's name and current value.
+    VariablesChanged(Vec<(Box<str>, i64)>),
+    /// The audio output devices available.
+    /// Contains the devices' names, and the chosen one (None if using the default device).
+    OutputDevices(Vec<Box<str>>, Option<Box<str>>),
+    /// The output device playing sounds stopped, for example because it was unplugged.
+    /// Contains its name, and the name of the device sounds moved to (None if no device could be opened).
+    OutputDeviceLost(Box<str>, Option<Box<str>>),
+    /// The output device each channel is routed to (None if it uses the same device as the others).
+    ChannelDevices(Vec<(Box<str>, Option<Box<str>>)>),
+    /// The filters, equalizer and reverb of each channel.
+    ChannelEffects(Vec<(Box<str>, crate::sound::EffectSettings)>),
+    /// The sample cache's statistics changed.
+    /// Contains display names and values.
+    SampleCacheStats(Vec<(Box<str>, Box<str>)>),
+    /// The stereo width, mono downmix and left/right swap in use.
+    StereoChanged(crate::sound::StereoSettings),
+    /// Gain reduction applied by the master limiter recently, in decibels. 0 if nothing was reduced.
+    LimiterReduction(f32),
     /// There was an error in the Sound thread.
     SoundThreadPanicked(String,String),
 }
\ No newline at end of file
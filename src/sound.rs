@@ -2,10 +2,10 @@ use std::time::{Instant, Duration};
 use std::fs::{self, File};
 use std::io::{Read, Seek, SeekFrom, BufReader, BufRead};
 use std::path::{Path, PathBuf};
-use std::collections::{BTreeMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{
     Arc,
-    atomic::{AtomicBool, AtomicUsize, Ordering}
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering}
 };
 use std::error::Error;
 
@@ -22,6 +22,37 @@ use regex::Regex;
 
 mod sound_manager; use sound_manager::SoundManager;
 mod sound_channel; use sound_channel::SoundChannel;
+mod soundpack; use soundpack::*;
+mod playlist; use playlist::*;
+mod state; use state::*;
+mod context; use context::*;
+mod fade; use fade::*;
+mod duck; use duck::*;
+mod loudness; use loudness::*;
+mod device; use device::*;
+mod sample_cache; use sample_cache::*;
+mod mixer; use mixer::*;
+mod audio_file; use audio_file::*;
+mod render;
+mod backend; use backend::*;
+mod output; use output::*;
+mod limiter; use limiter::*;
+mod effects; use effects::*;
+mod panner; use panner::*;
+mod loop_points; use loop_points::*;
+mod voices; use voices::*;
+#[cfg(test)]
+mod test_util;
+pub use soundpack::{PackFormat, export_soundpack};
+pub use loudness::DEFAULT_LOUDNESS_TARGET;
+pub use device::output_device_names;
+pub use sample_cache::DEFAULT_SAMPLE_CACHE_SIZE;
+pub use render::{render, RenderOptions, Recorder};
+pub use backend::AudioBackend;
+pub use panner::{StereoSettings, DEFAULT_STEREO_WIDTH};
+pub use limiter::DEFAULT_LIMITER_CEILING;
+pub use effects::EffectSettings;
+pub use voices::DEFAULT_MAX_VOICES;
 
 pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
@@ -40,8 +71,8 @@ lazy_static! {
 pub enum SoundFileType {
     /// Contains a single file path.
     IsPath(PathBuf),
-    /// Contains multiple file paths.
-    IsPlaylist(Vec<PathBuf>)
+    /// Contains multiple playlist entries.
+    IsPlaylist(Vec<PlaylistEntry>)
 }
 
 /// A struct containing all the information about a SoundFile.
@@ -51,7 +82,8 @@ pub struct SoundFile {
     pub r#type: SoundFileType,
     /// Controls likelihood of sound to be chosen. Default is 100.
     pub weight: f32,
-    /// Adjusts volume of sample. Can range from -40 to +6 decibles, default 0.
+    /// Volume multiplier, converted from the file's volumeAdjustment.
+    /// volumeAdjustment can range from -40 to +6 decibels, default 0.
     pub volume: f32,
     /// If set to true will randomply distribute sound between stereo channels.
     pub random_balance: bool,
@@ -59,6 +91,83 @@ pub struct SoundFile {
     pub delay: usize,
     /// Adjusts stereo channel, can range for -1 (full left) to 1 (full right).
     pub balance: f32,
+    /// Semitones the pitch varies by, either way, every time the file is played. Default 0.
+    pub random_pitch: f32,
+    /// Decibels the volume varies by, either way, every time the file is played. Default 0.
+    pub random_volume: f32,
+    /// Start of the section repeated when the file loops. Default is the start of the file.
+    pub loop_start: Option<LoopPoint>,
+    /// End of the section repeated when the file loops. Default is the end of the file.
+    pub loop_end: Option<LoopPoint>,
+    /// File played once before the file, when it starts looping.
+    pub intro: Option<PathBuf>,
+    /// File played after the file, when its loop is stopped.
+    pub outro: Option<PathBuf>,
+    /// The file can only be chosen while this is true.
+    pub condition: Option<Condition>,
+    /// The file can only be chosen in these seasons and game modes.
+    pub context_filter: ContextFilter,
+}
+
+impl SoundFile {
+    /// The balance to play the file at: random if randomBalance is set, balanceAdjustment otherwise.
+    #[inline]
+    pub fn pick_balance<R: Rng>(&self, rng: &mut R) -> f32 {
+        if self.random_balance {
+            rng.gen_range(-1.0, 1.0)
+        } else {
+            self.balance
+        }
+    }
+
+    /// Whether the file repeats a section of itself when looping, instead of moving on to the next file.
+    #[inline]
+    pub fn has_loop_points(&self) -> bool {
+        self.loop_start.is_some() || self.loop_end.is_some()
+    }
+
+    /// The speed to play the file at, changing its pitch by up to randomPitch semitones.
+    #[inline]
+    pub fn pick_pitch<R: Rng>(&self, rng: &mut R) -> f32 {
+        if self.random_pitch > 0.0 {
+            2f32.powf(rng.gen_range(-self.random_pitch, self.random_pitch) / 12.0)
+        } else {
+            1.0
+        }
+    }
+
+    /// The gain to play the file at, changing its volume by up to randomVolume decibels.
+    #[inline]
+    pub fn pick_gain<R: Rng>(&self, rng: &mut R) -> f32 {
+        if self.random_volume > 0.0 {
+            db_to_gain(rng.gen_range(-self.random_volume, self.random_volume))
+        } else {
+            1.0
+        }
+    }
+
+    /// Whether the file can be chosen, according to its condition, season and game mode.
+    #[inline]
+    pub fn is_available(&self, variables: &Variables) -> bool {
+        self.context_filter.matches(variables)
+        && match &self.condition {
+            Some(condition) => condition.eval(variables),
+            None => true,
+        }
+    }
+}
+
+/// One step of a sequence: a SoundFile chosen among the step's files, played after a gap.
+#[derive(Clone)]
+pub struct SequenceStep {
+    /// Silence before the step, in miliseconds. Default 0.
+    pub gap: usize,
+    /// percentage, Probability that the step is played. A skipped step doesn't add its gap.
+    pub probability: Option<usize>,
+    /// The files to choose from.
+    pub files: Vec<SoundFile>,
+    /// Each file's weight.
+    pub weights: Vec<f32>,
 }
 
 /// A thread-safe wrapper around a volume(f32) volume.
@@ -111,12 +220,19 @@ pub struct SoundEntry {
     /// number of councured sounds allowed to be played besides this sound.
     /// If currenty playing more than that, sound is ignored. In miliseconds, default unlimited.
     pub concurency: Option<usize>,
+    /// number, instances of this sound allowed to play at once, whatever else plays on the channel.
+    /// Default unlimited.
+    pub max_instances: Option<usize>,
     /// number, timeout during which is sound going to be prevented from playing again. In miliseconds default 0.
     pub timeout: Option<usize>,
     /// percentage, Propablity that sound will be played. Default is always played.
     pub probability: Option<usize>,
     /// number, delay before sound is played. In miliseconds, default 0.
     pub delay: Option<usize>,
+    /// number, length of the crossfade when this sound starts a loop. In miliseconds, default is the channel's.
+    pub crossfade: Option<usize>,
+    /// number, importance of the sound when voices are stolen. Higher wins, default 0.
+    pub priority: i32,
     /// boolean, if set to true, sound sense will stop processing long line after it was matched to this sound.
     /// Default false
     pub halt_on_match: bool,
@@ -124,18 +240,31 @@ pub struct SoundEntry {
     pub random_balance: bool,
     /// number, threashold used when filtering sound depending on level (currently not used)
     pub playback_threshold: u8,
+    /// The sound is ignored unless this is true.
+    pub condition: Option<Condition>,
+    /// The sound is ignored outside of these seasons and game modes.
+    pub context_filter: ContextFilter,
+    /// Changes to variables, applied whenever the sound matches.
+    pub actions: Vec<VarAction>,
+    /// Changes to channels' effects, applied whenever the sound matches.
+    pub effects: Vec<EffectChange>,
     /// Collection of SoundFiles
     pub files: Vec<SoundFile>,
     /// Collection of each SoundFile's weight.
     pub weights: Vec<f32>,
+    /// Steps played one after the other, as a single sound. Used instead of `files` if not empty.
+    pub sequence: Vec<SequenceStep>,
     /// Timeout. While timeout, can't be played.
     pub current_timeout: usize,
     /// Number of times this SoundEntry has been called.
     pub recent_call: usize,
+    /// Oneshots of this sound playing.
+    pub instances: Instances,
 }
 
 #[non_exhaustive]
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all="camelCase")]
 pub enum ChannelPlayType {
     All,
     SingleEager,
@@ -144,11 +273,81 @@ pub enum ChannelPlayType {
 
 pub struct ChannelSetting {
     play_type: ChannelPlayType,
+    /// Length of the crossfade between loops, in miliseconds.
+    crossfade: usize,
+    /// Length of the fades when pausing, skipping or stopping, in miliseconds.
+    fade: usize,
+    /// How a new oneshot lowers the channel's other sounds.
+    duck: DuckSetting,
+    /// Channels lowered while this channel plays oneshots, and by how much.
+    ducks: Vec<(Box<str>, f32)>,
+    /// Name of the output device the channel plays on. None uses the manager's device.
+    device: Option<Box<str>>,
+    /// Ceiling of the channel's limiter, in dBFS. None if the channel has no limiter.
+    limiter: Option<f32>,
+    /// Filters, equalizer and reverb applied to the channel.
+    effects: EffectSettings,
+}
+
+/// Options for the sound thread, given on the command line.
+#[derive(Clone)]
+pub struct SoundOptions {
+    /// Loudness every file is normalized to, in LUFS. None disables normalization.
+    pub normalize: Option<f32>,
+    /// Name of the audio output device. None uses the default device.
+    pub device: Option<Box<str>>,
+    /// Size of the cache of decoded short sounds, in megabytes. 0 disables the cache.
+    pub sample_cache: usize,
+    /// Whether to decode the oneshots into the cache when the soundpack is loaded.
+    pub prewarm: bool,
+    /// Records everything played into a file, if set.
+    pub recorder: Option<Recorder>,
+    /// Where sounds are played.
+    pub backend: AudioBackend,
+    /// Seed of the random choices, so they're the same every time. None uses a random seed.
+    pub seed: Option<u64>,
+    /// Stereo width, mono downmix and left/right swap of the whole mix.
+    pub stereo: StereoSettings,
+    /// Ceiling of the master limiter, in dBFS. None disables the limiter.
+    pub limiter: Option<f32>,
+    /// Maximum number of oneshots playing at once, over every channel. 0 is unlimited.
+    pub max_voices: usize,
+}
+
+impl Default for SoundOptions {
+    fn default() -> Self {
+        Self {
+            normalize: None,
+            device: None,
+            sample_cache: DEFAULT_SAMPLE_CACHE_SIZE,
+            prewarm: false,
+            recorder: None,
+            backend: AudioBackend::default(),
+            seed: None,
+            stereo: StereoSettings::default(),
+            limiter: Some(DEFAULT_LIMITER_CEILING),
+            max_voices: DEFAULT_MAX_VOICES,
+        }
+    }
+}
+
+/// Read an ignore list. Each line is a regex pattern, and invalid patterns are skipped.
+fn read_ignore_list(path: &Path) -> Result<Vec<Regex>> {
+    let file = &mut File::open(path)?;
+    let buf = &mut Vec::new();
+    file.read_to_end(buf)?;
+    Ok(String::from_utf8_lossy(&buf).lines().filter_map(|expr| {
+        let processed = FAULTY_ESCAPE.replace_all(expr, "$1");
+        let processed = EMPTY_EXPR.replace_all(&processed, ")?");
+        Regex::new(&processed).ok()
+    }).collect())
 }
 
 /// The sound thread function.
-pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
+pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>, mut options: SoundOptions) {
     // Outer loop. Restarts the inner loop if an error occured, but didn't panic.
+    ui_tx.send(UIMessage::OutputDevices(output_device_names(), options.device.clone())).unwrap();
+    ui_tx.send(UIMessage::StereoChanged(options.stereo)).unwrap();
     loop {
         info!("(Re)Starting sound thread.");
         // SoundManager
@@ -178,23 +377,36 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
                                 prev_manager.finish();
                             }
                             manager.replace(
-                                SoundManager::new(&path, ui_tx.clone())?
+                                SoundManager::new(&path, ui_tx.clone(), &options)?
                             );
                         }
 
+                        ChangeDevice(name) => {
+                            trace!("Change output device to {}", name.as_deref().unwrap_or("default"));
+                            match manager.as_mut().map(|manager| manager.change_device(name.as_deref())) {
+                                Some(Err(e)) => {
+                                    warn!("Failed to change the audio output device: {}", e);
+                                    warn!("Will keep playing on the current device.");
+                                }
+                                _ => options.device = name,
+                            }
+                            ui_tx.send(UIMessage::OutputDevices(output_device_names(), options.device.clone()))?;
+                        }
+
+                        ChangeStereo(settings) => {
+                            trace!("Change stereo settings to {:?}", settings);
+                            options.stereo = settings;
+                            if let Some(manager) = manager.as_ref() {
+                                manager.set_stereo(settings);
+                            }
+                            ui_tx.send(UIMessage::StereoChanged(settings))?;
+                        }
+
                         // These types of messages require a manager.
                         message => if let Some(manager) = manager.as_mut() {
                             match message {
                                 ChangeIgnoreList(path) => {
-                                    let file = &mut File::open(&path)?;
-                                    let buf = &mut Vec::new();
-                                    file.read_to_end(buf)?;
-                                    let list: Vec<Regex> = String::from_utf8_lossy(&buf).lines().filter_map(|expr| {
-                                        let processed = FAULTY_ESCAPE.replace_all(expr, "$1");
-                                        let processed = EMPTY_EXPR.replace_all(&processed, ")?");
-                                        Regex::new(&processed).ok()
-                                    }).collect();
-                                    manager.set_ignore_list(list)?;
+                                    manager.set_ignore_list(read_ignore_list(&path)?)?;
                                 }
 
                                 VolumeChange(channel,volume) => {
@@ -219,6 +431,16 @@ pub fn run(sound_rx: Receiver<SoundMessage>, ui_tx: Sender<UIMessage>) {
                                 SetCurrentVolumesAsDefault(file) => {
                                     manager.set_current_volumes_as_default(file)?;
                                 }
+
+                                ChangeChannelDevice(channel, device) => {
+                                    trace!("Route {} to {}", channel, device.as_deref().unwrap_or("default"));
+                                    manager.set_channel_device(&channel, device)?;
+                                }
+
+                                ChangeChannelEffects(channel, settings) => {
+                                    trace!("Change {} effects to {:?}", channel, settings);
+                                    manager.set_channel_effects(&channel, settings)?;
+                                }
                                 _ => (),
                             }
                         }
@@ -0,0 +1,485 @@
+use super::*;
+use std::io::{Write, BufWriter};
+
+/// Number of samples per channel in each FLAC frame.
+const FLAC_BLOCK_SIZE: usize = 4096;
+/// How many samples are written between updates of the file's header.
+/// The header is kept up to date, so the file stays valid if SoundSense-RS is closed while writing.
+const HEADER_UPDATE_INTERVAL: u64 = 44100;
+
+/// Audio file formats that sounds can be rendered or recorded to.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum AudioFileFormat {
+    Wav,
+    Flac,
+}
+
+impl AudioFileFormat {
+    /// Get the format from the extension of the path.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "wav" | "wave" => Some(Self::Wav),
+            "flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+}
+
+/// Writes 16 bit samples into a WAV or FLAC file.
+pub struct AudioFileWriter {
+    file: BufWriter<File>,
+    format: AudioFileFormat,
+    channels: u16,
+    sample_rate: u32,
+    /// Number of samples written so far, counting every channel.
+    samples: u64,
+    /// Samples waiting for a full FLAC block.
+    pending: Vec<i16>,
+    /// Index of the next FLAC frame.
+    flac_frame: u64,
+    /// Samples written since the header was last updated.
+    since_update: u64,
+}
+
+impl AudioFileWriter {
+    /// Create the file, guessing the format from its extension.
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> Result<Self> {
+        let format = AudioFileFormat::from_path(path)
+            .ok_or_else(|| format!("Unknown audio file format: {}. Use .wav or .flac.", path.display()))?;
+        if format == AudioFileFormat::Flac && channels > 8 {
+            return Err(format!("FLAC files can't have {} channels.", channels).into())
+        }
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            format,
+            channels: channels.max(1),
+            sample_rate,
+            samples: 0,
+            pending: Vec::new(),
+            flac_frame: 0,
+            since_update: 0,
+        };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    #[inline]
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Write interleaved samples. Samples outside of [-1.0, 1.0] are clipped.
+    pub fn write_samples(&mut self, samples: &[f32]) -> Result<()> {
+        self.write_pcm(samples.iter()
+            .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16))
+    }
+
+    /// Write interleaved 16 bit samples.
+    fn write_pcm(&mut self, samples: impl Iterator<Item=i16>) -> Result<()> {
+        match self.format {
+            AudioFileFormat::Wav => for sample in samples {
+                self.file.write_all(&sample.to_le_bytes())?;
+                self.count_samples(1);
+            }
+            AudioFileFormat::Flac => {
+                self.pending.extend(samples);
+                let block_len = FLAC_BLOCK_SIZE * self.channels as usize;
+                while self.pending.len() >= block_len {
+                    let block: Vec<i16> = self.pending.drain(..block_len).collect();
+                    self.write_flac_frame(&block)?;
+                    self.count_samples(block_len);
+                }
+            }
+        }
+        if self.since_update >= HEADER_UPDATE_INTERVAL * self.channels as u64 {
+            self.since_update = 0;
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    /// Write the remaining samples, and complete the header.
+    pub fn finish(mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            let block = std::mem::take(&mut self.pending);
+            // The last block can't be split in the middle of a frame.
+            let len = block.len() - block.len() % self.channels as usize;
+            self.write_flac_frame(&block[..len])?;
+            self.count_samples(len);
+        }
+        self.update_header()
+    }
+
+    #[inline]
+    fn count_samples(&mut self, samples: usize) {
+        self.samples += samples as u64;
+        self.since_update += samples as u64;
+    }
+
+    /// Number of complete frames written so far.
+    #[inline]
+    fn frames(&self) -> u64 {
+        self.samples / self.channels as u64
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        match self.format {
+            AudioFileFormat::Wav => {
+                let block_align = self.channels as u32 * 2;
+                self.file.write_all(b"RIFF")?;
+                self.file.write_all(&36u32.to_le_bytes())?;
+                self.file.write_all(b"WAVEfmt ")?;
+                self.file.write_all(&16u32.to_le_bytes())?;
+                // PCM
+                self.file.write_all(&1u16.to_le_bytes())?;
+                self.file.write_all(&self.channels.to_le_bytes())?;
+                self.file.write_all(&self.sample_rate.to_le_bytes())?;
+                self.file.write_all(&(self.sample_rate * block_align).to_le_bytes())?;
+                self.file.write_all(&(block_align as u16).to_le_bytes())?;
+                self.file.write_all(&16u16.to_le_bytes())?;
+                self.file.write_all(b"data")?;
+                self.file.write_all(&0u32.to_le_bytes())?;
+            }
+            AudioFileFormat::Flac => {
+                self.file.write_all(b"fLaC")?;
+                self.file.write_all(&self.flac_streaminfo())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the number of samples into the header, then go back to the end of the file.
+    fn update_header(&mut self) -> Result<()> {
+        match self.format {
+            AudioFileFormat::Wav => {
+                let data_size = (self.frames() * self.channels as u64 * 2).min(u32::MAX as u64 - 36) as u32;
+                self.file.seek(SeekFrom::Start(4))?;
+                self.file.write_all(&(data_size + 36).to_le_bytes())?;
+                self.file.seek(SeekFrom::Start(40))?;
+                self.file.write_all(&data_size.to_le_bytes())?;
+            }
+            AudioFileFormat::Flac => {
+                let streaminfo = self.flac_streaminfo();
+                self.file.seek(SeekFrom::Start(4))?;
+                self.file.write_all(&streaminfo)?;
+            }
+        }
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()?;
+        Ok(())
+    }
+
+    /// The STREAMINFO metadata block, with its header.
+    /// The MD5 signature is left empty, which means it's unknown.
+    fn flac_streaminfo(&self) -> [u8; 38] {
+        let mut bits = BitWriter::default();
+        // Last metadata block, STREAMINFO, 34 bytes.
+        bits.write(1, 1);
+        bits.write(0, 7);
+        bits.write(34, 24);
+        bits.write(FLAC_BLOCK_SIZE as u64, 16);
+        bits.write(FLAC_BLOCK_SIZE as u64, 16);
+        // Unknown frame sizes.
+        bits.write(0, 24);
+        bits.write(0, 24);
+        bits.write(self.sample_rate as u64, 20);
+        bits.write(self.channels as u64 - 1, 3);
+        bits.write(15, 5);
+        bits.write(self.frames(), 36);
+        let mut streaminfo = [0u8; 38];
+        streaminfo[..22].copy_from_slice(&bits.bytes);
+        streaminfo
+    }
+
+    /// Write a FLAC frame, each channel encoded with the fixed predictor that suits it best.
+    fn write_flac_frame(&mut self, samples: &[i16]) -> Result<()> {
+        let channels = self.channels as usize;
+        let block_size = samples.len() / channels;
+        if block_size == 0 {
+            return Ok(())
+        }
+        let mut bits = BitWriter::default();
+        // Sync code, fixed block size.
+        bits.write(0b1111_1111_1111_1000, 16);
+        // Block size stored at the end of the header, sample rate from STREAMINFO.
+        bits.write(0b0111, 4);
+        bits.write(0b0000, 4);
+        // Independent channels, 16 bit samples.
+        bits.write(channels as u64 - 1, 4);
+        bits.write(0b100, 3);
+        bits.write(0, 1);
+        bits.write_utf8(self.flac_frame);
+        bits.write(block_size as u64 - 1, 16);
+        let crc = crc8(&bits.bytes);
+        bits.write(crc as u64, 8);
+        for channel in 0..channels {
+            let channel_samples: Vec<i32> = samples.iter()
+                .skip(channel)
+                .step_by(channels)
+                .map(|&sample| sample as i32)
+                .collect();
+            write_fixed_subframe(&mut bits, &channel_samples);
+        }
+        bits.align();
+        let crc = crc16(&bits.bytes);
+        bits.write(crc as u64, 16);
+        self.file.write_all(&bits.bytes)?;
+        self.flac_frame += 1;
+        Ok(())
+    }
+}
+
+/// Write a subframe using a fixed predictor and Rice coded residuals.
+fn write_fixed_subframe(bits: &mut BitWriter, samples: &[i32]) {
+    // Choose the predictor order leaving the smallest residuals.
+    let max_order = samples.len().min(4);
+    let (order, residuals) = (0..=max_order)
+        .map(|order| (order, fixed_residuals(samples, order)))
+        .min_by_key(|(_, residuals)| residuals.iter().map(|r| r.unsigned_abs() as u64).sum::<u64>())
+        .unwrap();
+    bits.write(0, 1);
+    bits.write(0b001000 | order as u64, 6);
+    bits.write(0, 1);
+    for &sample in samples[..order].iter() {
+        bits.write(sample as u16 as u64, 16);
+    }
+    // Rice coding, a single partition.
+    let residuals: Vec<u64> = residuals.iter()
+        .map(|&r| ((r << 1) ^ (r >> 31)) as u32 as u64)
+        .collect();
+    let parameter = (0..15)
+        .min_by_key(|k| residuals.iter().map(|u| (u >> k) + 1 + k).sum::<u64>())
+        .unwrap();
+    bits.write(0b00, 2);
+    bits.write(0, 4);
+    bits.write(parameter, 4);
+    for u in residuals.iter() {
+        bits.write_unary(u >> parameter);
+        bits.write(u & ((1 << parameter) - 1), parameter as u32);
+    }
+}
+
+/// Difference between the samples and the fixed predictor of the given order.
+fn fixed_residuals(samples: &[i32], order: usize) -> Vec<i32> {
+    (order..samples.len())
+        .map(|i| {
+            let s = |n: usize| samples[i - n];
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// Writes values bit by bit, most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    /// Number of bits used in the last byte. 0 if it's full.
+    used: u32,
+}
+
+impl BitWriter {
+    /// Write the lowest `count` bits of `value`.
+    fn write(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    #[inline]
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    /// Write `value` zeros, then a one.
+    fn write_unary(&mut self, value: u64) {
+        for _ in 0..value {
+            self.write_bit(false);
+        }
+        self.write_bit(true);
+    }
+
+    /// Write a number in UTF-8's variable length coding, as FLAC frame numbers are.
+    fn write_utf8(&mut self, value: u64) {
+        if value < 0x80 {
+            self.write(value, 8);
+            return
+        }
+        // Number of continuation bytes, each holding 6 bits.
+        let mut extra = 1;
+        while value >= 1 << (6 * extra + 6 - extra) {
+            extra += 1;
+        }
+        let lead_mask = !(0xFFu64 >> (extra + 1)) & 0xFF;
+        self.write(lead_mask | (value >> (6 * extra)), 8);
+        for i in (0..extra).rev() {
+            self.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+        }
+    }
+
+    /// Pad the last byte with zeros.
+    #[inline]
+    fn align(&mut self) {
+        self.used = 0;
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {(crc << 1) ^ 0x07} else {crc << 1};
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {(crc << 1) ^ 0x8005} else {crc << 1};
+        }
+        crc
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    /// Interleaved samples mixing a slow sine, noise, and the extreme values.
+    fn test_samples(frames: usize, channels: u16) -> Vec<i16> {
+        let mut noise: u32 = 12345;
+        (0..frames * channels as usize)
+            .map(|i| {
+                noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                match (i / 997) % 4 {
+                    0 => ((i as f32 * 0.01).sin() * 20000.0) as i16,
+                    1 => (noise >> 16) as i16,
+                    2 => if i % 2 == 0 {i16::MAX} else {i16::MIN},
+                    _ => 0,
+                }
+            })
+            .collect()
+    }
+
+    /// Write the samples into a file, in chunks of uneven sizes.
+    fn write_file(path: &Path, channels: u16, samples: &[i16]) {
+        let mut writer = AudioFileWriter::create(path, channels, 44100).unwrap();
+        for chunk in samples.chunks(1000 * channels as usize + 1) {
+            writer.write_pcm(chunk.iter().copied()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    fn read_wav(path: &Path, channels: u16) -> Vec<i16> {
+        let mut reader = hound::WavReader::open(path).unwrap();
+        let spec = reader.spec();
+        assert_eq!(spec.channels, channels);
+        assert_eq!(spec.sample_rate, 44100);
+        assert_eq!(spec.bits_per_sample, 16);
+        reader.samples::<i16>().map(|sample| sample.unwrap()).collect()
+    }
+
+    fn read_flac(path: &Path, channels: u16) -> Vec<i16> {
+        let mut reader = claxon::FlacReader::open(path).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!(info.channels, channels as u32);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.bits_per_sample, 16);
+        let samples: Vec<i16> = reader.samples().map(|sample| sample.unwrap() as i16).collect();
+        // A length of 0 means unknown.
+        assert_eq!(info.samples.unwrap_or(0), samples.len() as u64 / channels as u64);
+        samples
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = test_dir("audio-file");
+        // Full blocks only, a short final block, and final blocks shorter than the predictor order.
+        let lengths = [0, 1, 2, 3, 4, 5, 100, FLAC_BLOCK_SIZE, FLAC_BLOCK_SIZE + 1,
+            FLAC_BLOCK_SIZE + 3, 3 * FLAC_BLOCK_SIZE + 1234];
+        for &channels in [1, 2, 6].iter() {
+            for &frames in lengths.iter() {
+                let samples = test_samples(frames, channels);
+                let wav = dir.join(format!("{}-{}.wav", channels, frames));
+                let flac = dir.join(format!("{}-{}.flac", channels, frames));
+                write_file(&wav, channels, &samples);
+                write_file(&flac, channels, &samples);
+                assert_eq!(read_wav(&wav, channels), samples, "{}", wav.display());
+                assert_eq!(read_flac(&flac, channels), samples, "{}", flac.display());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn long_flac() {
+        // Enough FLAC frames to need frame numbers of two bytes.
+        let dir = test_dir("audio-file-long");
+        let path = dir.join("long.flac");
+        let samples = test_samples(130 * FLAC_BLOCK_SIZE + 7, 1);
+        write_file(&path, 1, &samples);
+        assert_eq!(read_flac(&path, 1), samples);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn clipping() {
+        let dir = test_dir("audio-file-clip");
+        let path = dir.join("clip.wav");
+        let mut writer = AudioFileWriter::create(&path, 1, 44100).unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0, 2.0, -2.0, 0.5]).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(read_wav(&path, 1), vec![0, i16::MAX, -i16::MAX, i16::MAX, -i16::MAX, i16::MAX / 2]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn utf8_frame_numbers() {
+        let encode = |value| {
+            let mut bits = BitWriter::default();
+            bits.write_utf8(value);
+            bits.bytes
+        };
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7F), [0x7F]);
+        assert_eq!(encode(0x80), [0xC2, 0x80]);
+        assert_eq!(encode(0x7FF), [0xDF, 0xBF]);
+        assert_eq!(encode(0x800), [0xE0, 0xA0, 0x80]);
+        assert_eq!(encode(0xFFFF), [0xEF, 0xBF, 0xBF]);
+        assert_eq!(encode(0x10000), [0xF0, 0x90, 0x80, 0x80]);
+        assert_eq!(encode(0x1F_FFFF), [0xF7, 0xBF, 0xBF, 0xBF]);
+        assert_eq!(encode(0x20_0000), [0xF8, 0x88, 0x80, 0x80, 0x80]);
+        assert_eq!(encode(0x400_0000), [0xFC, 0x84, 0x80, 0x80, 0x80, 0x80]);
+        assert_eq!(encode(0xF_FFFF_FFFF), [0xFE, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]);
+    }
+
+    #[test]
+    fn crcs() {
+        // The standard check values of CRC-8 and CRC-16/UMTS.
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+        assert_eq!(crc8(&[]), 0);
+        assert_eq!(crc16(&[]), 0);
+    }
+}
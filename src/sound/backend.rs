@@ -0,0 +1,169 @@
+use super::*;
+
+/// How much audio the null sink reads at once, in miliseconds.
+const SINK_CHUNK_LENGTH: usize = 10;
+
+/// Somewhere a master bus can be played.
+pub trait AudioSink {
+    /// Channel count and sample rate the sink plays at.
+    fn format(&self) -> (u16, u32);
+    /// Name of the output device, if the sink is one.
+    fn device_name(&self) -> Option<Box<str>> {
+        None
+    }
+    /// Start playing the source, until it ends or the sink fails.
+    fn play(&self, source: BoxedSource) -> SinkStatus;
+}
+
+impl AudioSink for Device {
+    fn format(&self) -> (u16, u32) {
+        // Mix in the device's own format, so the output doesn't need converting.
+        self.default_output_format()
+            .map(|format| (format.channels, format.sample_rate.0))
+            .unwrap_or((DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE))
+    }
+
+    fn device_name(&self) -> Option<Box<str>> {
+        self.name().ok().map(String::into_boxed_str)
+    }
+
+    fn play(&self, source: BoxedSource) -> SinkStatus {
+        play_on_device(self, source)
+    }
+}
+
+/// Plays sources at the speed of a real device, but discards the samples.
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn format(&self) -> (u16, u32) {
+        (DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE)
+    }
+
+    fn play(&self, source: BoxedSource) -> SinkStatus {
+        play_in_realtime(source)
+    }
+}
+
+/// Stands for a missing output device: sources fail at once, so they're moved to a device once one is found.
+pub struct MissingSink;
+
+impl AudioSink for MissingSink {
+    fn format(&self) -> (u16, u32) {
+        (DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE)
+    }
+
+    fn play(&self, _source: BoxedSource) -> SinkStatus {
+        let status = SinkStatus::new();
+        status.fail();
+        status
+    }
+}
+
+/// Read the source in its own thread, as fast as a device would, until it ends.
+fn play_in_realtime(mut source: BoxedSource) -> SinkStatus {
+    let status = SinkStatus::new();
+    let thread_status = status.clone();
+    std::thread::Builder::new()
+        .name("sink_thread".to_string())
+        .spawn(move || {
+            let channels = source.channels().max(1) as usize;
+            let sample_rate = source.sample_rate().max(1) as u64;
+            let chunk_len = SINK_CHUNK_LENGTH * sample_rate as usize / 1000 * channels;
+            let start = Instant::now();
+            let mut frames = 0u64;
+            loop {
+                let read = source.by_ref().take(chunk_len).count();
+                thread_status.mark_read();
+                if read < chunk_len {
+                    return
+                }
+                frames += (read / channels) as u64;
+                // Sleep until the device would have played the chunk.
+                let due = Duration::from_micros(frames * 1_000_000 / sample_rate);
+                if let Some(wait) = due.checked_sub(start.elapsed()) {
+                    std::thread::sleep(wait);
+                }
+            }
+        })
+        .unwrap();
+    status
+}
+
+/// Where sounds are played, chosen on the command line.
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub enum AudioBackend {
+    /// The system's audio output devices.
+    #[default]
+    Device,
+    /// No output. Sounds are played, but not heard.
+    Null,
+    /// Output devices plugged in and out by the tests.
+    #[cfg(test)]
+    Test,
+}
+
+impl AudioBackend {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "device" | "rodio" => Some(Self::Device),
+            "null" | "none" => Some(Self::Null),
+            _ => None,
+        }
+    }
+
+    /// Open the sink the master bus plays on.
+    /// `device` is the name of the output device, for the backend using devices. None uses the default device.
+    pub fn open(&self, device: Option<&str>) -> Result<Box<dyn AudioSink>> {
+        match self {
+            Self::Device => Ok(Box::new(find_output_device(device)?)),
+            Self::Null => Ok(Box::new(NullSink)),
+            #[cfg(test)]
+            Self::Test => Ok(Box::new(test_util::find_device_or_default(device)?)),
+        }
+    }
+
+    /// Whether channels can be routed to output devices of their own.
+    #[inline]
+    pub fn has_devices(&self) -> bool {
+        match self {
+            Self::Device => true,
+            #[cfg(test)]
+            Self::Test => true,
+            _ => false,
+        }
+    }
+
+    /// Names of the output devices. Empty for the backends without devices.
+    pub fn device_names(&self) -> Vec<Box<str>> {
+        match self {
+            Self::Device => output_device_names(),
+            #[cfg(test)]
+            Self::Test => test_util::device_names(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Name of the default output device, if the backend has one.
+    pub fn default_device_name(&self) -> Option<Box<str>> {
+        match self {
+            Self::Device => default_output_device_name(),
+            #[cfg(test)]
+            Self::Test => test_util::default_device_name(),
+            _ => None,
+        }
+    }
+
+    /// Open the named output device, to route a channel to it.
+    /// Returns None if it wasn't found.
+    pub fn open_device(&self, name: &str) -> Option<Box<dyn AudioSink>> {
+        match self {
+            Self::Device => find_named_output_device(name)
+                .map(|device| Box::new(device) as Box<dyn AudioSink>),
+            #[cfg(test)]
+            Self::Test => test_util::find_device(name)
+                .map(|sink| Box::new(sink) as Box<dyn AudioSink>),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,399 @@
+use super::*;
+
+/// Names of the variables the GameContext keeps up to date.
+pub const SEASON_VAR: &str = "game.season";
+pub const YEAR_VAR: &str = "game.year";
+pub const MODE_VAR: &str = "game.mode";
+pub const SIEGE_VAR: &str = "game.siege";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Season {
+    Spring,
+    Summer,
+    Autumn,
+    Winter,
+}
+
+impl Season {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "spring" => Some(Season::Spring),
+            "summer" => Some(Season::Summer),
+            "autumn" | "fall" => Some(Season::Autumn),
+            "winter" => Some(Season::Winter),
+            _ => None,
+        }
+    }
+
+    /// Value of the `game.season` variable. 0 means unknown.
+    #[inline]
+    pub fn value(self) -> i64 {
+        self as i64 + 1
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Season::Spring => "Spring",
+            Season::Summer => "Summer",
+            Season::Autumn => "Autumn",
+            Season::Winter => "Winter",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GameMode {
+    Fortress,
+    Adventure,
+}
+
+impl GameMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "fortress" => Some(GameMode::Fortress),
+            "adventure" | "adventurer" => Some(GameMode::Adventure),
+            _ => None,
+        }
+    }
+
+    /// Value of the `game.mode` variable. 0 means unknown.
+    #[inline]
+    pub fn value(self) -> i64 {
+        self as i64 + 1
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GameMode::Fortress => "Fortress",
+            GameMode::Adventure => "Adventure",
+        }
+    }
+}
+
+/// Something the gamelog announced.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum ContextEvent {
+    /// A new season started. Spring also starts a new year.
+    Season(Season),
+    /// The first capture group of the pattern is the current year.
+    Year,
+    /// A new game was started. Everything becomes unknown.
+    NewGame,
+    /// A game of this mode was loaded.
+    Mode(GameMode),
+    SiegeStart,
+    SiegeEnd,
+}
+
+impl ContextEvent {
+    /// Parse the `event` attribute of a `<contextPattern>`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "year" => Some(ContextEvent::Year),
+            "newGame" => Some(ContextEvent::NewGame),
+            "siegeStart" => Some(ContextEvent::SiegeStart),
+            "siegeEnd" => Some(ContextEvent::SiegeEnd),
+            other => Season::from_name(other).map(ContextEvent::Season)
+                .or_else(|| GameMode::from_name(other).map(ContextEvent::Mode)),
+        }
+    }
+}
+
+/// The default patterns, matching Dwarf Fortress's own announcements.
+const DEFAULT_PATTERNS: &[(&str, ContextEvent)] = &[
+    (r"^Spring has arrived", ContextEvent::Season(Season::Spring)),
+    (r"^Summer has arrived", ContextEvent::Season(Season::Summer)),
+    (r"^Autumn has (?:come|arrived)", ContextEvent::Season(Season::Autumn)),
+    (r"^Winter (?:is upon you|has arrived)", ContextEvent::Season(Season::Winter)),
+    (r"^\*+ ?STARTING NEW GAME ?\*+", ContextEvent::NewGame),
+    (r"^\*+ ?Loading Fortress ?\*+", ContextEvent::Mode(GameMode::Fortress)),
+    (r"^\*+ ?Loading Adventure ?\*+", ContextEvent::Mode(GameMode::Adventure)),
+    (r"^A vile force of darkness has arrived!", ContextEvent::SiegeStart),
+    (r"^The (?:enemy|invaders|siegers) (?:have|has) (?:fled|retreated|been routed)", ContextEvent::SiegeEnd),
+];
+
+/// Keeps track of the season, year, game mode and sieges, as announced by the gamelog.
+/// The values are mirrored into the `game.*` variables, so conditions can use them.
+pub struct GameContext {
+    pub season: Option<Season>,
+    pub year: Option<i64>,
+    pub mode: Option<GameMode>,
+    pub siege: bool,
+    patterns: Vec<(Regex, ContextEvent)>,
+}
+
+impl GameContext {
+    pub fn new() -> Self {
+        Self {
+            season: None,
+            year: None,
+            mode: None,
+            siege: false,
+            patterns: DEFAULT_PATTERNS.iter()
+                .map(|(pattern, event)| (Regex::new(pattern).unwrap(), *event))
+                .collect(),
+        }
+    }
+
+    /// Add a soundpack-defined pattern, checked before the default ones.
+    pub fn add_pattern(&mut self, pattern: Regex, event: ContextEvent) {
+        self.patterns.insert(0, (pattern, event));
+    }
+
+    /// Update the context from a line of the gamelog. Returns true if anything changed.
+    pub fn process_log(&mut self, log: &str) -> bool {
+        let (caps, event) = match self.patterns.iter()
+            .find_map(|(pattern, event)| pattern.captures(log).map(|caps| (caps, *event)))
+        {
+            Some(found) => found,
+            None => return false,
+        };
+        trace!(" context event: {:?}", event);
+        let previous = (self.season, self.year, self.mode, self.siege);
+        match event {
+            ContextEvent::Season(season) => {
+                // Spring arriving after Winter is a new year.
+                if season == Season::Spring && self.season == Some(Season::Winter) {
+                    self.year = self.year.map(|year| year + 1);
+                }
+                self.season = Some(season);
+            }
+            ContextEvent::Year => {
+                if let Some(year) = caps.get(1).and_then(|m| m.as_str().parse().ok()) {
+                    self.year = Some(year);
+                }
+            }
+            ContextEvent::NewGame => {
+                self.season = None;
+                self.year = None;
+                self.mode = None;
+                self.siege = false;
+            }
+            ContextEvent::Mode(mode) => {
+                self.season = None;
+                self.year = None;
+                self.mode = Some(mode);
+                self.siege = false;
+            }
+            ContextEvent::SiegeStart => self.siege = true,
+            ContextEvent::SiegeEnd => self.siege = false,
+        }
+        previous != (self.season, self.year, self.mode, self.siege)
+    }
+
+    /// Write the context into the `game.*` variables.
+    pub fn write_variables(&self, variables: &mut Variables) {
+        variables.set(SEASON_VAR, self.season.map_or(0, Season::value));
+        variables.set(YEAR_VAR, self.year.unwrap_or(0));
+        variables.set(MODE_VAR, self.mode.map_or(0, GameMode::value));
+        variables.set(SIEGE_VAR, self.siege as i64);
+    }
+
+    /// Display names and values, for the UI.
+    pub fn describe(&self) -> Vec<(Box<str>, Box<str>)> {
+        vec![
+            ("Season".into(), self.season.map_or("?", Season::name).into()),
+            ("Year".into(), self.year.map_or_else(|| "?".to_string(), |y| y.to_string()).into()),
+            ("Mode".into(), self.mode.map_or("?", GameMode::name).into()),
+            ("Siege".into(), if self.siege {"Yes"} else {"No"}.into()),
+        ]
+    }
+}
+
+/// Restricts a sound or sound file to some seasons and game modes.
+/// An unknown season or mode passes every filter.
+#[derive(Clone, Default)]
+pub struct ContextFilter {
+    seasons: Vec<Season>,
+    modes: Vec<GameMode>,
+}
+
+impl ContextFilter {
+    /// Parse the comma separated `season` and `mode` attributes.
+    pub fn parse(seasons: Option<&str>, modes: Option<&str>) -> Result<Self> {
+        let names = |list: Option<&str>| list.unwrap_or("")
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect::<Vec<String>>();
+        let seasons = names(seasons).iter()
+            .map(|name| Season::from_name(name)
+                .ok_or_else(|| format!("Unknown season: {}", name)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let modes = names(modes).iter()
+            .map(|name| GameMode::from_name(name)
+                .ok_or_else(|| format!("Unknown game mode: {}", name)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        Ok(Self { seasons, modes })
+    }
+
+    /// Check the filter against the `game.*` variables.
+    pub fn matches(&self, variables: &Variables) -> bool {
+        let season = variables.get(SEASON_VAR);
+        let mode = variables.get(MODE_VAR);
+        (self.seasons.is_empty() || season == 0
+            || self.seasons.iter().any(|s| s.value() == season))
+        && (self.modes.is_empty() || mode == 0
+            || self.modes.iter().any(|m| m.value() == mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed the lines to the context, and check which ones changed it.
+    fn feed(context: &mut GameContext, lines: &[&str]) -> Vec<bool> {
+        lines.iter().map(|line| context.process_log(line)).collect()
+    }
+
+    fn state(context: &GameContext) -> (Option<Season>, Option<i64>, Option<GameMode>, bool) {
+        (context.season, context.year, context.mode, context.siege)
+    }
+
+    #[test]
+    fn seasons() {
+        let mut context = GameContext::new();
+        assert_eq!(feed(&mut context, &[
+            "Spring has arrived on the calendar.",
+            "Summer has arrived on the calendar.",
+            "Autumn has come.",
+            "Winter is upon you.",
+        ]), vec![true, true, true, true]);
+        assert_eq!(context.season, Some(Season::Winter));
+        for (line, season) in [
+            ("Spring has arrived!", Season::Spring),
+            ("Summer has arrived.", Season::Summer),
+            ("Autumn has arrived on the calendar.", Season::Autumn),
+            ("Winter has arrived on the calendar.", Season::Winter),
+        ].iter() {
+            assert!(context.process_log(line));
+            assert_eq!(context.season, Some(*season), "{}", line);
+        }
+        // The same season again changes nothing.
+        assert!(!context.process_log("Winter has arrived on the calendar."));
+        // The year stays unknown until it's announced.
+        assert_eq!(context.year, None);
+    }
+
+    #[test]
+    fn new_year() {
+        let mut context = GameContext::new();
+        context.add_pattern(Regex::new(r"^It is now the year (\d+)").unwrap(), ContextEvent::Year);
+        assert!(context.process_log("It is now the year 125."));
+        assert_eq!(context.year, Some(125));
+        feed(&mut context, &[
+            "Summer has arrived on the calendar.",
+            "Autumn has arrived on the calendar.",
+            "Winter has arrived on the calendar.",
+        ]);
+        assert_eq!(context.year, Some(125));
+        // Spring after Winter is a new year.
+        assert!(context.process_log("Spring has arrived on the calendar."));
+        assert_eq!(state(&context), (Some(Season::Spring), Some(126), None, false));
+        // Spring after another season isn't.
+        feed(&mut context, &["Summer has arrived on the calendar.", "Spring has arrived on the calendar."]);
+        assert_eq!(context.year, Some(126));
+        // A year that isn't a number is ignored.
+        context.add_pattern(Regex::new(r"^The year is (\w+)").unwrap(), ContextEvent::Year);
+        assert!(!context.process_log("The year is unknown"));
+        assert_eq!(context.year, Some(126));
+    }
+
+    #[test]
+    fn sieges() {
+        let mut context = GameContext::new();
+        assert!(context.process_log("A vile force of darkness has arrived!"));
+        assert!(context.siege);
+        assert!(!context.process_log("A vile force of darkness has arrived!"));
+        for line in [
+            "The enemy have fled.",
+            "The invaders have retreated.",
+            "The siegers have been routed!",
+            "The enemy has fled.",
+        ].iter() {
+            context.siege = true;
+            assert!(context.process_log(line), "{}", line);
+            assert!(!context.siege, "{}", line);
+        }
+    }
+
+    #[test]
+    fn game_loads() {
+        let mut context = GameContext::new();
+        feed(&mut context, &[
+            "** Loading Fortress **",
+            "Winter is upon you.",
+            "A vile force of darkness has arrived!",
+        ]);
+        assert_eq!(state(&context), (Some(Season::Winter), None, Some(GameMode::Fortress), true));
+        // Loading a game forgets the previous game.
+        assert!(context.process_log("** Loading Adventure **"));
+        assert_eq!(state(&context), (None, None, Some(GameMode::Adventure), false));
+        assert!(context.process_log("*** Loading Fortress ***"));
+        assert_eq!(context.mode, Some(GameMode::Fortress));
+        feed(&mut context, &["Summer has arrived on the calendar."]);
+        assert!(context.process_log("*** STARTING NEW GAME ***"));
+        assert_eq!(state(&context), (None, None, None, false));
+        assert!(!context.process_log("**STARTING NEW GAME**"));
+    }
+
+    #[test]
+    fn non_matching_lines() {
+        let mut context = GameContext::new();
+        context.process_log("Summer has arrived on the calendar.");
+        let before = state(&context);
+        assert_eq!(feed(&mut context, &[
+            "",
+            "Urist McMiner cancels Dig: Interrupted by Giant Cave Spider.",
+            "spring has arrived on the calendar.",
+            "Urist McSinger says: Spring has arrived!",
+            "The Spring has arrived in the caverns.",
+            "Winter",
+            "Autumn has gone.",
+            "** Loading Fortress",
+            "Loading Fortress",
+            "A vile force of darkness has been spotted.",
+            "The goblin has fled.",
+            "The enemy have taken the gate.",
+        ]), vec![false; 12]);
+        assert_eq!(state(&context), before);
+    }
+
+    #[test]
+    fn soundpack_patterns_come_first() {
+        let mut context = GameContext::new();
+        context.add_pattern(Regex::new(r"^Spring has arrived").unwrap(), ContextEvent::Season(Season::Winter));
+        context.process_log("Spring has arrived on the calendar.");
+        assert_eq!(context.season, Some(Season::Winter));
+    }
+
+    #[test]
+    fn variables() {
+        let mut context = GameContext::new();
+        let mut variables = Variables::default();
+        context.write_variables(&mut variables);
+        assert_eq!(
+            [SEASON_VAR, YEAR_VAR, MODE_VAR, SIEGE_VAR].iter().map(|v| variables.get(v)).collect::<Vec<_>>(),
+            vec![0, 0, 0, 0]
+        );
+        context.add_pattern(Regex::new(r"^It is now the year (\d+)").unwrap(), ContextEvent::Year);
+        feed(&mut context, &[
+            "** Loading Adventure **",
+            "Autumn has come.",
+            "It is now the year 1050",
+            "A vile force of darkness has arrived!",
+        ]);
+        context.write_variables(&mut variables);
+        assert_eq!(
+            [SEASON_VAR, YEAR_VAR, MODE_VAR, SIEGE_VAR].iter().map(|v| variables.get(v)).collect::<Vec<_>>(),
+            vec![3, 1050, 2, 1]
+        );
+        let filter = ContextFilter::parse(Some("autumn, winter"), Some("adventurer")).unwrap();
+        assert!(filter.matches(&variables));
+        let filter = ContextFilter::parse(Some("spring"), None).unwrap();
+        assert!(!filter.matches(&variables));
+        assert!(ContextFilter::parse(Some("monsoon"), None).is_err());
+    }
+}
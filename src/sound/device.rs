@@ -0,0 +1,48 @@
+use super::*;
+
+/// Time between two checks of the output devices, in miliseconds.
+/// A device that didn't play anything for that long is considered lost.
+pub const DEVICE_CHECK_INTERVAL: usize = 3000;
+
+/// Get the names of the system's audio output devices.
+pub fn output_device_names() -> Vec<Box<str>> {
+    match output_devices() {
+        Ok(devices) => devices
+            .filter_map(|device| device.name().ok())
+            .map(String::into_boxed_str)
+            .collect(),
+        Err(e) => {
+            warn!("Failed to list audio output devices: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Get the name of the system's default audio output device.
+pub fn default_output_device_name() -> Option<Box<str>> {
+    default_output_device()
+        .and_then(|device| device.name().ok())
+        .map(String::into_boxed_str)
+}
+
+/// Find the audio output device with the given name, if there's one.
+pub fn find_named_output_device(name: &str) -> Option<Device> {
+    output_devices().ok()?
+        .find(|device| device.name().ok().as_deref() == Some(name))
+}
+
+/// Find the audio output device with the given name.
+/// Without a name, or if it can't be found, use the default device,
+/// or the first device available if there's no default.
+pub fn find_output_device(name: Option<&str>) -> Result<Device> {
+    if let Some(name) = name {
+        if let Some(device) = find_named_output_device(name) {
+            return Ok(device)
+        }
+        warn!("Audio output device {} wasn't found.", name);
+        warn!("Will use the default device instead.");
+    }
+    default_output_device()
+        .or_else(|| output_devices().ok().and_then(|mut devices| devices.next()))
+        .ok_or_else(|| "Failed to find any audio output device.".into())
+}
@@ -0,0 +1,109 @@
+use super::*;
+
+/// Default amount a new oneshot lowers the channel's other sounds by.
+pub const DEFAULT_DUCK_AMOUNT: f32 = 0.75;
+/// Default time to lower a sound, in miliseconds.
+pub const DEFAULT_DUCK_ATTACK: usize = 50;
+/// Default time to bring a sound back up, in miliseconds.
+pub const DEFAULT_DUCK_RELEASE: usize = 500;
+
+/// How a channel lowers the volume of sounds while a oneshot plays.
+#[derive(Copy, Clone)]
+pub struct DuckSetting {
+    /// How much the volume is lowered, from 0 (not at all) to 1 (silenced).
+    pub amount: f32,
+    /// Time to go from full volume to silence, in miliseconds.
+    pub attack: usize,
+    /// Time to go from silence to full volume, in miliseconds.
+    pub release: usize,
+}
+
+impl Default for DuckSetting {
+    fn default() -> Self {
+        Self {
+            amount: DEFAULT_DUCK_AMOUNT,
+            attack: DEFAULT_DUCK_ATTACK,
+            release: DEFAULT_DUCK_RELEASE,
+        }
+    }
+}
+
+impl DuckSetting {
+    /// Move `current` towards `target`, after `dt` miliseconds.
+    /// Uses the attack time when going down, and the release time when going up.
+    pub fn approach(&self, current: f32, target: f32, dt: usize) -> f32 {
+        if current > target {
+            match self.attack {
+                0 => target,
+                attack => (current - dt as f32 / attack as f32).max(target),
+            }
+        }
+        else {
+            match self.release {
+                0 => target,
+                release => (current + dt as f32 / release as f32).min(target),
+            }
+        }
+    }
+}
+
+/// Parse a `ducks` attribute, like `"music=60, weather=30"`.
+/// Returns each channel's name and how much it's lowered, from 0 to 1.
+pub fn parse_duck_rules(rules: &str) -> Result<Vec<(Box<str>, f32)>> {
+    rules.split(',')
+        .map(str::trim)
+        .filter(|rule| !rule.is_empty())
+        .map(|rule| {
+            let mut parts = rule.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            let amount: f32 = parts.next()
+                .ok_or_else(|| format!("ducks=\"{}\" needs an amount, like \"{}=50\".", rule, name))?
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid amount in \"{}\": {}", rule, e))?;
+            if name.is_empty() {
+                return Err(format!("ducks=\"{}\" needs a channel name.", rule).into())
+            }
+            Ok((Box::from(name), (amount / 100.0).clamp(0.0, 1.0)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn approach() {
+        let setting = DuckSetting {amount: 0.5, attack: 100, release: 400};
+        // Down with the attack time, up with the release time.
+        assert_eq!(setting.approach(1.0, 0.0, 50), 0.5);
+        assert_eq!(setting.approach(0.0, 1.0, 100), 0.25);
+        // Never past the target.
+        assert_eq!(setting.approach(1.0, 0.75, 50), 0.75);
+        assert_eq!(setting.approach(0.5, 0.6, 400), 0.6);
+        assert_eq!(setting.approach(0.5, 0.5, 10), 0.5);
+        // No time passed.
+        assert_eq!(setting.approach(1.0, 0.0, 0), 1.0);
+        // Instant with a length of 0.
+        let instant = DuckSetting {amount: 0.5, attack: 0, release: 0};
+        assert_eq!(instant.approach(1.0, 0.25, 1), 0.25);
+        assert_eq!(instant.approach(0.25, 1.0, 1), 1.0);
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = parse_duck_rules("music=60, weather = 30,,trade=150, combat=-5").unwrap();
+        assert_eq!(rules, vec![
+            ("music".into(), 0.6),
+            ("weather".into(), 0.3),
+            ("trade".into(), 1.0),
+            ("combat".into(), 0.0),
+        ]);
+        assert!(parse_duck_rules("").unwrap().is_empty());
+        assert!(parse_duck_rules(" , ").unwrap().is_empty());
+        for rules in ["music", "music=", "music=loud", "=50", "music=50, weather"].iter() {
+            assert!(parse_duck_rules(rules).is_err(), "{}", rules);
+        }
+    }
+}
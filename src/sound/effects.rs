@@ -0,0 +1,590 @@
+use super::*;
+use std::f32::consts::{PI, SQRT_2};
+
+/// Lowest and highest cutoff of the filters, in Hz.
+pub const MIN_CUTOFF: f32 = 20.0;
+pub const MAX_CUTOFF: f32 = 20000.0;
+/// Lowest and highest gain of the equalizer's bands, in decibels.
+pub const MIN_EQ_GAIN: f32 = -24.0;
+pub const MAX_EQ_GAIN: f32 = 12.0;
+/// Length of the transition when a channel's effects are changed in the UI, in miliseconds.
+pub const DEFAULT_EFFECTS_TRANSITION: usize = 50;
+/// Default length of the transition of a soundpack's effect changes, in miliseconds.
+pub const DEFAULT_EFFECT_CHANGE_TRANSITION: usize = 1000;
+/// Frequencies of the equalizer's low shelf, mid peak and high shelf, in Hz.
+const EQ_LOW_FREQ: f32 = 250.0;
+const EQ_MID_FREQ: f32 = 1000.0;
+const EQ_HIGH_FREQ: f32 = 4000.0;
+/// Time between two updates of the effects' parameters, in miliseconds.
+const EFFECTS_UPDATE_INTERVAL: usize = 5;
+/// Time the reverb keeps ringing after its send is turned off, in miliseconds.
+const REVERB_TAIL: usize = 3000;
+/// Delays of the reverb's comb and allpass filters, in samples at 44100Hz.
+const COMB_DELAYS: [usize; 4] = [1116, 1188, 1277, 1356];
+const ALLPASS_DELAYS: [usize; 2] = [556, 441];
+/// Extra delay of each channel's filters, so the sides of the reverb differ.
+const REVERB_SPREAD: usize = 23;
+const REVERB_FEEDBACK: f32 = 0.84;
+const REVERB_DAMPING: f32 = 0.2;
+/// Scales the reverb's output, so a full send is about as loud as the dry sound.
+const REVERB_GAIN: f32 = 0.06;
+
+/// A channel's effects: filters, a 3-band equalizer and a reverb.
+#[derive(Copy, Clone, PartialEq, Debug, Default, serde::Serialize)]
+#[serde(rename_all="camelCase")]
+pub struct EffectSettings {
+    /// Cutoff of the low-pass filter, in Hz. None if there's no low-pass filter.
+    pub lowpass: Option<f32>,
+    /// Cutoff of the high-pass filter, in Hz. None if there's no high-pass filter.
+    pub highpass: Option<f32>,
+    /// Gains of the equalizer's low, mid and high bands, in decibels.
+    pub eq_low: f32,
+    pub eq_mid: f32,
+    pub eq_high: f32,
+    /// Part of the channel sent to its reverb, from 0 to 1.
+    pub reverb: f32,
+}
+
+/// A change of a channel's effects, made when a sound's pattern matches.
+/// Effects it doesn't mention are at the channel's own settings.
+#[derive(Clone)]
+pub struct EffectChange {
+    pub channel: Box<str>,
+    pub lowpass: Option<f32>,
+    pub highpass: Option<f32>,
+    pub eq_low: Option<f32>,
+    pub eq_mid: Option<f32>,
+    pub eq_high: Option<f32>,
+    pub reverb: Option<f32>,
+    /// Time to move to the new settings, in miliseconds.
+    pub transition: usize,
+}
+
+impl EffectChange {
+    /// The settings to move to, from the channel's own settings.
+    pub fn apply(&self, base: EffectSettings) -> EffectSettings {
+        EffectSettings {
+            lowpass: self.lowpass.or(base.lowpass),
+            highpass: self.highpass.or(base.highpass),
+            eq_low: self.eq_low.unwrap_or(base.eq_low),
+            eq_mid: self.eq_mid.unwrap_or(base.eq_mid),
+            eq_high: self.eq_high.unwrap_or(base.eq_high),
+            reverb: self.reverb.unwrap_or(base.reverb),
+        }
+    }
+}
+
+/// The settings as numbers that can be interpolated.
+/// Cutoffs are in octaves, and disabled filters are at the edge of the range.
+#[derive(Copy, Clone, PartialEq)]
+struct EffectParams([f32; 6]);
+
+impl EffectParams {
+    const LOWPASS: usize = 0;
+    const HIGHPASS: usize = 1;
+    const EQ_LOW: usize = 2;
+    const EQ_MID: usize = 3;
+    const EQ_HIGH: usize = 4;
+    const REVERB: usize = 5;
+
+    fn new(settings: EffectSettings) -> Self {
+        let octaves = |cutoff: f32| cutoff.clamp(MIN_CUTOFF, MAX_CUTOFF).log2();
+        Self([
+            octaves(settings.lowpass.unwrap_or(MAX_CUTOFF)),
+            octaves(settings.highpass.unwrap_or(MIN_CUTOFF)),
+            settings.eq_low.clamp(MIN_EQ_GAIN, MAX_EQ_GAIN),
+            settings.eq_mid.clamp(MIN_EQ_GAIN, MAX_EQ_GAIN),
+            settings.eq_high.clamp(MIN_EQ_GAIN, MAX_EQ_GAIN),
+            settings.reverb.clamp(0.0, 1.0),
+        ])
+    }
+
+    #[inline]
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        let mut params = self.0;
+        for (param, to) in params.iter_mut().zip(other.0.iter()) {
+            *param += (to - *param) * t;
+        }
+        Self(params)
+    }
+}
+
+/// Shares a channel's effect settings between its bus and the sound thread.
+/// Each change comes with the time to move to it, in miliseconds.
+#[derive(Clone)]
+pub struct EffectsLock(Arc<ShardedLock<(EffectSettings, usize, usize)>>);
+impl EffectsLock {
+    #[inline]
+    pub fn new(settings: EffectSettings) -> Self {
+        Self(Arc::new(ShardedLock::new((settings, 0, 0))))
+    }
+    #[inline]
+    pub fn get(&self) -> EffectSettings {
+        self.0.read().unwrap().0
+    }
+    #[inline]
+    pub fn set(&self, settings: EffectSettings, transition: usize) {
+        let mut lock = self.0.write().unwrap();
+        *lock = (settings, transition, lock.2.wrapping_add(1));
+    }
+    /// The settings, the time to move to them, and a number changed by every `set`.
+    #[inline]
+    fn target(&self) -> (EffectSettings, usize, usize) {
+        *self.0.read().unwrap()
+    }
+}
+
+enum FilterKind {
+    LowPass,
+    HighPass,
+    LowShelf,
+    Peak,
+    HighShelf,
+}
+
+/// A state variable filter, with trapezoidal integration as described by Andrew Simper,
+/// and a state for each channel. Unlike a biquad's, its state stays valid while it's modulated.
+struct Filter {
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    /// Mix of the input, band-pass and low-pass outputs.
+    m0: f32,
+    m1: f32,
+    m2: f32,
+    state: Vec<[f32; 2]>,
+}
+
+impl Filter {
+    fn new(channels: usize) -> Self {
+        Self {
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            m0: 1.0,
+            m1: 0.0,
+            m2: 0.0,
+            state: vec![[0.0; 2]; channels],
+        }
+    }
+
+    /// Compute the coefficients. `gain` is in decibels, and only used by shelves and peaks.
+    fn set(&mut self, kind: FilterKind, freq: f32, gain: f32, sample_rate: u32) {
+        let g = (PI * freq.min(sample_rate as f32 * 0.45) / sample_rate as f32).tan();
+        let a = 10f32.powf(gain / 40.0);
+        // Q of 1/sqrt(2), so k is 1/Q.
+        let k = SQRT_2;
+        let (g, k, m0, m1, m2) = match kind {
+            FilterKind::LowPass => (g, k, 0.0, 0.0, 1.0),
+            FilterKind::HighPass => (g, k, 1.0, -k, -1.0),
+            FilterKind::Peak => (g, k / a, 1.0, k / a * (a * a - 1.0), 0.0),
+            FilterKind::LowShelf => (g / a.sqrt(), k, 1.0, k * (a - 1.0), a * a - 1.0),
+            FilterKind::HighShelf => (g * a.sqrt(), k, a * a, k * (1.0 - a) * a, 1.0 - a * a),
+        };
+        self.a1 = 1.0 / (1.0 + g * (g + k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+        self.m0 = m0;
+        self.m1 = m1;
+        self.m2 = m2;
+    }
+
+    #[inline]
+    fn process(&mut self, channel: usize, x: f32) -> f32 {
+        let [ic1, ic2] = &mut self.state[channel];
+        let v3 = x - *ic2;
+        let v1 = self.a1 * *ic1 + self.a2 * v3;
+        let v2 = *ic2 + self.a2 * *ic1 + self.a3 * v3;
+        *ic1 = 2.0 * v1 - *ic1;
+        *ic2 = 2.0 * v2 - *ic2;
+        self.m0 * x + self.m1 * v1 + self.m2 * v2
+    }
+}
+
+/// A damped comb filter, as in Freeverb.
+struct Comb {
+    buffer: Vec<f32>,
+    pos: usize,
+    damped: f32,
+}
+
+impl Comb {
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let out = self.buffer[self.pos];
+        self.damped = out * (1.0 - REVERB_DAMPING) + self.damped * REVERB_DAMPING;
+        self.buffer[self.pos] = x + self.damped * REVERB_FEEDBACK;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        out
+    }
+}
+
+struct AllPass {
+    buffer: Vec<f32>,
+    pos: usize,
+}
+
+impl AllPass {
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let delayed = self.buffer[self.pos];
+        self.buffer[self.pos] = x + delayed * 0.5;
+        self.pos = (self.pos + 1) % self.buffer.len();
+        delayed - x
+    }
+}
+
+/// A small Schroeder reverb for each channel: parallel combs, then allpasses in series.
+struct Reverb {
+    combs: Vec<Vec<Comb>>,
+    allpasses: Vec<Vec<AllPass>>,
+}
+
+impl Reverb {
+    fn new(channels: usize, sample_rate: u32) -> Self {
+        let scale = |delay: usize, channel: usize| {
+            ((delay + channel * REVERB_SPREAD) * sample_rate as usize / 44100).max(1)
+        };
+        Self {
+            combs: (0..channels)
+                .map(|channel| COMB_DELAYS.iter()
+                    .map(|&delay| Comb {
+                        buffer: vec![0.0; scale(delay, channel)],
+                        pos: 0,
+                        damped: 0.0,
+                    })
+                    .collect()
+                )
+                .collect(),
+            allpasses: (0..channels)
+                .map(|channel| ALLPASS_DELAYS.iter()
+                    .map(|&delay| AllPass {
+                        buffer: vec![0.0; scale(delay, channel)],
+                        pos: 0,
+                    })
+                    .collect()
+                )
+                .collect(),
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, channel: usize, x: f32) -> f32 {
+        let mut out = self.combs[channel].iter_mut()
+            .map(|comb| comb.process(x))
+            .sum::<f32>();
+        for allpass in self.allpasses[channel].iter_mut() {
+            out = allpass.process(out);
+        }
+        out * REVERB_GAIN
+    }
+}
+
+/// Source wrapper applying a channel's effects: high-pass, low-pass, equalizer, then reverb.
+/// The settings are read from an EffectsLock, and changes are interpolated over their transition.
+/// While the settings are neutral, samples go through untouched.
+pub struct Effects<I> {
+    input: I,
+    lock: EffectsLock,
+    channels: usize,
+    sample_rate: u32,
+    /// Number changed by every change of the lock's settings.
+    version: usize,
+    /// Parameters in use, and the transition they're in.
+    current: EffectParams,
+    from: EffectParams,
+    target: EffectParams,
+    /// Progress and length of the transition, in frames.
+    elapsed: usize,
+    transition: usize,
+    /// Frames until the next update of the parameters.
+    until_update: usize,
+    /// Whether the filters run. They're crossfaded in and out, so they never click.
+    engaged: bool,
+    /// Frames left in the crossfade from the untouched samples to the filtered ones, once they start.
+    engaging: usize,
+    /// Frames left in the crossfade back to the untouched samples,
+    /// once the settings are neutral again and the reverb has rung out.
+    disengaging: usize,
+    filters: [Filter; 5],
+    reverb: Option<Reverb>,
+    /// Frames the reverb keeps ringing after its send is turned off.
+    reverb_tail: usize,
+    /// Position in the current frame.
+    frame_pos: usize,
+}
+
+impl<I> Effects<I>
+where
+    I: Source<Item=f32>,
+{
+    pub fn new(input: I, lock: EffectsLock) -> Self {
+        let channels = input.channels().max(1) as usize;
+        let sample_rate = input.sample_rate().max(1);
+        let (settings, _, version) = lock.target();
+        let params = EffectParams::new(settings);
+        let mut effects = Self {
+            input,
+            lock,
+            channels,
+            sample_rate,
+            version,
+            current: params,
+            from: params,
+            target: params,
+            elapsed: 0,
+            transition: 0,
+            until_update: 0,
+            engaged: false,
+            engaging: 0,
+            disengaging: 0,
+            filters: [
+                Filter::new(channels),
+                Filter::new(channels),
+                Filter::new(channels),
+                Filter::new(channels),
+                Filter::new(channels),
+            ],
+            reverb: None,
+            reverb_tail: 0,
+            frame_pos: 0,
+        };
+        effects.apply_params();
+        effects
+    }
+
+    /// Length of EFFECTS_UPDATE_INTERVAL, in frames.
+    #[inline]
+    fn interval(&self) -> usize {
+        (EFFECTS_UPDATE_INTERVAL * self.sample_rate as usize / 1000).max(1)
+    }
+
+    /// Pick up new settings, and move the parameters along the transition.
+    fn update(&mut self) {
+        let interval = self.interval();
+        self.until_update = interval;
+        let (settings, transition, version) = self.lock.target();
+        if version != self.version {
+            self.version = version;
+            self.from = self.current;
+            self.target = EffectParams::new(settings);
+            self.elapsed = 0;
+            self.transition = transition * self.sample_rate as usize / 1000;
+        }
+        else if self.current == self.target {
+            if self.engaged && self.engaging == 0 && self.disengaging == 0
+                && self.reverb_tail == 0 && self.is_neutral()
+            {
+                self.disengaging = interval;
+            }
+            return
+        }
+        else {
+            self.elapsed += interval;
+        }
+        self.current = if self.elapsed >= self.transition {
+            self.target
+        } else {
+            self.from.lerp(&self.target, self.elapsed as f32 / self.transition as f32)
+        };
+        self.apply_params();
+    }
+
+    /// Whether the parameters in use change nothing.
+    #[inline]
+    fn is_neutral(&self) -> bool {
+        self.current == EffectParams::new(EffectSettings::default())
+    }
+
+    fn apply_params(&mut self) {
+        let params = self.current.0;
+        let rate = self.sample_rate;
+        if !self.engaged {
+            if self.is_neutral() {
+                return
+            }
+            self.engaged = true;
+            self.engaging = self.interval();
+        } else if self.disengaging > 0 && !self.is_neutral() {
+            // Changed again before the filters were bypassed: crossfade back from where it is.
+            self.engaging = self.interval() - self.disengaging;
+            self.disengaging = 0;
+        }
+        self.filters[0].set(FilterKind::HighPass, params[EffectParams::HIGHPASS].exp2(), 0.0, rate);
+        self.filters[1].set(FilterKind::LowPass, params[EffectParams::LOWPASS].exp2(), 0.0, rate);
+        self.filters[2].set(FilterKind::LowShelf, EQ_LOW_FREQ, params[EffectParams::EQ_LOW], rate);
+        self.filters[3].set(FilterKind::Peak, EQ_MID_FREQ, params[EffectParams::EQ_MID], rate);
+        self.filters[4].set(FilterKind::HighShelf, EQ_HIGH_FREQ, params[EffectParams::EQ_HIGH], rate);
+        if params[EffectParams::REVERB] > 0.0 {
+            if self.reverb.is_none() {
+                self.reverb = Some(Reverb::new(self.channels, rate));
+            }
+            self.reverb_tail = REVERB_TAIL * rate as usize / 1000;
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, channel: usize, x: f32) -> f32 {
+        let mut y = x;
+        for filter in self.filters.iter_mut() {
+            y = filter.process(channel, y);
+        }
+        if self.reverb_tail > 0 {
+            if let Some(reverb) = &mut self.reverb {
+                y += reverb.process(channel, y * self.current.0[EffectParams::REVERB]);
+            }
+        }
+        if self.engaging > 0 {
+            // The filters' state starts empty, so their first samples are faded in.
+            let mix = 1.0 - self.engaging as f32 / self.interval() as f32;
+            y = x + (y - x) * mix;
+        } else if self.disengaging > 0 {
+            let mix = self.disengaging as f32 / self.interval() as f32;
+            y = x + (y - x) * mix;
+        }
+        y
+    }
+
+    /// Bypass the filters and the reverb, starting them again from silence if they're needed later.
+    fn disengage(&mut self) {
+        self.engaged = false;
+        for filter in self.filters.iter_mut() {
+            filter.state.iter_mut().for_each(|state| *state = [0.0; 2]);
+        }
+        self.reverb = None;
+    }
+}
+
+impl<I> Iterator for Effects<I>
+where
+    I: Source<Item=f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let channel = self.frame_pos;
+        if channel == 0 {
+            if self.until_update == 0 {
+                self.update();
+            }
+            self.until_update -= 1;
+            self.engaging = self.engaging.saturating_sub(1);
+            if self.disengaging > 0 {
+                self.disengaging -= 1;
+                if self.disengaging == 0 {
+                    self.disengage();
+                }
+            }
+            if self.current.0[EffectParams::REVERB] <= 0.0 {
+                self.reverb_tail = self.reverb_tail.saturating_sub(1);
+            }
+        }
+        self.frame_pos = (self.frame_pos + 1) % self.channels;
+        let sample = self.input.next()?;
+        if self.engaged {
+            Some(self.process(channel, sample))
+        } else {
+            Some(sample)
+        }
+    }
+}
+
+impl<I> Source for Effects<I>
+where
+    I: Source<Item=f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    const RATE: u32 = 44100;
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (i as f32 * frequency * std::f32::consts::TAU / RATE as f32).sin() * 0.5)
+            .collect()
+    }
+
+    fn effects(samples: Vec<f32>, lock: &EffectsLock) -> Effects<buffer::SamplesBuffer<f32>> {
+        Effects::new(buffer::SamplesBuffer::new(1, RATE, samples), lock.clone())
+    }
+
+    /// Gain of the settings at this frequency, once the filters settled.
+    fn response(settings: EffectSettings, frequency: f32) -> f32 {
+        let lock = EffectsLock::new(settings);
+        let output: Vec<f32> = effects(sine(frequency, RATE as usize / 2), &lock).collect();
+        let peak = output[RATE as usize / 4..].iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        peak / 0.5
+    }
+
+    #[test]
+    fn filters_respond_at_their_cutoff() {
+        let lowpass = EffectSettings {lowpass: Some(1000.0), ..EffectSettings::default()};
+        assert!((response(lowpass, 100.0) - 1.0).abs() < 0.02);
+        assert!((response(lowpass, 1000.0) - FRAC_1_SQRT_2).abs() < 0.02);
+        assert!(response(lowpass, 8000.0) < 0.02);
+        let highpass = EffectSettings {highpass: Some(1000.0), ..EffectSettings::default()};
+        assert!((response(highpass, 10000.0) - 1.0).abs() < 0.02);
+        assert!((response(highpass, 1000.0) - FRAC_1_SQRT_2).abs() < 0.02);
+        assert!(response(highpass, 125.0) < 0.02);
+        let eq = EffectSettings {eq_mid: 6.0, ..EffectSettings::default()};
+        assert!((gain_to_db(response(eq, EQ_MID_FREQ)) - 6.0).abs() < 0.1);
+        assert!(gain_to_db(response(eq, 50.0)).abs() < 0.1);
+    }
+
+    #[test]
+    fn neutral_settings_change_nothing() {
+        let input = sine(440.0, RATE as usize / 10);
+        let lock = EffectsLock::new(EffectSettings::default());
+        assert_eq!(effects(input.clone(), &lock).collect::<Vec<f32>>(), input);
+
+        // Back to neutral after a change: bypassed once the filters are crossfaded out.
+        let input = sine(440.0, RATE as usize);
+        let lock = EffectsLock::new(EffectSettings {lowpass: Some(200.0), ..EffectSettings::default()});
+        let mut effects = effects(input.clone(), &lock);
+        let filtered: Vec<f32> = effects.by_ref().take(RATE as usize / 4).collect();
+        assert!(filtered.iter().zip(&input).any(|(out, input)| (out - input).abs() > 0.1));
+        lock.set(EffectSettings::default(), 0);
+        let rest: Vec<f32> = effects.by_ref().collect();
+        let bypassed = RATE as usize / 10;
+        assert_eq!(&rest[bypassed..], &input[RATE as usize / 4 + bypassed..]);
+        assert!(!effects.engaged);
+    }
+
+    #[test]
+    fn reverb_tail_decays() {
+        let mut input = vec![0.0; RATE as usize * 2];
+        input[0] = 1.0;
+        let lock = EffectsLock::new(EffectSettings {reverb: 1.0, ..EffectSettings::default()});
+        let output: Vec<f32> = effects(input, &lock).collect();
+        let energy: Vec<f32> = output[1..].chunks(RATE as usize / 4)
+            .map(|window| window.iter().map(|sample| sample * sample).sum())
+            .collect();
+        assert!(energy[0] > 0.0);
+        assert!(energy.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", energy);
+        assert!(energy[energy.len() - 1] < energy[0] * 0.001, "{:?}", energy);
+    }
+}
@@ -0,0 +1,432 @@
+use super::*;
+use std::sync::atomic::AtomicU32;
+
+/// Default length of the ramps used when pausing, resuming, skipping or stopping, in miliseconds.
+pub const DEFAULT_FADE_LENGTH: usize = 25;
+
+/// Shared state between a FadeControl and its Fade source.
+struct FadeState {
+    /// Gain the source starts with.
+    initial: f32,
+    /// Incremented for every new fade, so the source knows to pick it up.
+    generation: AtomicUsize,
+    /// Gain to fade to, as f32 bits.
+    target: AtomicU32,
+    /// Length of the fade, in miliseconds.
+    duration: AtomicUsize,
+    /// Whether the source should end once it faded to silence.
+    finish: AtomicBool,
+    /// Set when the source has ended, or was dropped.
+    finished: AtomicBool,
+}
+
+/// Handle used by the sound thread to fade a source in or out.
+/// The fade itself is done by the source, one sample at a time.
+#[derive(Clone)]
+pub struct FadeControl(Arc<FadeState>);
+
+impl FadeControl {
+    /// Create a control for a source that starts with the given gain.
+    pub fn new(initial: f32) -> Self {
+        Self(Arc::new(FadeState {
+            initial,
+            generation: AtomicUsize::new(0),
+            target: AtomicU32::new(initial.to_bits()),
+            duration: AtomicUsize::new(0),
+            finish: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+        }))
+    }
+
+    /// Fade from the current gain to `target`, over `duration` miliseconds.
+    pub fn fade_to(&self, target: f32, duration: usize) {
+        self.command(target, duration, false);
+    }
+
+    /// Fade to silence over `duration` miliseconds, then end the source.
+    pub fn fade_out(&self, duration: usize) {
+        self.command(0.0, duration, true);
+    }
+
+    /// Whether the source has ended.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.0.finished.load(Ordering::Relaxed)
+    }
+
+    fn command(&self, target: f32, duration: usize, finish: bool) {
+        self.0.target.store(target.to_bits(), Ordering::Relaxed);
+        self.0.duration.store(duration, Ordering::Relaxed);
+        self.0.finish.store(finish, Ordering::Relaxed);
+        self.0.generation.fetch_add(1, Ordering::Release);
+    }
+}
+
+/// Source wrapper applying the fades requested through a FadeControl.
+/// The gain moves linearly, by the same amount every sample.
+pub struct Fade<I> {
+    input: I,
+    control: FadeControl,
+    /// The last command that was picked up.
+    generation: usize,
+    gain: f32,
+    target: f32,
+    step: f32,
+    finishing: bool,
+}
+
+impl<I> Fade<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    pub fn new(input: I, control: FadeControl) -> Self {
+        let gain = control.0.initial;
+        Self {
+            input,
+            control,
+            generation: 0,
+            gain,
+            target: gain,
+            step: 0.0,
+            finishing: false,
+        }
+    }
+
+    /// Pick up a new command, if there's one.
+    #[inline]
+    fn update(&mut self) {
+        let state = &self.control.0;
+        let generation = state.generation.load(Ordering::Acquire);
+        if generation == self.generation {
+            return
+        }
+        self.generation = generation;
+        self.target = f32::from_bits(state.target.load(Ordering::Relaxed));
+        self.finishing = state.finish.load(Ordering::Relaxed);
+        let samples = state.duration.load(Ordering::Relaxed)
+            * self.input.sample_rate() as usize
+            * self.input.channels() as usize
+            / 1000;
+        if samples == 0 {
+            self.gain = self.target;
+            self.step = 0.0;
+        } else {
+            self.step = (self.target - self.gain) / samples as f32;
+        }
+    }
+}
+
+impl<I> Iterator for Fade<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        self.update();
+        if self.finishing && self.gain <= 0.0 {
+            self.control.0.finished.store(true, Ordering::Relaxed);
+            return None
+        }
+        let sample = self.input.next()?;
+        if self.gain != self.target {
+            self.gain += self.step;
+            if (self.step > 0.0 && self.gain >= self.target)
+            || (self.step <= 0.0 && self.gain <= self.target) {
+                self.gain = self.target;
+            }
+        }
+        Some(sample.amplify(self.gain))
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I> Source for Fade<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Drop for Fade<I> {
+    fn drop(&mut self) {
+        self.control.0.finished.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Source wrapper replacing `pausable`, `amplify` and `stoppable`,
+/// ramping the volume instead of cutting the sound, so there are no clicks.
+/// Meant to be driven from a `periodic_access` callback.
+pub struct Ramp<I> {
+    input: I,
+    /// How much the gain can change every sample.
+    step: f32,
+    /// Volume set by the last `set_factor`.
+    factor: f32,
+    /// Current gain.
+    gain: f32,
+    paused: bool,
+    stopping: bool,
+    /// Whether a sample was played. Until then, changes are applied without ramping.
+    started: bool,
+    /// Position in the current frame, so pauses and stops happen between frames.
+    frame_pos: u16,
+    /// Whether the current frame is silence, held while paused.
+    holding: bool,
+}
+
+impl<I> Ramp<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    /// `length` is how long a ramp between silence and full volume takes, in miliseconds.
+    pub fn new(input: I, length: usize) -> Self {
+        let mut ramp = Self {
+            input,
+            step: 0.0,
+            factor: 1.0,
+            gain: 1.0,
+            paused: false,
+            stopping: false,
+            started: false,
+            frame_pos: 0,
+            holding: false,
+        };
+        ramp.set_length(length);
+        ramp
+    }
+
+    /// Change how long a ramp between silence and full volume takes, in miliseconds.
+    #[inline]
+    pub fn set_length(&mut self, length: usize) {
+        let samples = length
+            * self.input.sample_rate() as usize
+            * self.input.channels() as usize
+            / 1000;
+        self.step = if samples == 0 {f32::INFINITY} else {1.0 / samples as f32};
+    }
+
+    #[inline]
+    pub fn set_factor(&mut self, factor: f32) {
+        self.factor = factor;
+    }
+
+    /// Fade out then hold the position, or fade back in.
+    #[inline]
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Fade out, then end the source.
+    #[inline]
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+}
+
+impl<I> Iterator for Ramp<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        if self.frame_pos == 0 {
+            self.holding = false;
+        }
+        if !self.holding {
+            let target = if self.paused || self.stopping {0.0} else {self.factor};
+            if !self.started {
+                self.gain = target;
+            }
+            else if self.gain < target {
+                self.gain = (self.gain + self.step).min(target);
+            }
+            else if self.gain > target {
+                self.gain = (self.gain - self.step).max(target);
+            }
+            if self.gain == 0.0 && self.frame_pos == 0 {
+                if self.stopping {
+                    return None
+                }
+                // Silence is held a whole frame at a time, so resuming starts on a new frame.
+                self.holding = self.paused;
+            }
+        }
+        let sample = if self.holding {
+            I::Item::zero_value()
+        } else {
+            let sample = self.input.next()?;
+            self.started = true;
+            sample.amplify(self.gain)
+        };
+        self.frame_pos = (self.frame_pos + 1) % self.input.channels().max(1);
+        Some(sample)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+impl<I> Source for Ramp<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mono source of ones, at 1kHz so a milisecond is a sample.
+    fn ones(len: usize) -> buffer::SamplesBuffer<f32> {
+        buffer::SamplesBuffer::new(1, 1000, vec![1.0; len])
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn fade_follows_the_latest_command() {
+        let control = FadeControl::new(0.0);
+        let mut fade = Fade::new(ones(100), control.clone());
+        assert_eq!(fade.next(), Some(0.0));
+        control.fade_to(1.0, 4);
+        assert_close(&fade.by_ref().take(5).collect::<Vec<f32>>(), &[0.25, 0.5, 0.75, 1.0, 1.0]);
+        // Commands sent between two samples: only the last one is picked up.
+        control.fade_to(0.0, 10);
+        control.fade_to(0.5, 0);
+        assert_close(&fade.by_ref().take(2).collect::<Vec<f32>>(), &[0.5, 0.5]);
+        // A new fade starts from where the last one is.
+        control.fade_to(1.0, 10);
+        fade.by_ref().take(5).count();
+        control.fade_to(0.0, 3);
+        assert_close(&fade.by_ref().take(3).collect::<Vec<f32>>(), &[0.5, 0.25, 0.0]);
+        assert!(!control.is_finished());
+    }
+
+    #[test]
+    fn fade_out_ends_the_source() {
+        let control = FadeControl::new(1.0);
+        let mut fade = Fade::new(ones(100), control.clone());
+        control.fade_out(4);
+        assert_close(&fade.by_ref().collect::<Vec<f32>>(), &[0.75, 0.5, 0.25, 0.0]);
+        assert!(control.is_finished());
+
+        // Dropped sources count as finished.
+        let control = FadeControl::new(1.0);
+        let fade = Fade::new(ones(100), control.clone());
+        assert!(!control.is_finished());
+        drop(fade);
+        assert!(control.is_finished());
+    }
+
+    /// A stereo source at 1.5kHz, with positive samples on the left and negative ones on the right.
+    fn sides(frames: usize) -> buffer::SamplesBuffer<f32> {
+        let samples = (0..frames).flat_map(|_| vec![1.0, -1.0]).collect::<Vec<f32>>();
+        buffer::SamplesBuffer::new(2, 1500, samples)
+    }
+
+    /// Whether every sample is on its side.
+    fn is_aligned(samples: &[f32]) -> bool {
+        samples.iter().enumerate()
+            .all(|(i, &sample)| if i % 2 == 0 {sample >= 0.0} else {sample <= 0.0})
+    }
+
+    #[test]
+    fn ramp_pauses_between_frames() {
+        // A ramp of 1ms is 3 samples long, so it can end in the middle of a frame.
+        for offset in 0..4 {
+            let mut ramp = Ramp::new(sides(100), 1);
+            let mut played: Vec<f32> = ramp.by_ref().take(offset).collect();
+            ramp.set_paused(true);
+            played.extend(ramp.by_ref().take(10));
+            // Silent once faded out, at the end of a frame.
+            let silent = played.iter().rposition(|&sample| sample != 0.0).map_or(0, |last| last + 1);
+            assert!(silent <= offset + 4, "{:?}", played);
+            ramp.set_paused(false);
+            played.extend(ramp.by_ref().take(20));
+            assert!(is_aligned(&played), "{:?}", played);
+            // Full volume again.
+            assert!(played[played.len() - 2..].iter().all(|sample| sample.abs() == 1.0), "{:?}", played);
+        }
+    }
+
+    #[test]
+    fn ramp_stops_between_frames() {
+        for offset in 0..4 {
+            let mut ramp = Ramp::new(sides(100), 1);
+            let mut played: Vec<f32> = ramp.by_ref().take(offset).collect();
+            ramp.stop();
+            played.extend(ramp.by_ref());
+            assert_eq!(played.len() % 2, 0, "{:?}", played);
+            assert!(played.len() <= offset + 4, "{:?}", played);
+            assert!(is_aligned(&played), "{:?}", played);
+        }
+    }
+
+    #[test]
+    fn ramp_changes_the_volume_smoothly() {
+        let mut ramp = Ramp::new(ones(100), 4);
+        // Before the first sample, without ramping.
+        ramp.set_factor(0.5);
+        assert_eq!(ramp.next(), Some(0.5));
+        ramp.set_factor(1.0);
+        assert_close(&ramp.by_ref().take(3).collect::<Vec<f32>>(), &[0.75, 1.0, 1.0]);
+    }
+}
@@ -0,0 +1,309 @@
+use super::*;
+use std::collections::VecDeque;
+
+/// Default ceiling of the master limiter, in dBFS.
+pub const DEFAULT_LIMITER_CEILING: f32 = -1.0;
+/// How far ahead the limiter looks for peaks, in miliseconds.
+const LIMITER_LOOKAHEAD: usize = 5;
+/// Time for the limiter's gain to come back up after a peak, in miliseconds.
+const LIMITER_RELEASE: usize = 100;
+/// Number of frames between two updates of the gain reduction meter.
+const METER_BLOCK_FRAMES: usize = 512;
+
+/// Shows the strongest gain reduction applied by limiters.
+/// Shared by the limiters of a master bus and whoever reads it.
+#[derive(Clone)]
+pub struct LimiterMeter(Arc<AtomicU32>);
+
+impl LimiterMeter {
+    #[inline]
+    pub fn new() -> Self {
+        Self(Arc::new(AtomicU32::new(1.0f32.to_bits())))
+    }
+
+    /// Record a gain applied by a limiter.
+    fn record(&self, gain: f32) {
+        let _ = self.0.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+            if gain < f32::from_bits(current) {
+                Some(gain.to_bits())
+            } else {
+                None
+            }
+        });
+    }
+
+    /// Get the strongest gain reduction since the last call, in decibels. 0 means nothing was reduced.
+    pub fn poll(&self) -> f32 {
+        let gain = f32::from_bits(self.0.swap(1.0f32.to_bits(), Ordering::Relaxed));
+        -gain_to_db(gain)
+    }
+}
+
+/// Source wrapper keeping the peaks of a source under a ceiling.
+/// The source is delayed by LIMITER_LOOKAHEAD, so the gain is already lowered when a peak comes,
+/// then comes back up over LIMITER_RELEASE. Samples still over the ceiling are clipped, as a last resort.
+pub struct Limiter<I> {
+    input: I,
+    /// Linear ceiling.
+    ceiling: f32,
+    channels: usize,
+    /// Length of the lookahead, in frames.
+    lookahead: usize,
+    /// Delayed samples.
+    delayed: VecDeque<f32>,
+    /// Gains the delayed frames need, with their frame number.
+    /// Only the gains that can still be the smallest are kept, smallest first.
+    needed: VecDeque<(usize, f32)>,
+    /// Number of frames read from the input.
+    frames_read: usize,
+    /// Frames left to output after the input ended.
+    tail: Option<usize>,
+    /// Gain currently applied.
+    gain: f32,
+    attack_coef: f32,
+    release_coef: f32,
+    /// The frame being output.
+    frame: Vec<f32>,
+    frame_pos: usize,
+    meter: Option<LimiterMeter>,
+    /// Smallest gain since the meter was last updated.
+    meter_gain: f32,
+    meter_frames: usize,
+}
+
+impl<I> Limiter<I>
+where
+    I: Source<Item=f32>,
+{
+    /// `ceiling` is in dBFS. If there's a meter, the gain reduction is recorded into it.
+    pub fn new(input: I, ceiling: f32, meter: Option<LimiterMeter>) -> Self {
+        let channels = input.channels().max(1) as usize;
+        let sample_rate = input.sample_rate().max(1) as usize;
+        let lookahead = (LIMITER_LOOKAHEAD * sample_rate / 1000).max(1);
+        let release = (LIMITER_RELEASE * sample_rate / 1000).max(1);
+        Self {
+            input,
+            ceiling: db_to_gain(ceiling),
+            channels,
+            lookahead,
+            delayed: VecDeque::with_capacity((lookahead + 1) * channels),
+            needed: VecDeque::new(),
+            frames_read: 0,
+            tail: None,
+            gain: 1.0,
+            // Reaches the needed gain within the lookahead.
+            attack_coef: (-5.0 / lookahead as f32).exp(),
+            release_coef: (-1.0 / release as f32).exp(),
+            frame: Vec::with_capacity(channels),
+            frame_pos: 0,
+            meter,
+            meter_gain: 1.0,
+            meter_frames: 0,
+        }
+    }
+
+    /// Read a frame from the input, and output the frame leaving the lookahead.
+    /// Returns false once every frame was output.
+    fn process_frame(&mut self) -> bool {
+        if self.tail.is_none() {
+            let mut peak: f32 = 0.0;
+            for idx in 0..self.channels {
+                match self.input.next() {
+                    Some(sample) => {
+                        peak = peak.max(sample.abs());
+                        self.delayed.push_back(sample);
+                    }
+                    None => {
+                        // Complete the last frame, then output what's left in the lookahead.
+                        if idx > 0 {
+                            self.delayed.resize(self.delayed.len() + self.channels - idx, 0.0);
+                        }
+                        self.tail = Some(self.delayed.len() / self.channels);
+                        break
+                    }
+                }
+            }
+            if self.tail.is_none() {
+                let needed = if peak > self.ceiling {self.ceiling / peak} else {1.0};
+                while matches!(self.needed.back(), Some((_, gain)) if *gain >= needed) {
+                    self.needed.pop_back();
+                }
+                self.needed.push_back((self.frames_read, needed));
+                self.frames_read += 1;
+                if self.delayed.len() <= self.lookahead * self.channels {
+                    // Still filling the lookahead.
+                    self.frame.clear();
+                    self.frame.resize(self.channels, 0.0);
+                    return true
+                }
+            }
+        }
+        if let Some(tail) = &mut self.tail {
+            if *tail == 0 {
+                return false
+            }
+            *tail -= 1;
+        }
+        // Frames that left the lookahead don't matter anymore.
+        let oldest = match self.tail {
+            Some(tail) => self.frames_read.saturating_sub(tail + 1),
+            None => self.frames_read.saturating_sub(self.lookahead + 1),
+        };
+        while matches!(self.needed.front(), Some((frame, _)) if *frame < oldest) {
+            self.needed.pop_front();
+        }
+        let target = self.needed.front().map_or(1.0, |(_, gain)| *gain);
+        let coef = if target < self.gain {self.attack_coef} else {self.release_coef};
+        self.gain = target + (self.gain - target) * coef;
+        self.frame.clear();
+        for _ in 0..self.channels {
+            let sample = self.delayed.pop_front().unwrap_or(0.0) * self.gain;
+            self.frame.push(sample.clamp(-self.ceiling, self.ceiling));
+        }
+        self.update_meter();
+        true
+    }
+
+    #[inline]
+    fn update_meter(&mut self) {
+        if let Some(meter) = &self.meter {
+            self.meter_gain = self.meter_gain.min(self.gain);
+            self.meter_frames += 1;
+            if self.meter_frames >= METER_BLOCK_FRAMES {
+                meter.record(self.meter_gain);
+                self.meter_gain = 1.0;
+                self.meter_frames = 0;
+            }
+        }
+    }
+}
+
+impl<I> Iterator for Limiter<I>
+where
+    I: Source<Item=f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_pos >= self.frame.len() {
+            if !self.process_frame() {
+                return None
+            }
+            self.frame_pos = 0;
+        }
+        let sample = self.frame[self.frame_pos];
+        self.frame_pos += 1;
+        Some(sample)
+    }
+}
+
+impl<I> Source for Limiter<I>
+where
+    I: Source<Item=f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 44100;
+    const LOOKAHEAD_FRAMES: usize = LIMITER_LOOKAHEAD * RATE / 1000;
+
+    fn limit(channels: u16, samples: Vec<f32>, meter: Option<LimiterMeter>) -> Vec<f32> {
+        let source = buffer::SamplesBuffer::new(channels, RATE as u32, samples);
+        Limiter::new(source, DEFAULT_LIMITER_CEILING, meter).collect()
+    }
+
+    fn sine(frames: usize, amplitude: f32) -> Vec<f32> {
+        (0..frames)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / RATE as f32).sin() * amplitude)
+            .collect()
+    }
+
+    #[test]
+    fn never_exceeds_the_ceiling() {
+        let ceiling = db_to_gain(DEFAULT_LIMITER_CEILING);
+        let mut noise: u32 = 12345;
+        let input: Vec<f32> = (0..RATE)
+            .map(|i| {
+                noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let sample = (noise >> 16) as f32 / 32768.0 - 1.0;
+                // Quiet, then loud, then sudden peaks.
+                match i * 3 / RATE {
+                    0 => sample * 0.2,
+                    1 => sample * 4.0,
+                    _ => if i % 1000 == 0 {8.0} else {sample * 0.2},
+                }
+            })
+            .collect();
+        let output = limit(2, input, None);
+        assert!(output.iter().all(|sample| sample.abs() <= ceiling), "{}", output.iter().fold(0.0f32, |a, b| a.max(b.abs())));
+    }
+
+    #[test]
+    fn lowers_the_gain_before_a_peak() {
+        let mut input = vec![0.5; RATE / 10];
+        input[RATE / 20] = 2.0;
+        let output = limit(1, input, None);
+        let peak = RATE / 20 + LOOKAHEAD_FRAMES;
+        // Already lowered when the peak comes, so it's barely clipped.
+        assert!(output[peak - 1] < 0.5 * 0.5);
+        assert!(output[peak] > db_to_gain(DEFAULT_LIMITER_CEILING) * 0.9);
+    }
+
+    #[test]
+    fn passes_quieter_sources_through() {
+        let input = sine(RATE / 10, 0.8);
+        let output = limit(1, input.clone(), None);
+        assert_eq!(output.len(), input.len() + LOOKAHEAD_FRAMES);
+        assert!(output[..LOOKAHEAD_FRAMES].iter().all(|&sample| sample == 0.0));
+        assert_eq!(&output[LOOKAHEAD_FRAMES..], &input[..]);
+
+        // Frames stay together.
+        let stereo: Vec<f32> = input.iter().flat_map(|&sample| vec![sample, -sample]).collect();
+        let output = limit(2, stereo.clone(), None);
+        assert_eq!(&output[LOOKAHEAD_FRAMES * 2..], &stereo[..]);
+    }
+
+    #[test]
+    fn recovers_after_the_release() {
+        let mut input = sine(RATE, 0.5);
+        input[RATE / 10] = 4.0;
+        let meter = LimiterMeter::new();
+        let output = limit(1, input.clone(), Some(meter.clone()));
+        // About 13dB were needed to bring the peak under the ceiling.
+        assert!((meter.poll() + DEFAULT_LIMITER_CEILING - gain_to_db(4.0)).abs() < 0.5);
+        assert_eq!(meter.poll(), 0.0);
+        // Still reduced at first.
+        let after_peak = RATE / 10 + LOOKAHEAD_FRAMES + RATE / 1000;
+        assert!(output[after_peak..after_peak + RATE / 100].iter()
+            .zip(&input[after_peak - LOOKAHEAD_FRAMES..])
+            .any(|(out, input)| out.abs() < input.abs() * 0.5));
+        // Back to unity after a few release times.
+        let recovered = RATE / 10 + LOOKAHEAD_FRAMES + LIMITER_RELEASE * 8 * RATE / 1000;
+        for (out, input) in output[recovered..].iter().zip(&input[recovered - LOOKAHEAD_FRAMES..]) {
+            assert!((out - input).abs() < 0.001, "{} instead of {}", out, input);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use super::*;
+use std::sync::mpsc::Receiver;
+
+/// A position in a file: a sample frame, or a time.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LoopPoint {
+    /// Offset in sample frames.
+    Frames(usize),
+    /// Offset in miliseconds.
+    Millis(usize),
+}
+
+impl LoopPoint {
+    /// Parse a frame offset like `"66150"`, or a time like `"1500ms"`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        match text.strip_suffix("ms") {
+            Some(millis) => Ok(Self::Millis(millis.trim().parse()?)),
+            None => Ok(Self::Frames(text.parse()?)),
+        }
+    }
+
+    /// The offset in samples, in a source with this format.
+    #[inline]
+    pub fn to_samples(self, channels: u16, sample_rate: u32) -> usize {
+        let frames = match self {
+            Self::Frames(frames) => frames,
+            Self::Millis(millis) => millis * sample_rate as usize / 1000,
+        };
+        frames * channels as usize
+    }
+}
+
+/// Longest part of a loop section kept in memory, in miliseconds.
+const MAX_SECTION_LENGTH: usize = 10_000;
+
+/// Opens the file again, to play the rest of a long section.
+pub type Reopen<I> = Arc<dyn Fn() -> Option<I> + Send + Sync>;
+
+/// Source wrapper looping a section of a file seamlessly.
+/// The file plays from its start to `loop_end`, then the section from `loop_start` to `loop_end` repeats.
+/// The start of the section is recorded during the first pass, up to MAX_SECTION_LENGTH.
+/// Shorter sections repeat from memory. Longer ones play their recorded start while the file is
+/// opened again in the background, and skipped to where the recording stops.
+/// Once `released` is set, it stops at the end of the current pass.
+pub struct LoopSection<I> {
+    input: I,
+    channels: u16,
+    sample_rate: u32,
+    /// Position of the section, in samples.
+    start: usize,
+    end: Option<usize>,
+    /// Position of the next sample read from the input.
+    pos: usize,
+    /// Start of the section, recorded during the first pass.
+    head: Vec<i16>,
+    /// Maximum length of `head`, in samples.
+    max_head: usize,
+    /// Whether the section is longer than `head`.
+    truncated: bool,
+    /// Whether the first pass is still playing.
+    recording: bool,
+    /// Position in `head`, while it's playing.
+    repeat_pos: Option<usize>,
+    reopen: Reopen<I>,
+    /// Input opened in the background, positioned after `head`.
+    prepared: Option<Receiver<Option<I>>>,
+    released: Arc<AtomicBool>,
+}
+
+impl<I> LoopSection<I>
+where
+    I: Source<Item=i16> + Send + 'static,
+{
+    /// Loop from `loop_start` (default: the start of the file) to `loop_end` (default: the end of the file).
+    /// `reopen` opens the file again, for sections too long to be kept in memory.
+    pub fn new(
+        input: I,
+        loop_start: Option<LoopPoint>,
+        loop_end: Option<LoopPoint>,
+        released: Arc<AtomicBool>,
+        reopen: Reopen<I>,
+    ) -> Self {
+        let channels = input.channels().max(1);
+        let sample_rate = input.sample_rate();
+        Self {
+            start: loop_start.map_or(0, |point| point.to_samples(channels, sample_rate)),
+            end: loop_end.map(|point| point.to_samples(channels, sample_rate)),
+            input,
+            channels,
+            sample_rate,
+            pos: 0,
+            head: Vec::new(),
+            max_head: LoopPoint::Millis(MAX_SECTION_LENGTH).to_samples(channels, sample_rate),
+            truncated: false,
+            recording: true,
+            repeat_pos: None,
+            reopen,
+            prepared: None,
+            released,
+        }
+    }
+
+    /// Go back to the start of the section, unless released.
+    #[inline]
+    fn repeat(&mut self) -> Option<i16> {
+        self.recording = false;
+        if self.released.load(Ordering::Relaxed) || self.head.is_empty() {
+            return None
+        }
+        self.repeat_pos = Some(1);
+        Some(self.head[0])
+    }
+
+    /// Open the file again in the background, skipping to the end of `head`.
+    fn prepare(&mut self) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reopen = self.reopen.clone();
+        let skip = self.start + self.head.len();
+        let result = std::thread::Builder::new()
+            .name("loop_section_thread".to_string())
+            .spawn(move || {
+                let input = reopen().map(|mut input| {
+                    input.by_ref().take(skip).for_each(drop);
+                    input
+                });
+                let _ = tx.send(input);
+            });
+        match result {
+            Ok(_) => self.prepared = Some(rx),
+            Err(e) => warn!("Failed to open a loop section again: {}", e),
+        }
+    }
+
+    /// Continue the section after `head`, on the input opened by `prepare`.
+    /// Waits for it if it isn't ready yet, which only happens if decoding is slower than playing.
+    fn resume(&mut self) -> Option<i16> {
+        let input = self.prepared.take()?.recv().ok().flatten()?;
+        self.input = input;
+        self.pos = self.start + self.head.len();
+        self.repeat_pos = None;
+        self.prepare();
+        self.next()
+    }
+}
+
+impl<I> Iterator for LoopSection<I>
+where
+    I: Source<Item=i16> + Send + 'static,
+{
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        if let Some(pos) = self.repeat_pos {
+            if pos < self.head.len() {
+                self.repeat_pos = Some(pos + 1);
+                return Some(self.head[pos])
+            }
+            if !self.truncated {
+                return self.repeat()
+            }
+            return self.resume()
+        }
+        if matches!(self.end, Some(end) if self.pos >= end) {
+            return self.repeat()
+        }
+        match self.input.next() {
+            Some(sample) => {
+                if self.recording && self.pos >= self.start {
+                    if self.head.len() < self.max_head {
+                        self.head.push(sample);
+                    } else if !self.truncated {
+                        self.truncated = true;
+                        self.prepare();
+                    }
+                }
+                self.pos += 1;
+                Some(sample)
+            }
+            // The file ended before loop_end.
+            None => self.repeat(),
+        }
+    }
+}
+
+impl<I> Source for LoopSection<I>
+where
+    I: Source<Item=i16> + Send + 'static,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A mono file at 1kHz, whose samples are their own positions.
+    fn file(len: usize) -> buffer::SamplesBuffer<i16> {
+        buffer::SamplesBuffer::new(1, 1000, (0..len as i16).collect::<Vec<i16>>())
+    }
+
+    fn section(len: usize, start: Option<usize>, end: Option<usize>) -> (LoopSection<buffer::SamplesBuffer<i16>>, Arc<AtomicBool>) {
+        let released = Arc::new(AtomicBool::new(false));
+        let section = LoopSection::new(
+            file(len),
+            start.map(LoopPoint::Frames),
+            end.map(LoopPoint::Frames),
+            released.clone(),
+            Arc::new(move || Some(file(len))),
+        );
+        (section, released)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(LoopPoint::parse("1500ms").unwrap(), LoopPoint::Millis(1500));
+        assert_eq!(LoopPoint::parse(" 1500 ms ").unwrap(), LoopPoint::Millis(1500));
+        assert_eq!(LoopPoint::parse("66150").unwrap(), LoopPoint::Frames(66150));
+        for text in ["", "ms", "abc", "-5", "1.5s", "1500s", "12ms34"].iter() {
+            assert!(LoopPoint::parse(text).is_err(), "{}", text);
+        }
+        assert_eq!(LoopPoint::Millis(1500).to_samples(2, 44100), 132_300);
+        assert_eq!(LoopPoint::Frames(100).to_samples(2, 44100), 200);
+    }
+
+    #[test]
+    fn repeats_seamlessly() {
+        let (section, _) = section(100, Some(20), Some(60));
+        let played: Vec<i16> = section.take(60 + 40 * 3).collect();
+        let expected: Vec<i16> = (0..60).chain(20..60).chain(20..60).chain(20..60).collect();
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn repeats_to_the_end_of_the_file() {
+        let (section, _) = section(100, Some(90), None);
+        let played: Vec<i16> = section.take(120).collect();
+        let expected: Vec<i16> = (0..100).chain(90..100).chain(90..100).collect();
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn released_at_the_end_of_the_pass() {
+        let (mut section, released) = section(100, Some(20), Some(60));
+        let mut played: Vec<i16> = section.by_ref().take(70).collect();
+        released.store(true, Ordering::SeqCst);
+        played.extend(section);
+        let expected: Vec<i16> = (0..60).chain(20..60).collect();
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn start_past_the_end() {
+        let (section, _) = section(100, Some(200), None);
+        assert_eq!(section.collect::<Vec<i16>>(), (0..100).collect::<Vec<i16>>());
+    }
+
+    #[test]
+    fn long_sections_are_read_again() {
+        let (mut section, released) = section(100, Some(20), Some(60));
+        section.max_head = 10;
+        let played: Vec<i16> = section.by_ref().take(60 + 40 * 3 + 5).collect();
+        let expected: Vec<i16> = (0..60).chain(20..60).chain(20..60).chain(20..60).chain(20..25).collect();
+        assert_eq!(played, expected);
+        assert_eq!(section.head.len(), 10);
+        released.store(true, Ordering::SeqCst);
+        assert_eq!(section.collect::<Vec<i16>>(), (25..60).collect::<Vec<i16>>());
+    }
+}
@@ -0,0 +1,441 @@
+use super::*;
+use std::time::UNIX_EPOCH;
+
+/// Loudness files are normalized to when no target is given, in LUFS.
+pub const DEFAULT_LOUDNESS_TARGET: f32 = -18.0;
+/// Quiet files are never boosted by more than this, in decibels.
+const MAX_BOOST: f32 = 12.0;
+/// How many files are analysed between saves of the cache.
+const SAVE_INTERVAL: usize = 50;
+
+/// Convert decibels into a volume multiplier.
+#[inline]
+pub fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Convert a volume multiplier into decibels.
+#[inline]
+pub fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-6).log10()
+}
+
+/// Shared state between the Normalizer and its analysis thread.
+struct NormalizerState {
+    /// Loudness to normalize to, in LUFS. None if normalization is off.
+    target: Option<f32>,
+    /// Gain of every file analysed so far.
+    gains: ShardedLock<HashMap<PathBuf, f32>>,
+    /// Tells the analysis thread to stop.
+    cancelled: AtomicBool,
+    /// The analysis thread, if one was started.
+    thread: std::sync::Mutex<Option<std::thread::JoinHandle<()>>>,
+}
+
+/// Gives the gain bringing each file to the target loudness.
+/// Files are analysed in the background. Until a file is analysed, its gain is 1.
+#[derive(Clone)]
+pub struct Normalizer(Arc<NormalizerState>);
+
+impl Normalizer {
+    /// Create a Normalizer bringing files to the `target` loudness, in LUFS.
+    /// If `target` is None, every file is left as is.
+    pub fn new(target: Option<f32>) -> Self {
+        Self(Arc::new(NormalizerState {
+            target,
+            gains: ShardedLock::new(HashMap::new()),
+            cancelled: AtomicBool::new(false),
+            thread: std::sync::Mutex::new(None),
+        }))
+    }
+
+    /// Start analysing the files in the background,
+    /// using the cached loudness of files that didn't change.
+    pub fn analyse(&self, files: Vec<PathBuf>) {
+        if self.0.target.is_none() {
+            return
+        }
+        let cache_path = cache_path();
+        let mut cache = cache_path.as_deref()
+            .map(LoudnessCache::load)
+            .unwrap_or_default();
+        let mut pending = Vec::new();
+        for path in files.into_iter() {
+            match cache.get(&path) {
+                Some(loudness) => self.insert(path, loudness),
+                None => pending.push(path),
+            }
+        }
+        info!("Loudness: {} file(s) cached, {} to analyse.", self.0.gains.read().unwrap().len(), pending.len());
+        if pending.is_empty() {
+            return
+        }
+        let normalizer = self.clone();
+        let thread = std::thread::Builder::new()
+            .name("loudness_thread".to_string())
+            .spawn(move || {
+                for (i, path) in pending.iter().enumerate() {
+                    if normalizer.0.cancelled.load(Ordering::Relaxed) {
+                        return
+                    }
+                    match measure_loudness(path) {
+                        Ok(loudness) => {
+                            trace!("Loudness of {}: {:.1} LUFS", path.display(), loudness);
+                            cache.insert(path, loudness);
+                            normalizer.insert(path.clone(), loudness);
+                        }
+                        Err(e) => {
+                            warn!("Failed to analyse {}: {}", path.display(), e);
+                            warn!("Will play this file without normalization.");
+                        }
+                    }
+                    if (i + 1) % SAVE_INTERVAL == 0 {
+                        cache.save_to(cache_path.as_deref());
+                    }
+                }
+                cache.save_to(cache_path.as_deref());
+                info!("Loudness analysis finished.");
+            })
+            .unwrap();
+        self.0.thread.lock().unwrap().replace(thread);
+    }
+
+    /// Block until every file is analysed.
+    pub fn wait(&self) {
+        let thread = self.0.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
+            if thread.join().is_err() {
+                warn!("The loudness analysis failed.");
+            }
+        }
+    }
+
+    /// Stop analysing files.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Gain to apply to the file at this path.
+    pub fn gain(&self, path: &Path) -> f32 {
+        if self.0.target.is_none() {
+            return 1.0
+        }
+        self.0.gains.read().unwrap()
+            .get(path)
+            .copied()
+            .unwrap_or(1.0)
+    }
+
+    fn insert(&self, path: PathBuf, loudness: f32) {
+        if let Some(target) = self.0.target {
+            // Silent files are left alone.
+            let gain = if loudness.is_finite() {
+                db_to_gain((target - loudness).min(MAX_BOOST))
+            } else {
+                1.0
+            };
+            self.0.gains.write().unwrap().insert(path, gain);
+        }
+    }
+}
+
+/// Path of the loudness cache, in the user's cache directory.
+fn cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|mut p| {
+        p.push("soundsense-rs/loudness-cache.txt");
+        p
+    })
+}
+
+/// Measured loudness of files, keyed by path and modification time.
+/// Saved as one `mtime<TAB>loudness<TAB>path` line per file.
+#[derive(Default)]
+struct LoudnessCache {
+    entries: HashMap<PathBuf, (u64, f32)>,
+}
+
+impl LoudnessCache {
+    fn load(path: &Path) -> Self {
+        let mut cache = Self::default();
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(_) => return cache,
+        };
+        for line in text.lines() {
+            let mut parts = line.splitn(3, '\t');
+            if let (Some(Ok(mtime)), Some(Ok(loudness)), Some(file)) = (
+                parts.next().map(str::parse),
+                parts.next().map(str::parse),
+                parts.next(),
+            ) {
+                cache.entries.insert(PathBuf::from(file), (mtime, loudness));
+            }
+        }
+        debug!("Loaded {} loudness cache entries from {}", cache.entries.len(), path.display());
+        cache
+    }
+
+    fn save_to(&self, path: Option<&Path>) {
+        use std::fmt::Write;
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+        let mut text = String::new();
+        for (file, (mtime, loudness)) in self.entries.iter() {
+            writeln!(&mut text, "{}\t{}\t{}", mtime, loudness, file.display()).unwrap();
+        }
+        let result = path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, text));
+        if let Err(e) = result {
+            warn!("Failed to save the loudness cache to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Get the loudness of a file, if it didn't change since it was measured.
+    fn get(&self, path: &Path) -> Option<f32> {
+        let (mtime, loudness) = self.entries.get(path)?;
+        if modified_time(path) == Some(*mtime) {
+            Some(*loudness)
+        } else {
+            None
+        }
+    }
+
+    fn insert(&mut self, path: &Path, loudness: f32) {
+        if let Some(mtime) = modified_time(path) {
+            self.entries.insert(path.to_path_buf(), (mtime, loudness));
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok()?
+        .modified().ok()?
+        .duration_since(UNIX_EPOCH).ok()
+        .map(|d| d.as_secs())
+}
+
+/// Measure a file's integrated loudness in LUFS, following ITU-R BS.1770 (used by EBU R128).
+/// Silent files have a loudness of -inf.
+pub fn measure_loudness(path: &Path) -> Result<f32> {
+    let source = Decoder::new(File::open(path)?)?;
+    let channels = source.channels().max(1) as usize;
+    let sample_rate = source.sample_rate();
+    let mut filters: Vec<KWeighting> = (0..channels)
+        .map(|_| KWeighting::new(sample_rate as f64))
+        .collect();
+    // Mean square of every 100ms step. Blocks are 400ms long, and overlap by 75%.
+    let step = (sample_rate as usize / 10).max(1);
+    let mut steps: Vec<f64> = Vec::new();
+    let mut energy = 0.0f64;
+    let mut total_energy = 0.0f64;
+    let mut frames = 0usize;
+    let mut total_frames = 0usize;
+    let mut channel = 0;
+    for sample in source.convert_samples::<f32>() {
+        let weighted = filters[channel].process(sample as f64);
+        energy += weighted * weighted;
+        channel += 1;
+        if channel == channels {
+            channel = 0;
+            frames += 1;
+            if frames == step {
+                steps.push(energy / step as f64);
+                total_energy += energy;
+                total_frames += frames;
+                energy = 0.0;
+                frames = 0;
+            }
+        }
+    }
+    total_energy += energy;
+    total_frames += frames;
+    let mut blocks: Vec<f64> = steps.windows(4)
+        .map(|window| window.iter().sum::<f64>() / 4.0)
+        .collect();
+    // Files shorter than a block are measured as a whole.
+    if blocks.is_empty() && total_frames > 0 {
+        blocks.push(total_energy / total_frames as f64);
+    }
+    let loudness = |energy: f64| -0.691 + 10.0 * energy.log10();
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+    // Absolute gate at -70 LUFS, then relative gate 10 LU under the gated loudness.
+    blocks.retain(|&energy| loudness(energy) > -70.0);
+    if blocks.is_empty() {
+        return Ok(f32::NEG_INFINITY)
+    }
+    let relative_gate = loudness(mean(&blocks)) - 10.0;
+    blocks.retain(|&energy| loudness(energy) > relative_gate);
+    Ok(loudness(mean(&blocks)) as f32)
+}
+
+/// The K-weighting filter of BS.1770: a high shelf followed by a high-pass.
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: f64) -> Self {
+        Self {
+            shelf: Biquad::high_shelf(sample_rate, 1500.0, 4.0, std::f64::consts::FRAC_1_SQRT_2),
+            high_pass: Biquad::high_pass(sample_rate, 38.0, 0.5),
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+/// Second order IIR filter, in transposed direct form II.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            z: [0.0; 2],
+        }
+    }
+
+    fn high_shelf(sample_rate: f64, frequency: f64, gain_db: f64, q: f64) -> Self {
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let sqrt_a = a.sqrt();
+        Self::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + 2.0 * sqrt_a * alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - 2.0 * sqrt_a * alpha),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + 2.0 * sqrt_a * alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - 2.0 * sqrt_a * alpha,
+            ],
+        )
+    }
+
+    fn high_pass(sample_rate: f64, frequency: f64, q: f64) -> Self {
+        let w0 = 2.0 * std::f64::consts::PI * frequency / sample_rate;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        Self::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    #[inline]
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    const RATE: u32 = 48000;
+
+    /// Write a 1kHz sine at these RMS levels in dBFS, for this many seconds each, on every channel.
+    fn write_sine(path: &Path, channels: u16, levels: &[(f32, usize)]) {
+        let mut writer = AudioFileWriter::create(path, channels, RATE).unwrap();
+        let mut frame = 0;
+        for &(level, seconds) in levels {
+            let amplitude = db_to_gain(level) * std::f32::consts::SQRT_2;
+            let samples: Vec<f32> = (frame..frame + seconds * RATE as usize)
+                .flat_map(|i| {
+                    let sample = (i as f32 * 1000.0 * std::f32::consts::TAU / RATE as f32).sin() * amplitude;
+                    vec![sample; channels as usize]
+                })
+                .collect();
+            writer.write_samples(&samples).unwrap();
+            frame += seconds * RATE as usize;
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn measures_a_sine() {
+        let dir = test_dir("loudness-sine");
+        let mono = dir.join("mono.wav");
+        write_sine(&mono, 1, &[(-20.0, 3)]);
+        let loudness = measure_loudness(&mono).unwrap();
+        assert!((loudness + 20.0).abs() < 0.1, "{}", loudness);
+        // Both channels add up.
+        let stereo = dir.join("stereo.wav");
+        write_sine(&stereo, 2, &[(-20.0, 3)]);
+        let loudness = measure_loudness(&stereo).unwrap();
+        assert!((loudness + 17.0).abs() < 0.1, "{}", loudness);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gates_quiet_parts() {
+        let dir = test_dir("loudness-gating");
+        // Silence is under the absolute gate. Blocks overlapping both parts aren't,
+        // so the loudness is slightly lower than the sine's.
+        let path = dir.join("silence.wav");
+        write_sine(&path, 1, &[(-20.0, 3), (-100.0, 3)]);
+        let loudness = measure_loudness(&path).unwrap();
+        assert!((loudness + 20.0).abs() < 0.5, "{}", loudness);
+        // A part 20 LU quieter is under the relative gate.
+        // Without the gate, it would measure about -23 LUFS.
+        write_sine(&path, 1, &[(-20.0, 3), (-40.0, 3)]);
+        let loudness = measure_loudness(&path).unwrap();
+        assert!((loudness + 20.0).abs() < 0.5, "{}", loudness);
+        // Only parts 10 LU quieter than the rest are.
+        write_sine(&path, 1, &[(-20.0, 3), (-25.0, 3)]);
+        let loudness = measure_loudness(&path).unwrap();
+        assert!(loudness < -21.0 && loudness > -25.0, "{}", loudness);
+        // Silent files.
+        write_sine(&path, 1, &[(-100.0, 1)]);
+        assert_eq!(measure_loudness(&path).unwrap(), f32::NEG_INFINITY);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache() {
+        let dir = test_dir("loudness-cache");
+        let cache_path = dir.join("cache").join("loudness-cache.txt");
+        let file = dir.join("a file.wav");
+        write_sine(&file, 1, &[(-20.0, 1)]);
+        let mut cache = LoudnessCache::default();
+        cache.insert(&file, -20.5);
+        cache.insert(&dir.join("missing.wav"), -10.0);
+        cache.save_to(Some(&cache_path));
+
+        let mut text = fs::read_to_string(&cache_path).unwrap();
+        text.push_str("not a cache line\n");
+        fs::write(&cache_path, text).unwrap();
+        let cache = LoudnessCache::load(&cache_path);
+        assert_eq!(cache.entries.len(), 1);
+        assert_eq!(cache.get(&file), Some(-20.5));
+        assert_eq!(cache.get(&dir.join("missing.wav")), None);
+
+        // A file that changed is measured again.
+        let modified = fs::metadata(&file).unwrap().modified().unwrap() + Duration::from_secs(10);
+        File::options().write(true).open(&file).unwrap().set_modified(modified).unwrap();
+        assert_eq!(cache.get(&file), None);
+
+        // A missing cache is empty.
+        assert!(LoudnessCache::load(&dir.join("missing.txt")).entries.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,668 @@
+use super::*;
+use std::sync::Mutex;
+use source::UniformSourceIterator;
+
+pub type BoxedSource = Box<dyn Source<Item=f32> + Send>;
+
+/// Output format used when the device's can't be queried, and for rendered files.
+pub const DEFAULT_CHANNELS: u16 = 2;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Where sources are added to a Mixer.
+struct MixerInput {
+    pending: Mutex<Vec<BoxedSource>>,
+    has_pending: AtomicBool,
+    channels: u16,
+    sample_rate: u32,
+}
+
+/// Mixes any number of sources, converted to the same format.
+/// Unlike rodio's DynamicMixer, it outputs silence when there's nothing to mix
+/// instead of ending, and only picks up new sources between frames.
+struct Mixer {
+    sources: Vec<BoxedSource>,
+    input: Arc<MixerInput>,
+    /// Position in the current frame.
+    frame_pos: u16,
+}
+
+impl Iterator for Mixer {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.frame_pos == 0 && self.input.has_pending.load(Ordering::Acquire) {
+            let mut pending = self.input.pending.lock().unwrap();
+            self.sources.append(&mut pending);
+            self.input.has_pending.store(false, Ordering::Release);
+        }
+        self.frame_pos = (self.frame_pos + 1) % self.input.channels;
+        let mut sum = 0.0;
+        let mut idx = 0;
+        while idx < self.sources.len() {
+            match self.sources[idx].next() {
+                Some(sample) => {
+                    sum += sample;
+                    idx += 1;
+                }
+                None => {
+                    self.sources.swap_remove(idx);
+                }
+            }
+        }
+        Some(sum)
+    }
+}
+
+impl Source for Mixer {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Source wrapper making sure a source added to a mixer is read until it ends.
+/// UniformSourceIterator stops reading sources reporting an empty frame,
+/// so wrappers like Done wouldn't notice they ended.
+struct ReadToEnd<I>(I);
+
+impl<I> Iterator for ReadToEnd<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    type Item = I::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<I::Item> {
+        self.0.next()
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<I> Source for ReadToEnd<I>
+where
+    I: Source,
+    I::Item: Sample,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        match self.0.current_frame_len() {
+            Some(0) => None,
+            len => len,
+        }
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.0.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.0.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.0.total_duration()
+    }
+}
+
+/// A node of the mixing graph.
+/// Mixes the sources added to it, then applies a gain and a pause, ramped so there are no clicks.
+/// Keeps playing until it's finished.
+struct Bus {
+    input: Arc<MixerInput>,
+    fade: FadeControl,
+}
+
+impl Bus {
+    /// Create a bus, and the source that outputs its mix.
+    /// `gain` and `paused` are checked every few miliseconds, and `fade_length` is the length of the ramps.
+    fn new<G, P>(
+        channels: u16,
+        sample_rate: u32,
+        fade_length: Arc<AtomicUsize>,
+        gain: G,
+        paused: P,
+    ) -> (Self, BoxedSource)
+    where
+        G: Fn() -> f32 + Send + 'static,
+        P: Fn() -> bool + Send + 'static,
+    {
+        let input = Arc::new(MixerInput {
+            pending: Mutex::new(Vec::new()),
+            has_pending: AtomicBool::new(false),
+            channels: channels.max(1),
+            sample_rate,
+        });
+        let mixer = Mixer {
+            sources: Vec::new(),
+            input: input.clone(),
+            frame_pos: 0,
+        };
+        let fade = FadeControl::new(1.0);
+        let source = Ramp::new(mixer, fade_length.load(Ordering::Relaxed))
+            .periodic_access(Duration::from_millis(5),
+                move |src| {
+                    src.set_length(fade_length.load(Ordering::Relaxed));
+                    src.set_factor(gain());
+                    src.set_paused(paused());
+                }
+            );
+        let source = Fade::new(source, fade.clone());
+        (Self { input, fade }, Box::new(source))
+    }
+
+    /// Add a source to the mix.
+    fn add<S>(&self, source: S)
+    where
+        S: Source<Item=f32> + Send + 'static,
+    {
+        let source = UniformSourceIterator::new(ReadToEnd(source), self.input.channels, self.input.sample_rate);
+        self.input.pending.lock().unwrap().push(Box::new(source));
+        self.input.has_pending.store(true, Ordering::Release);
+    }
+
+    /// Fade out over `fade_length` miliseconds, then end the bus and everything in it.
+    fn finish(&self, fade_length: usize) {
+        self.fade.fade_out(fade_length);
+    }
+}
+
+/// The end of the mixing graph, playing on an audio sink.
+/// Mixes the channel buses, and applies the total volume, pause and stereo settings.
+pub struct MasterBus {
+    bus: Bus,
+    total_volume: VolumeLock,
+    total_is_paused: IsPausedLock,
+    stereo: StereoLock,
+    /// Records the output, if the session is being recorded.
+    recorder: Option<Recorder>,
+    /// Whether the sink is still playing the bus. None if the bus is detached.
+    status: Option<SinkStatus>,
+    /// Name of the output device the bus plays on, if it's played on one.
+    device_name: Option<Box<str>>,
+    /// Ceiling of the limiter, in dBFS. None if there's no limiter.
+    limiter: Option<f32>,
+    /// Gain reduction of the limiter, shared with the buses on other sinks.
+    limiter_meter: LimiterMeter,
+}
+
+impl MasterBus {
+    /// Create a master bus, and start playing it on the sink.
+    /// If there's a recorder, everything played is also recorded.
+    /// If there's a limiter ceiling, in dBFS, the output is limited to it.
+    pub fn new(
+        sink: &dyn AudioSink,
+        recorder: Option<Recorder>,
+        stereo: StereoSettings,
+        limiter: Option<f32>,
+    ) -> Self {
+        Self::with_locks(
+            sink,
+            VolumeLock::new(),
+            IsPausedLock::new(),
+            StereoLock::new(stereo),
+            (limiter, LimiterMeter::new()),
+            recorder,
+        )
+    }
+
+    /// Create a master bus that isn't played, and the source that outputs its mix.
+    /// Used to render sounds faster than realtime.
+    pub fn detached(
+        channels: u16,
+        sample_rate: u32,
+        stereo: StereoSettings,
+        limiter: Option<f32>,
+    ) -> (Self, BoxedSource) {
+        let total_volume = VolumeLock::new();
+        let total_is_paused = IsPausedLock::new();
+        let stereo = StereoLock::new(stereo);
+        let limiter_meter = LimiterMeter::new();
+        let (bus, source) = Self::bus(
+            channels,
+            sample_rate,
+            &total_volume,
+            &total_is_paused,
+            &stereo,
+            (limiter, &limiter_meter),
+        );
+        let master = Self {
+            bus,
+            total_volume,
+            total_is_paused,
+            stereo,
+            recorder: None,
+            status: None,
+            device_name: None,
+            limiter,
+            limiter_meter,
+        };
+        (master, source)
+    }
+
+    fn with_locks(
+        sink: &dyn AudioSink,
+        total_volume: VolumeLock,
+        total_is_paused: IsPausedLock,
+        stereo: StereoLock,
+        (limiter, limiter_meter): (Option<f32>, LimiterMeter),
+        recorder: Option<Recorder>,
+    ) -> Self {
+        let (channels, sample_rate) = sink.format();
+        let (bus, source) = Self::bus(
+            channels,
+            sample_rate,
+            &total_volume,
+            &total_is_paused,
+            &stereo,
+            (limiter, &limiter_meter),
+        );
+        let status = match &recorder {
+            Some(recorder) => sink.play(Box::new(recorder.tap(source))),
+            None => sink.play(source),
+        };
+        Self {
+            bus,
+            total_volume,
+            total_is_paused,
+            stereo,
+            recorder,
+            status: Some(status),
+            device_name: sink.device_name(),
+            limiter,
+            limiter_meter,
+        }
+    }
+
+    fn bus(
+        channels: u16,
+        sample_rate: u32,
+        total_volume: &VolumeLock,
+        total_is_paused: &IsPausedLock,
+        stereo: &StereoLock,
+        (limiter, limiter_meter): (Option<f32>, &LimiterMeter),
+    ) -> (Bus, BoxedSource) {
+        let total_volume = total_volume.clone();
+        let total_is_paused = total_is_paused.clone();
+        let stereo = stereo.clone();
+        let (bus, source) = Bus::new(
+            channels,
+            sample_rate,
+            Arc::new(AtomicUsize::new(DEFAULT_FADE_LENGTH)),
+            move || total_volume.get(),
+            move || total_is_paused.get(),
+        );
+        let source = StereoImage::new(source, stereo.get())
+            .periodic_access(Duration::from_millis(5),
+                move |src| src.set_settings(stereo.get())
+            );
+        // Limited after the stereo image, which can make peaks louder.
+        match limiter {
+            Some(ceiling) => (bus, Box::new(Limiter::new(source, ceiling, Some(limiter_meter.clone())))),
+            None => (bus, Box::new(source)),
+        }
+    }
+
+    /// Create a master bus on another sink, replacing this one.
+    /// It shares this one's volume, pause, stereo settings, limiter and recorder.
+    pub fn on_sink(&self, sink: &dyn AudioSink) -> Self {
+        Self::with_locks(
+            sink,
+            self.total_volume.clone(),
+            self.total_is_paused.clone(),
+            self.stereo.clone(),
+            (self.limiter, self.limiter_meter.clone()),
+            self.recorder.clone(),
+        )
+    }
+
+    /// Create a master bus for channels routed to another sink.
+    /// It shares this one's volume, pause, stereo settings and limiter, but isn't recorded.
+    pub fn routed(&self, sink: &dyn AudioSink) -> Self {
+        Self::with_locks(
+            sink,
+            self.total_volume.clone(),
+            self.total_is_paused.clone(),
+            self.stereo.clone(),
+            (self.limiter, self.limiter_meter.clone()),
+            None,
+        )
+    }
+
+    /// Whether the sink is still playing the bus: it didn't fail, and read from it since the last check.
+    /// Detached buses always are.
+    #[inline]
+    pub fn check_sink(&self) -> bool {
+        match &self.status {
+            Some(status) => status.check(),
+            None => true,
+        }
+    }
+
+    /// Whether the sink stopped playing the bus, as far as the last check knows.
+    #[inline]
+    pub fn has_failed(&self) -> bool {
+        match &self.status {
+            Some(status) => status.has_failed(),
+            None => false,
+        }
+    }
+
+    /// Name of the output device the bus plays on.
+    #[inline]
+    pub fn device_name(&self) -> Option<&str> {
+        self.device_name.as_deref()
+    }
+
+    /// Change the stereo settings, of this bus and the buses sharing them.
+    #[inline]
+    pub fn set_stereo(&self, settings: StereoSettings) {
+        self.stereo.set(settings);
+    }
+
+    /// Gain reduction of the limiter, on every sink. None if there's no limiter.
+    #[inline]
+    pub fn limiter_meter(&self) -> Option<LimiterMeter> {
+        self.limiter.map(|_| self.limiter_meter.clone())
+    }
+
+    #[inline]
+    pub fn total_volume(&self) -> VolumeLock {
+        self.total_volume.clone()
+    }
+
+    #[inline]
+    pub fn total_is_paused(&self) -> IsPausedLock {
+        self.total_is_paused.clone()
+    }
+
+    /// Fade out, then stop playing on the sink.
+    pub fn finish(&self) {
+        self.bus.finish(DEFAULT_FADE_LENGTH);
+    }
+}
+
+/// A channel's bus, feeding a master bus.
+/// Mixes the channel's voices, and applies the channel's volume, ducking, pause and effects.
+pub struct ChannelBus {
+    bus: Bus,
+    local_volume: VolumeLock,
+    duck_volume: VolumeLock,
+    local_is_paused: IsPausedLock,
+    /// Length of the ramps when the channel is paused or resumed, in miliseconds.
+    fade_length: Arc<AtomicUsize>,
+    /// Ceiling of the channel's limiter, in dBFS. None if there's no limiter.
+    limiter: Option<f32>,
+    effects: EffectsLock,
+}
+
+impl ChannelBus {
+    pub fn new(
+        master: &MasterBus,
+        local_volume: VolumeLock,
+        duck_volume: VolumeLock,
+        local_is_paused: IsPausedLock,
+        fade_length: usize,
+    ) -> Self {
+        Self::with_fade_length(
+            master,
+            local_volume,
+            duck_volume,
+            local_is_paused,
+            Arc::new(AtomicUsize::new(fade_length)),
+            None,
+            EffectsLock::new(EffectSettings::default()),
+        )
+    }
+
+    /// Create a bus feeding another master bus, sharing this one's volumes, pause, fade length, limiter and effects.
+    pub fn sibling(&self, master: &MasterBus) -> Self {
+        self.with_limiter(master, self.limiter)
+    }
+
+    /// Create a bus like `sibling`, but with another limiter ceiling, in dBFS.
+    pub fn with_limiter(&self, master: &MasterBus, limiter: Option<f32>) -> Self {
+        Self::with_fade_length(
+            master,
+            self.local_volume.clone(),
+            self.duck_volume.clone(),
+            self.local_is_paused.clone(),
+            self.fade_length.clone(),
+            limiter,
+            self.effects.clone(),
+        )
+    }
+
+    fn with_fade_length(
+        master: &MasterBus,
+        local_volume: VolumeLock,
+        duck_volume: VolumeLock,
+        local_is_paused: IsPausedLock,
+        fade_length: Arc<AtomicUsize>,
+        limiter: Option<f32>,
+        effects: EffectsLock,
+    ) -> Self {
+        let (bus, source) = {
+            let local_volume = local_volume.clone();
+            let duck_volume = duck_volume.clone();
+            let local_is_paused = local_is_paused.clone();
+            Bus::new(
+                master.bus.input.channels,
+                master.bus.input.sample_rate,
+                fade_length.clone(),
+                move || local_volume.get() * duck_volume.get(),
+                move || local_is_paused.get(),
+            )
+        };
+        let source = Effects::new(source, effects.clone());
+        match limiter {
+            Some(ceiling) => master.bus.add(Limiter::new(source, ceiling, None)),
+            None => master.bus.add(source),
+        }
+        Self {
+            bus,
+            local_volume,
+            duck_volume,
+            local_is_paused,
+            fade_length,
+            limiter,
+            effects,
+        }
+    }
+
+    /// Play a voice on the channel.
+    #[inline]
+    pub fn add_voice<S>(&self, voice: S)
+    where
+        S: Source<Item=f32> + Send + 'static,
+    {
+        self.bus.add(voice);
+    }
+
+    /// Set the length of the ramps when the channel is paused or resumed, in miliseconds.
+    #[inline]
+    pub fn set_fade_length(&self, fade_length: usize) {
+        self.fade_length.store(fade_length, Ordering::Relaxed);
+    }
+
+    /// Change the channel's effects, moving to the new settings over `transition` miliseconds.
+    #[inline]
+    pub fn set_effects(&self, settings: EffectSettings, transition: usize) {
+        self.effects.set(settings, transition);
+    }
+
+    #[inline]
+    pub fn effects(&self) -> EffectSettings {
+        self.effects.get()
+    }
+
+    /// Fade out, then end the bus and every voice in it.
+    pub fn finish(&self) {
+        self.bus.finish(self.fade_length.load(Ordering::Relaxed));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util;
+
+    /// Stereo frames of a constant value.
+    fn constant(value: f32, frames: usize) -> buffer::SamplesBuffer<f32> {
+        buffer::SamplesBuffer::new(DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE, vec![value; frames * DEFAULT_CHANNELS as usize])
+    }
+
+    /// Read `millis` miliseconds of the source.
+    fn read(source: &mut BoxedSource, millis: usize) -> Vec<f32> {
+        let len = millis * DEFAULT_SAMPLE_RATE as usize / 1000 * DEFAULT_CHANNELS as usize;
+        source.by_ref().take(len).collect()
+    }
+
+    fn assert_level(samples: &[f32], level: f32) {
+        let last = samples[samples.len() - 1];
+        assert!((last - level).abs() < 1e-4, "{} instead of {}", last, level);
+    }
+
+    fn channel_bus(master: &MasterBus) -> (ChannelBus, VolumeLock, IsPausedLock) {
+        let local_volume = VolumeLock::new();
+        let local_is_paused = IsPausedLock::new();
+        let bus = ChannelBus::new(master, local_volume.clone(), VolumeLock::new(), local_is_paused.clone(), 10);
+        (bus, local_volume, local_is_paused)
+    }
+
+    #[test]
+    fn mixer_sums_sources_between_frames() {
+        let input = Arc::new(MixerInput {
+            pending: Mutex::new(Vec::new()),
+            has_pending: AtomicBool::new(false),
+            channels: 2,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+        });
+        let add = |samples: Vec<f32>| {
+            input.pending.lock().unwrap().push(Box::new(buffer::SamplesBuffer::new(2, DEFAULT_SAMPLE_RATE, samples)));
+            input.has_pending.store(true, Ordering::Release);
+        };
+        let mut mixer = Mixer {
+            sources: Vec::new(),
+            input: input.clone(),
+            frame_pos: 0,
+        };
+        // Silence while there's nothing to mix.
+        assert_eq!(mixer.by_ref().take(4).collect::<Vec<f32>>(), vec![0.0; 4]);
+        add(vec![0.25, 0.5, 0.25, 0.5]);
+        assert_eq!(mixer.next(), Some(0.25));
+        // Added in the middle of a frame: starts with the next one.
+        add(vec![1.0, 2.0]);
+        assert_eq!(mixer.by_ref().take(3).collect::<Vec<f32>>(), vec![0.5, 1.25, 2.5]);
+        // Ended sources are dropped, and the mixer doesn't end.
+        assert_eq!(mixer.by_ref().take(4).collect::<Vec<f32>>(), vec![0.0; 4]);
+        assert!(mixer.sources.is_empty());
+    }
+
+    #[test]
+    fn read_to_end() {
+        let count = Arc::new(AtomicUsize::new(1));
+        let short = || constant(0.5, 1000).take_duration(Duration::from_millis(1));
+        let mut source = ReadToEnd(source::Done::new(short(), count.clone()));
+        while source.0.current_frame_len() != Some(0) {
+            source.next();
+        }
+        // An empty frame would stop the conversion before Done sees the end.
+        assert_eq!(source.current_frame_len(), None);
+        assert_eq!(source.next(), None);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+
+        // Through a bus, Done notices.
+        let count = Arc::new(AtomicUsize::new(1));
+        let (master, mut output) = MasterBus::detached(DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE, StereoSettings::default(), None);
+        let (channel, _, _) = channel_bus(&master);
+        channel.add_voice(source::Done::new(short(), count.clone()));
+        read(&mut output, 10);
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn channel_bus_volume_and_pause() {
+        let (master, mut output) = MasterBus::detached(DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE, StereoSettings::default(), None);
+        let (channel, local_volume, local_is_paused) = channel_bus(&master);
+        channel.add_voice(constant(0.5, DEFAULT_SAMPLE_RATE as usize * 2));
+        channel.add_voice(constant(0.25, DEFAULT_SAMPLE_RATE as usize * 2));
+        assert_level(&read(&mut output, 50), 0.75);
+        // Ramped over the fade length.
+        local_volume.set(0.5);
+        let ramp = read(&mut output, 50);
+        assert!(ramp.iter().any(|&sample| sample < 0.75 && sample > 0.375));
+        assert_level(&ramp, 0.375);
+        master.total_volume().set(0.5);
+        assert_level(&read(&mut output, 50), 0.1875);
+        local_is_paused.flip();
+        assert_level(&read(&mut output, 50), 0.0);
+        local_is_paused.flip();
+        assert_level(&read(&mut output, 50), 0.1875);
+
+        // A finished channel is silent, but the master keeps playing until it's finished.
+        channel.finish();
+        assert_level(&read(&mut output, 50), 0.0);
+        assert_eq!(read(&mut output, 50).len(), 50 * 441 / 10 * 2);
+        master.finish();
+        assert!(read(&mut output, 50).len() < 50 * 441 / 10 * 2);
+        assert!(output.next().is_none());
+    }
+
+    #[test]
+    fn moves_to_another_sink() {
+        let speakers = test_util::plug("Speakers");
+        let headphones = test_util::plug("Headphones");
+        let master = MasterBus::new(&speakers, None, StereoSettings::default(), None);
+        assert_eq!(master.device_name(), Some("Speakers"));
+        let (channel, _, _) = channel_bus(&master);
+        channel.add_voice(constant(0.5, DEFAULT_SAMPLE_RATE as usize));
+        assert_level(&speakers.read(441), 0.5);
+        // Stalled until the sink reads again.
+        assert!(master.check_sink());
+        assert!(!master.check_sink());
+        assert!(master.has_failed());
+
+        // The new bus shares the total volume, and the channel's sibling shares its volume.
+        master.total_volume().set(0.5);
+        let moved = master.on_sink(&headphones);
+        assert_eq!(moved.device_name(), Some("Headphones"));
+        let sibling = channel.sibling(&moved);
+        channel.bus.input.pending.lock().unwrap().clear();
+        sibling.add_voice(constant(0.5, DEFAULT_SAMPLE_RATE as usize));
+        assert_level(&headphones.read(4410), 0.25);
+        assert!(moved.check_sink());
+        assert!(!moved.has_failed());
+
+        // The old bus fades out, and is dropped by its sink.
+        master.finish();
+        speakers.read(4410);
+        assert!(speakers.read(1).iter().all(|&sample| sample == 0.0));
+
+        headphones.fail();
+        assert!(moved.has_failed());
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+use std::sync::Mutex;
+use cpal::traits::{EventLoopTrait, HostTrait};
+use cpal::{EventLoop, StreamId, StreamData, UnknownTypeOutputBuffer, Sample as CpalSample};
+
+/// Tells whether a sink is still playing a source.
+/// Shared by the sink, which reports errors and reads, and the master bus it plays.
+#[derive(Clone)]
+pub struct SinkStatus(Arc<SinkStatusInner>);
+
+struct SinkStatusInner {
+    /// Whether the sink stopped playing the source because of an error.
+    failed: AtomicBool,
+    /// Whether the sink read from the source since the last check.
+    read: AtomicBool,
+}
+
+impl SinkStatus {
+    #[inline]
+    pub fn new() -> Self {
+        Self(Arc::new(SinkStatusInner {
+            failed: AtomicBool::new(false),
+            // Counts as read, so a sink that didn't start yet isn't lost on its first check.
+            read: AtomicBool::new(true),
+        }))
+    }
+
+    /// Report that the sink failed, and won't play the source anymore.
+    #[inline]
+    pub fn fail(&self) {
+        self.0.failed.store(true, Ordering::Relaxed);
+    }
+
+    /// Report that the sink read from the source.
+    #[inline]
+    pub fn mark_read(&self) {
+        self.0.read.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the sink failed, or was found stalled by `check`.
+    #[inline]
+    pub fn has_failed(&self) -> bool {
+        self.0.failed.load(Ordering::Relaxed)
+    }
+
+    /// Whether the sink is still playing: it didn't fail, and read from the source since the last check.
+    /// A sink that stopped reading is considered failed from then on.
+    pub fn check(&self) -> bool {
+        if !self.0.read.swap(false, Ordering::Relaxed) {
+            self.fail();
+        }
+        !self.has_failed()
+    }
+}
+
+/// Plays sources on output devices, each on a stream of its own.
+/// Unlike rodio's play_raw, streams are destroyed when their source ends or their device fails,
+/// so a device that was unplugged can be opened again when it comes back.
+struct OutputEngine {
+    event_loop: EventLoop,
+    streams: Mutex<HashMap<StreamId, OutputStream>>,
+}
+
+struct OutputStream {
+    source: BoxedSource,
+    status: SinkStatus,
+}
+
+lazy_static! {
+    static ref ENGINE: Arc<OutputEngine> = {
+        let engine = Arc::new(OutputEngine {
+            event_loop: cpal::default_host().event_loop(),
+            streams: Mutex::new(HashMap::new()),
+        });
+        let result = std::thread::Builder::new()
+            .name("output_thread".to_string())
+            .spawn({
+                let engine = engine.clone();
+                move || engine.event_loop.run(|stream_id, result| engine.on_stream(stream_id, result))
+            });
+        if let Err(e) = result {
+            error!("Failed to start the audio output thread: {}", e);
+        }
+        engine
+    };
+}
+
+impl OutputEngine {
+    /// Called by the event loop, whenever a stream needs samples or failed.
+    fn on_stream(&self, stream_id: StreamId, result: cpal::StreamDataResult) {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = match streams.get_mut(&stream_id) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let ended = match result {
+            Ok(StreamData::Output { buffer }) => {
+                stream.status.mark_read();
+                match buffer {
+                    UnknownTypeOutputBuffer::U16(mut buffer) => fill(&mut buffer, &mut stream.source),
+                    UnknownTypeOutputBuffer::I16(mut buffer) => fill(&mut buffer, &mut stream.source),
+                    UnknownTypeOutputBuffer::F32(mut buffer) => fill(&mut buffer, &mut stream.source),
+                }
+            }
+            Ok(StreamData::Input { .. }) => false,
+            Err(e) => {
+                warn!("Audio output stream failed: {}", e);
+                stream.status.fail();
+                true
+            }
+        };
+        if ended {
+            streams.remove(&stream_id);
+            self.event_loop.destroy_stream(stream_id);
+        }
+    }
+}
+
+/// Fill a device's buffer with samples from the source.
+/// Returns true if the source ended.
+#[inline]
+fn fill<T: CpalSample>(buffer: &mut [T], source: &mut BoxedSource) -> bool {
+    let mut ended = false;
+    for sample in buffer.iter_mut() {
+        *sample = match source.next() {
+            Some(value) => T::from(&value),
+            None => {
+                ended = true;
+                T::from(&0.0f32)
+            }
+        };
+    }
+    ended
+}
+
+/// Play a source on an output device, in the device's default format, until it ends or the device fails.
+pub fn play_on_device(device: &Device, source: BoxedSource) -> SinkStatus {
+    let status = SinkStatus::new();
+    let name = device.name().unwrap_or_default();
+    let stream_id = device.default_output_format()
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|format| Ok(ENGINE.event_loop.build_output_stream(device, &format)?));
+    let stream_id = match stream_id {
+        Ok(stream_id) => stream_id,
+        Err(e) => {
+            warn!("Failed to open audio output device {}: {}", name, e);
+            warn!("Will look for another device.");
+            status.fail();
+            return status
+        }
+    };
+    ENGINE.streams.lock().unwrap().insert(stream_id.clone(), OutputStream {
+        source,
+        status: status.clone(),
+    });
+    if let Err(e) = ENGINE.event_loop.play_stream(stream_id.clone()) {
+        warn!("Failed to play on audio output device {}: {}", name, e);
+        warn!("Will look for another device.");
+        ENGINE.streams.lock().unwrap().remove(&stream_id);
+        ENGINE.event_loop.destroy_stream(stream_id);
+        status.fail();
+    }
+    status
+}
@@ -0,0 +1,327 @@
+use super::*;
+use std::f32::consts::{FRAC_PI_4, SQRT_2};
+
+/// Gains of the left and right sides for a position from -1 (full left) to 1 (full right),
+/// with an equal-power pan law. Each side is at unity at its own end.
+#[inline]
+fn pan_gains(position: f32) -> (f32, f32) {
+    let angle = (position.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
+}
+
+/// Source wrapper placing a source in the stereo field, with an equal-power pan law.
+/// The output is always stereo. Mono sources are sent to both sides,
+/// and only the first two channels of sources with more are kept.
+/// Each side of a stereo source is panned from its own side: at full balance,
+/// both sides play on one speaker, instead of one of them being dropped.
+/// A balance of 0 keeps the source at its original level.
+pub struct Panner<I> {
+    input: I,
+    /// Gains of a mono source on the left and right sides.
+    mono_gains: (f32, f32),
+    /// Gains of a stereo source's left side, then its right side, on the left and right sides.
+    stereo_gains: [(f32, f32); 2],
+    /// Right sample of the current frame, not yet returned.
+    pending: Option<f32>,
+}
+
+impl<I> Panner<I>
+where
+    I: Source<Item=f32>,
+{
+    /// `balance` ranges from -1 (full left) to 1 (full right).
+    pub fn new(input: I, balance: f32) -> Self {
+        let balance = balance.clamp(-1.0, 1.0);
+        let (left_gain, right_gain) = pan_gains(balance);
+        Self {
+            input,
+            // Scaled so the center is at unity gain, as it was without panning.
+            mono_gains: (SQRT_2 * left_gain, SQRT_2 * right_gain),
+            stereo_gains: [pan_gains(balance - 1.0), pan_gains(balance + 1.0)],
+            pending: None,
+        }
+    }
+}
+
+impl<I> Iterator for Panner<I>
+where
+    I: Source<Item=f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.pending.take() {
+            return Some(right)
+        }
+        let channels = self.input.channels();
+        let sample = self.input.next()?;
+        let (left, right) = if channels > 1 {
+            let right = self.input.next().unwrap_or(0.0);
+            for _ in 2..channels {
+                self.input.next();
+            }
+            let [(left_left, left_right), (right_left, right_right)] = self.stereo_gains;
+            (sample * left_left + right * right_left, sample * left_right + right * right_right)
+        } else {
+            (sample * self.mono_gains.0, sample * self.mono_gains.1)
+        };
+        self.pending = Some(right);
+        Some(left)
+    }
+}
+
+impl<I> Source for Panner<I>
+where
+    I: Source<Item=f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        let channels = self.input.channels().max(1) as usize;
+        let pending = self.pending.is_some() as usize;
+        self.input.current_frame_len()
+            .map(|len| len / channels * 2 + pending)
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        2
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+/// Default stereo width, in percent.
+pub const DEFAULT_STEREO_WIDTH: f32 = 100.0;
+
+/// How the whole mix is placed in the stereo field.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct StereoSettings {
+    /// Width of the stereo image. 0 is mono, 1 is unchanged, 2 is twice as wide.
+    pub width: f32,
+    /// Downmix everything to mono, played on both sides.
+    pub mono: bool,
+    /// Swap the left and right sides.
+    pub swap: bool,
+}
+
+impl Default for StereoSettings {
+    fn default() -> Self {
+        Self {
+            width: DEFAULT_STEREO_WIDTH * 0.01,
+            mono: false,
+            swap: false,
+        }
+    }
+}
+
+impl StereoSettings {
+    /// Apply the settings to a frame's left and right samples.
+    #[inline]
+    fn apply(&self, left: f32, right: f32) -> (f32, f32) {
+        let mid = (left + right) * 0.5;
+        let side = if self.mono {
+            0.0
+        } else {
+            (left - right) * 0.5 * self.width
+        };
+        if self.swap {
+            (mid - side, mid + side)
+        } else {
+            (mid + side, mid - side)
+        }
+    }
+}
+
+/// A thread-safe wrapper around StereoSettings.
+/// Shared by a master bus and the buses of channels routed to other devices.
+#[derive(Clone)]
+pub struct StereoLock(Arc<ShardedLock<StereoSettings>>);
+impl StereoLock {
+    #[inline]
+    pub fn new(settings: StereoSettings) -> Self {
+        Self(Arc::new(ShardedLock::new(settings)))
+    }
+    #[inline]
+    pub fn get(&self) -> StereoSettings {
+        *self.0.read().unwrap()
+    }
+    #[inline]
+    pub fn set(&self, settings: StereoSettings) {
+        *self.0.write().unwrap() = settings;
+    }
+}
+
+/// Source wrapper applying StereoSettings to the first two channels of every frame.
+/// Sources with a single channel are unchanged.
+pub struct StereoImage<I> {
+    input: I,
+    settings: StereoSettings,
+    /// Position in the current frame.
+    frame_pos: u16,
+    /// Right sample of the current frame, not yet returned.
+    pending: Option<f32>,
+}
+
+impl<I> StereoImage<I>
+where
+    I: Source<Item=f32>,
+{
+    pub fn new(input: I, settings: StereoSettings) -> Self {
+        Self {
+            input,
+            settings,
+            frame_pos: 0,
+            pending: None,
+        }
+    }
+
+    #[inline]
+    pub fn set_settings(&mut self, settings: StereoSettings) {
+        self.settings = settings;
+    }
+}
+
+impl<I> Iterator for StereoImage<I>
+where
+    I: Source<Item=f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.pending.take() {
+            return Some(right)
+        }
+        let channels = self.input.channels().max(1);
+        if self.frame_pos == 0 && channels > 1 {
+            let left = self.input.next()?;
+            let right = self.input.next()?;
+            let (left, right) = self.settings.apply(left, right);
+            self.pending = Some(right);
+            self.frame_pos = 2 % channels;
+            return Some(left)
+        }
+        self.frame_pos = (self.frame_pos + 1) % channels;
+        self.input.next()
+    }
+}
+
+impl<I> Source for StereoImage<I>
+where
+    I: Source<Item=f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+            .map(|len| len + self.pending.is_some() as usize)
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(channels: u16, samples: Vec<f32>) -> buffer::SamplesBuffer<f32> {
+        buffer::SamplesBuffer::new(channels, 44100, samples)
+    }
+
+    fn pan(channels: u16, samples: Vec<f32>, balance: f32) -> Vec<f32> {
+        Panner::new(frames(channels, samples), balance).collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn mono_is_at_unity_in_the_center() {
+        assert_close(&pan(1, vec![0.5, -0.25], 0.0), &[0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn mono_keeps_its_power() {
+        assert_close(&pan(1, vec![1.0], -1.0), &[SQRT_2, 0.0]);
+        assert_close(&pan(1, vec![1.0], 1.0), &[0.0, SQRT_2]);
+        for &balance in [-0.75, -0.3, 0.1, 0.5, 0.9].iter() {
+            let panned = pan(1, vec![1.0], balance);
+            assert!((panned[0].powi(2) + panned[1].powi(2) - 2.0).abs() < 1e-5, "{}", balance);
+        }
+    }
+
+    #[test]
+    fn stereo_is_unchanged_in_the_center() {
+        assert_close(&pan(2, vec![0.5, -0.25, 0.1, 0.2], 0.0), &[0.5, -0.25, 0.1, 0.2]);
+    }
+
+    #[test]
+    fn stereo_keeps_both_sides() {
+        let half = SQRT_2 * 0.5;
+        // The left side moves to the center, the right side stays right.
+        assert_close(&pan(2, vec![1.0, 0.0], 1.0), &[half, half]);
+        assert_close(&pan(2, vec![0.0, 1.0], 1.0), &[0.0, 1.0]);
+        assert_close(&pan(2, vec![1.0, 0.0], -1.0), &[1.0, 0.0]);
+        assert_close(&pan(2, vec![0.0, 1.0], -1.0), &[half, half]);
+        // Each side keeps its power.
+        for &balance in [-0.75, -0.3, 0.1, 0.5, 0.9].iter() {
+            for side in 0..2 {
+                let mut input = vec![0.0; 2];
+                input[side] = 1.0;
+                let panned = pan(2, input, balance);
+                assert!((panned[0].powi(2) + panned[1].powi(2) - 1.0).abs() < 1e-5, "{} {}", balance, side);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_first_two_channels() {
+        assert_close(&pan(3, vec![0.5, -0.5, 1.0, 0.25, 0.75, 1.0], 0.0), &[0.5, -0.5, 0.25, 0.75]);
+    }
+
+    fn image(channels: u16, samples: Vec<f32>, settings: StereoSettings) -> Vec<f32> {
+        StereoImage::new(frames(channels, samples), settings).collect()
+    }
+
+    #[test]
+    fn stereo_image() {
+        let input = vec![1.0, 0.0, 0.5, 0.5];
+        assert_close(&image(2, input.clone(), StereoSettings::default()), &input);
+        let narrow = StereoSettings {width: 0.0, ..StereoSettings::default()};
+        assert_close(&image(2, input.clone(), narrow), &[0.5, 0.5, 0.5, 0.5]);
+        let wide = StereoSettings {width: 2.0, ..StereoSettings::default()};
+        assert_close(&image(2, input.clone(), wide), &[1.5, -0.5, 0.5, 0.5]);
+        let mono = StereoSettings {mono: true, ..wide};
+        assert_close(&image(2, input.clone(), mono), &[0.5, 0.5, 0.5, 0.5]);
+        let swap = StereoSettings {swap: true, ..StereoSettings::default()};
+        assert_close(&image(2, input, swap), &[0.0, 1.0, 0.5, 0.5]);
+        // Mono sources are unchanged, and other channels are kept.
+        assert_close(&image(1, vec![1.0, 0.5], swap), &[1.0, 0.5]);
+        assert_close(&image(3, vec![1.0, 0.0, 0.3, 0.0, 1.0, 0.6], swap), &[0.0, 1.0, 0.3, 1.0, 0.0, 0.6]);
+    }
+}
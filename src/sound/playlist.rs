@@ -0,0 +1,423 @@
+use super::*;
+
+/// How deep playlists may be nested inside each other.
+const MAX_DEPTH: usize = 8;
+
+/// One entry of a playlist.
+#[derive(Clone)]
+pub struct PlaylistEntry {
+    /// Path to the audio file.
+    pub path: PathBuf,
+    /// Title given by the playlist, if any.
+    pub title: Option<Box<str>>,
+    /// Length given by the playlist, if any.
+    pub duration: Option<Duration>,
+}
+
+impl PlaylistEntry {
+    #[inline]
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            title: None,
+            duration: None,
+        }
+    }
+
+    /// Text shown in the UI while this entry is playing.
+    /// Uses the title if the playlist gave one, otherwise the file name.
+    pub fn display_name(&self) -> String {
+        let name = match &self.title {
+            Some(title) => title.to_string(),
+            None => display_name(&self.path),
+        };
+        match self.duration {
+            Some(duration) => {
+                let secs = duration.as_secs();
+                format!("{} ({}:{:02})", name, secs / 60, secs % 60)
+            }
+            None => name,
+        }
+    }
+}
+
+/// Text shown in the UI for a file that isn't part of a playlist.
+pub fn display_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Check if the path has a playlist extension.
+pub fn is_playlist(path: &Path) -> bool {
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    matches!(
+        extension.as_deref(),
+        Some("m3u") | Some("m3u8") | Some("pls") | Some("xspf")
+    )
+}
+
+/// Convert a playlist into a list of entries.
+/// Supports extended M3U, PLS and XSPF. Entries that are playlists themselves are expanded in place.
+pub fn parse_playlist(path: &Path) -> Result<Vec<PlaylistEntry>> {
+    let mut entries = Vec::new();
+    parse_nested(path, &mut Vec::new(), &mut entries)?;
+    Ok(entries)
+}
+
+/// `parents` contains the playlists that include this one, to catch playlists that include themselves.
+fn parse_nested(path: &Path, parents: &mut Vec<PathBuf>, entries: &mut Vec<PlaylistEntry>) -> Result<()> {
+    if parents.len() > MAX_DEPTH {
+        warn!("Playlist {} is nested too deeply.", path.display());
+        warn!("Will ignore this playlist.");
+        return Ok(())
+    }
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if parents.contains(&canonical) {
+        warn!("Playlist {} includes itself.", path.display());
+        warn!("Will ignore this playlist.");
+        return Ok(())
+    }
+    let parent_path = path.parent().unwrap_or_else(|| Path::new(""));
+    let extension = path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    let parsed = match extension.as_deref() {
+        Some("m3u") | Some("m3u8") => parse_m3u(path, parent_path)?,
+        Some("pls") => parse_pls(path, parent_path)?,
+        Some("xspf") => parse_xspf(path, parent_path)?,
+        _ => return Err(format!(
+            "Playlist {:?} is not valid! Playlist needs to have either .m3u, .m3u8, .pls or .xspf extension.",
+            path
+        ).into()),
+    };
+    parents.push(canonical);
+    for entry in parsed.into_iter() {
+        if is_playlist(&entry.path) {
+            trace!("   Nested Playlist: {:?}", entry.path);
+            if let Err(e) = parse_nested(&entry.path, parents, entries) {
+                warn!("Failed to read nested playlist {}: {}", entry.path.display(), e);
+                warn!("Will ignore this playlist.");
+            }
+        }
+        else {
+            trace!("   Playlist Entry: {:?}", entry.path);
+            entries.push(entry);
+        }
+    }
+    parents.pop();
+    Ok(())
+}
+
+/// Parse an (extended) M3U playlist.
+/// `#EXTINF:<seconds>,<title>` lines apply to the entry that follows them.
+fn parse_m3u(path: &Path, parent_path: &Path) -> Result<Vec<PlaylistEntry>> {
+    lazy_static! {
+        static ref EXTINF_PATTERN: Regex = Regex::new(
+            r"^#EXTINF:\s*(-?[0-9]+(?:\.[0-9]+)?)[^,]*(?:,(.*))?$"
+        ).unwrap();
+    }
+    let f = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    let mut info: Option<(Option<Duration>, Option<Box<str>>)> = None;
+    for line in f.lines() {
+        let line = line?;
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() {
+            continue
+        }
+        if let Some(caps) = EXTINF_PATTERN.captures(line) {
+            let duration = caps.get(1)
+                .and_then(|m| m.as_str().parse::<f32>().ok())
+                .filter(|&secs| secs >= 0.0)
+                .map(|secs| Duration::from_millis((secs * 1000.0) as u64));
+            let title = caps.get(2)
+                .map(|m| m.as_str().trim())
+                .filter(|t| !t.is_empty())
+                .map(Box::from);
+            info = Some((duration, title));
+        }
+        else if !line.starts_with('#') {
+            let mut entry = PlaylistEntry::new(resolve_path(parent_path, line));
+            if let Some((duration, title)) = info.take() {
+                entry.duration = duration;
+                entry.title = title;
+            }
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Parse a PLS playlist.
+/// `FileN`, `TitleN` and `LengthN` keys with the same N describe the same entry.
+fn parse_pls(path: &Path, parent_path: &Path) -> Result<Vec<PlaylistEntry>> {
+    lazy_static! {
+        static ref PLS_PATTERN: Regex = Regex::new(
+            r"^(?i)(File|Title|Length)([0-9]+)\s*=(.*)$"
+        ).unwrap();
+    }
+    let f = BufReader::new(File::open(path)?);
+    let mut entries: BTreeMap<usize, PlaylistEntry> = BTreeMap::new();
+    let mut titles: HashMap<usize, Box<str>> = HashMap::new();
+    let mut durations: HashMap<usize, Duration> = HashMap::new();
+    for line in f.lines() {
+        let line = line?;
+        if let Some(caps) = PLS_PATTERN.captures(line.trim()) {
+            let idx: usize = caps[2].parse()?;
+            let value = caps[3].trim();
+            match caps[1].to_ascii_lowercase().as_str() {
+                "file" => {
+                    entries.insert(idx, PlaylistEntry::new(resolve_path(parent_path, value)));
+                }
+                "title" => if !value.is_empty() {
+                    titles.insert(idx, Box::from(value));
+                }
+                _ => if let Ok(secs) = value.parse::<i64>() {
+                    // Length is -1 for streams and unknown lengths.
+                    if secs >= 0 {
+                        durations.insert(idx, Duration::from_secs(secs as u64));
+                    }
+                }
+            }
+        }
+    }
+    Ok(entries.into_iter()
+        .map(|(idx, mut entry)| {
+            entry.title = titles.remove(&idx);
+            entry.duration = durations.remove(&idx);
+            entry
+        })
+        .collect())
+}
+
+/// Parse an XSPF playlist.
+/// Reads each `<track>`'s `<location>`, `<title>` and `<duration>` (in milliseconds).
+fn parse_xspf(path: &Path, parent_path: &Path) -> Result<Vec<PlaylistEntry>> {
+    use quick_xml::{Reader, events::Event};
+    let mut reader = Reader::from_file(path)?;
+    reader.trim_text(true);
+    let mut entries = Vec::new();
+    let mut current: Option<PlaylistEntry> = None;
+    let mut element: Vec<u8> = Vec::new();
+    let buf = &mut Vec::new();
+    loop {
+        match reader.read_event(buf) {
+            Ok(Event::Start(ref data)) => {
+                if data.local_name() == b"track" {
+                    current = Some(PlaylistEntry::new(PathBuf::new()));
+                }
+                element = data.local_name().to_vec();
+            }
+            Ok(Event::Text(ref data)) => if let Some(entry) = current.as_mut() {
+                let text = data.unescape_and_decode(&reader)?;
+                match element.as_slice() {
+                    // Only the first location is used.
+                    b"location" if entry.path.as_os_str().is_empty() => {
+                        entry.path = resolve_path(parent_path, &text);
+                    }
+                    b"title" => entry.title = Some(Box::from(text.as_str())),
+                    b"duration" => if let Ok(ms) = text.parse() {
+                        entry.duration = Some(Duration::from_millis(ms));
+                    }
+                    _ => (),
+                }
+            }
+            Ok(Event::End(ref data)) => {
+                if data.local_name() == b"track" {
+                    match current.take() {
+                        Some(entry) if !entry.path.as_os_str().is_empty() => entries.push(entry),
+                        _ => warn!("A track in {} doesn't have a location.", path.display()),
+                    }
+                }
+                element.clear();
+            }
+            Ok(Event::Eof) => break,
+            Err(e) => return Err(
+                format!("Error parsing xspf at position {}: {:?}", reader.buffer_position(), e).into()
+            ),
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(entries)
+}
+
+/// Resolve a playlist location.
+/// Absolute paths and `file://` URLs are used as-is, other paths are relative to the playlist.
+fn resolve_path(parent_path: &Path, location: &str) -> PathBuf {
+    let location = if let Some(url_path) = strip_prefix_ignore_case(location, "file://") {
+        let decoded = percent_decode(url_path);
+        // file:///C:/... on Windows.
+        if decoded.len() > 2 && decoded.starts_with('/') && decoded.as_bytes()[2] == b':' {
+            decoded[1..].to_string()
+        } else {
+            decoded
+        }
+    } else {
+        location.to_string()
+    };
+    let path = PathBuf::from(location);
+    if path.is_absolute() {
+        path
+    } else {
+        parent_path.join(path)
+    }
+}
+
+fn strip_prefix_ignore_case<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    if text.len() >= prefix.len()
+    && text.is_char_boundary(prefix.len())
+    && text[..prefix.len()].eq_ignore_ascii_case(prefix) {
+        Some(&text[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Decode `%XX` escapes in a URL path.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(byte) = std::str::from_utf8(&bytes[i+1..i+3]).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    fn summary(entries: &[PlaylistEntry]) -> Vec<(PathBuf, Option<&str>, Option<u64>)> {
+        entries.iter()
+            .map(|e| (e.path.clone(), e.title.as_deref(), e.duration.map(|d| d.as_millis() as u64)))
+            .collect()
+    }
+
+    #[test]
+    fn m3u() {
+        let dir = test_dir("playlist-m3u");
+        let path = dir.join("music.m3u");
+        fs::write(&path, "\u{feff}#EXTM3U\r\n\
+            # A comment.\r\n\
+            #EXTINF:123,Artist - First Song\r\n\
+            songs/first.ogg\r\n\
+            \r\n\
+            second.ogg\r\n\
+            #EXTINF:-1,Stream\r\n\
+            /absolute/third.ogg\r\n\
+            #EXTINF:4.5 tvg-id=\"x\",\r\n\
+            fourth.ogg\r\n\
+            #EXTINF:10,Dangling info\r\n").unwrap();
+        let entries = parse_playlist(&path).unwrap();
+        assert_eq!(summary(&entries), vec![
+            (dir.join("songs/first.ogg"), Some("Artist - First Song"), Some(123_000)),
+            (dir.join("second.ogg"), None, None),
+            (PathBuf::from("/absolute/third.ogg"), Some("Stream"), None),
+            (dir.join("fourth.ogg"), None, Some(4500)),
+        ]);
+        assert_eq!(entries[0].display_name(), "Artist - First Song (2:03)");
+        assert_eq!(entries[1].display_name(), "second");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pls() {
+        let dir = test_dir("playlist-pls");
+        let path = dir.join("music.pls");
+        fs::write(&path, "[playlist]\n\
+            ; A comment.\n\
+            File3=third.ogg\n\
+            Title3=Third\n\
+            Length3=-1\n\
+            File1=first.ogg\n\
+            Title1=First\n\
+            Length1=61\n\
+            file10 = tenth.ogg\n\
+            File2=sub/second.ogg\n\
+            Title2=\n\
+            NumberOfEntries=4\n\
+            Version=2\n").unwrap();
+        let entries = parse_playlist(&path).unwrap();
+        // Ordered by N, not by position in the file.
+        assert_eq!(summary(&entries), vec![
+            (dir.join("first.ogg"), Some("First"), Some(61_000)),
+            (dir.join("sub/second.ogg"), None, None),
+            (dir.join("third.ogg"), Some("Third"), None),
+            (dir.join("tenth.ogg"), None, None),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xspf() {
+        let dir = test_dir("playlist-xspf");
+        let path = dir.join("music.xspf");
+        fs::write(&path, r#"<?xml version="1.0" encoding="UTF-8"?>
+<playlist version="1" xmlns="http://xspf.org/ns/0/">
+  <title>Not a track title</title>
+  <trackList>
+    <track>
+      <location>file:///music/My%20Song.ogg</location>
+      <location>ignored.ogg</location>
+      <title>My &amp; Song</title>
+      <duration>90500</duration>
+    </track>
+    <track>
+      <location>relative/second.ogg</location>
+    </track>
+    <track>
+      <title>No location</title>
+    </track>
+    <track>
+      <location>FILE:///C:/Music/third.ogg</location>
+    </track>
+  </trackList>
+</playlist>
+"#).unwrap();
+        let entries = parse_playlist(&path).unwrap();
+        let third = if cfg!(windows) {PathBuf::from("C:/Music/third.ogg")} else {dir.join("C:/Music/third.ogg")};
+        assert_eq!(summary(&entries), vec![
+            (PathBuf::from("/music/My Song.ogg"), Some("My & Song"), Some(90_500)),
+            (dir.join("relative/second.ogg"), None, None),
+            (third, None, None),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested() {
+        let dir = test_dir("playlist-nested");
+        fs::write(dir.join("outer.m3u"), "a.ogg\ninner.pls\nouter.m3u\nd.ogg\n").unwrap();
+        fs::write(dir.join("inner.pls"), "[playlist]\nFile1=b.ogg\nFile2=outer.m3u\nFile3=c.ogg\n").unwrap();
+        let entries = parse_playlist(&dir.join("outer.m3u")).unwrap();
+        // Playlists including themselves are skipped.
+        let names: Vec<_> = entries.iter().map(|e| display_name(&e.path)).collect();
+        assert_eq!(names, vec!["a", "b", "c", "d"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(parse_playlist(Path::new("music.txt")).is_err());
+        assert!(is_playlist(Path::new("a/b.M3U8")));
+        assert!(!is_playlist(Path::new("a/b.ogg")));
+        assert_eq!(percent_decode("a%20b%2"), "a b%2");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+    }
+}
@@ -0,0 +1,384 @@
+use super::*;
+
+/// Default simulated time between two gamelog lines, when rendering, in miliseconds.
+pub const DEFAULT_RENDER_INTERVAL: usize = 1000;
+/// Default maximum time rendered after the last gamelog line, in miliseconds.
+pub const DEFAULT_RENDER_TAIL: usize = 10_000;
+/// Simulated time between two updates of the SoundManager, in miliseconds.
+/// The same as the sound thread's loop.
+const RENDER_TICK: usize = 10;
+/// Number of frames the recorder's tap collects before sending them.
+const RECORDED_BLOCK_FRAMES: usize = 1024;
+
+/// How a gamelog is rendered.
+#[derive(Copy, Clone)]
+pub struct RenderOptions {
+    /// Simulated time between two gamelog lines, in miliseconds.
+    pub interval: usize,
+    /// Maximum time rendered after the last line, in miliseconds.
+    /// Rendering stops earlier if nothing is playing anymore.
+    pub tail: usize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_RENDER_INTERVAL,
+            tail: DEFAULT_RENDER_TAIL,
+        }
+    }
+}
+
+/// Render a whole gamelog into an audio file, as fast as possible.
+/// The gamelog is read from the start, and a simulated clock moves forward by
+/// `render_options.interval` after every line.
+/// Channels play at their default volume, whatever volumes and devices the user saved.
+/// Returns the length of the rendered audio, in miliseconds.
+pub fn render(
+    soundpack: &Path,
+    gamelog: &Path,
+    ignore_list: Option<&Path>,
+    output: &Path,
+    options: &SoundOptions,
+    render_options: RenderOptions,
+) -> Result<usize> {
+    let mut writer = AudioFileWriter::create(output, DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE)?;
+    let (master, source) = MasterBus::detached(DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE, options.stereo, options.limiter);
+    // Nothing reads these messages, but they have to go somewhere.
+    let (ui_tx, ui_rx) = crossbeam::channel::unbounded();
+    // Channels routed to their own device are rendered with the others.
+    let options = SoundOptions {
+        backend: AudioBackend::Null,
+        ..options.clone()
+    };
+    let mut manager = SoundManager::with_master(soundpack, ui_tx, &options, master)?;
+    // Rendering should be the same every time, whether the files were analysed before or not.
+    manager.wait_for_analysis();
+    if let Some(path) = ignore_list {
+        manager.set_ignore_list(read_ignore_list(path)?)?;
+    }
+    let mut clock = RenderClock {
+        source,
+        writer: &mut writer,
+        buffer: Vec::new(),
+        elapsed: 0,
+    };
+    let gamelog = BufReader::new(File::open(gamelog)?);
+    for log in gamelog.lines() {
+        // Lines that aren't valid UTF-8 are skipped, as when playing.
+        let log = match log {
+            Ok(log) => log,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => continue,
+            Err(e) => return Err(e.into()),
+        };
+        manager.process_log(&log)?;
+        clock.advance(&mut manager, render_options.interval)?;
+        ui_rx.try_iter().for_each(drop);
+    }
+    let mut tail = 0;
+    while tail < render_options.tail && manager.is_playing() {
+        clock.advance(&mut manager, RENDER_TICK)?;
+        tail += RENDER_TICK;
+        ui_rx.try_iter().for_each(drop);
+    }
+    let elapsed = clock.elapsed;
+    manager.finish();
+    writer.finish()?;
+    Ok(elapsed)
+}
+
+/// Moves the simulated time forward, updating the manager and writing its output.
+struct RenderClock<'a> {
+    /// Output of the detached master bus.
+    source: BoxedSource,
+    writer: &'a mut AudioFileWriter,
+    buffer: Vec<f32>,
+    /// Simulated time since the start, in miliseconds.
+    elapsed: usize,
+}
+
+impl RenderClock<'_> {
+    fn advance(&mut self, manager: &mut SoundManager, duration: usize) -> Result<()> {
+        let samples = RENDER_TICK
+            * self.writer.sample_rate() as usize
+            / 1000
+            * self.writer.channels() as usize;
+        let mut remaining = duration;
+        while remaining > 0 {
+            manager.maintain(RENDER_TICK)?;
+            self.buffer.clear();
+            self.buffer.extend(self.source.by_ref().take(samples));
+            self.writer.write_samples(&self.buffer)?;
+            remaining = remaining.saturating_sub(RENDER_TICK);
+            self.elapsed += RENDER_TICK;
+        }
+        Ok(())
+    }
+}
+
+/// Samples played by a master bus.
+struct RecordedBlock {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+/// Records everything played by the master bus into an audio file, while playing normally.
+/// The file is written by its own thread, and stays valid if SoundSense-RS is closed while recording.
+/// Channels routed to their own device aren't recorded.
+#[derive(Clone)]
+pub struct Recorder {
+    sender: Sender<RecordedBlock>,
+    /// Incremented for every new tap. Only the newest tap is recorded,
+    /// so a master bus fading out after a device change doesn't overlap the new one.
+    generation: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    /// Create the file, and start the thread writing it.
+    /// The recording is stereo at 44.1kHz. Devices using another format are converted.
+    pub fn start(path: &Path) -> Result<Self> {
+        let writer = AudioFileWriter::create(path, DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE)?;
+        let (sender, receiver) = crossbeam::channel::unbounded::<RecordedBlock>();
+        info!("Recording to {}", path.display());
+        let path = path.to_path_buf();
+        std::thread::Builder::new()
+            .name("recorder_thread".to_string())
+            .spawn(move || record(&receiver, writer, &path))?;
+        Ok(Self {
+            sender,
+            generation: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Wrap a master bus' output, so it's recorded while it plays.
+    pub fn tap(&self, source: BoxedSource) -> RecorderTap {
+        let block_len = RECORDED_BLOCK_FRAMES * source.channels() as usize;
+        RecorderTap {
+            input: source,
+            sender: self.sender.clone(),
+            generation: self.generation.clone(),
+            own_generation: self.generation.fetch_add(1, Ordering::Relaxed) + 1,
+            block: Vec::with_capacity(block_len),
+            block_len,
+        }
+    }
+}
+
+/// Write the blocks received into the file, until every tap and the Recorder are dropped.
+/// Blocks in the same format are converted as one source, so the conversion stays continuous.
+fn record(receiver: &Receiver<RecordedBlock>, mut writer: AudioFileWriter, path: &Path) {
+    let mut converted = false;
+    let mut next_block = receiver.recv().ok();
+    while let Some(block) = next_block.take() {
+        let same_format = block.channels == writer.channels()
+            && block.sample_rate == writer.sample_rate();
+        if !same_format && !converted {
+            converted = true;
+            info!("Converting the recording from {} channel(s) at {}Hz.", block.channels, block.sample_rate);
+        }
+        let received = ReceivedBlocks {
+            receiver,
+            channels: block.channels,
+            sample_rate: block.sample_rate,
+            samples: block.samples.into_iter(),
+            next_block: &mut next_block,
+        };
+        let result = if same_format {
+            write_all(&mut writer, received)
+        } else {
+            let (channels, sample_rate) = (writer.channels(), writer.sample_rate());
+            write_all(&mut writer, source::UniformSourceIterator::new(received, channels, sample_rate))
+        };
+        if let Err(e) = result {
+            error!("Failed to write the recording {}: {}", path.display(), e);
+            return
+        }
+    }
+    if let Err(e) = writer.finish() {
+        error!("Failed to finish the recording {}: {}", path.display(), e);
+    }
+}
+
+/// Write samples into the file, a block at a time.
+fn write_all(writer: &mut AudioFileWriter, mut samples: impl Iterator<Item=f32>) -> Result<()> {
+    let block_len = RECORDED_BLOCK_FRAMES * writer.channels() as usize;
+    let mut buffer = Vec::with_capacity(block_len);
+    loop {
+        buffer.clear();
+        buffer.extend(samples.by_ref().take(block_len));
+        if buffer.is_empty() {
+            return Ok(())
+        }
+        writer.write_samples(&buffer)?;
+    }
+}
+
+/// The blocks received by the recorder, as one source, until one comes in another format.
+/// That block is left in `next_block`.
+struct ReceivedBlocks<'a> {
+    receiver: &'a Receiver<RecordedBlock>,
+    channels: u16,
+    sample_rate: u32,
+    samples: std::vec::IntoIter<f32>,
+    next_block: &'a mut Option<RecordedBlock>,
+}
+
+impl Iterator for ReceivedBlocks<'_> {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            if let Some(sample) = self.samples.next() {
+                return Some(sample)
+            }
+            let block = self.receiver.recv().ok()?;
+            if block.channels != self.channels || block.sample_rate != self.sample_rate {
+                *self.next_block = Some(block);
+                return None
+            }
+            self.samples = block.samples.into_iter();
+        }
+    }
+}
+
+impl Source for ReceivedBlocks<'_> {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Source wrapper sending a copy of every sample to the recorder.
+pub struct RecorderTap {
+    input: BoxedSource,
+    sender: Sender<RecordedBlock>,
+    generation: Arc<AtomicUsize>,
+    own_generation: usize,
+    block: Vec<f32>,
+    block_len: usize,
+}
+
+impl RecorderTap {
+    fn send(&mut self) {
+        let samples = std::mem::replace(&mut self.block, Vec::with_capacity(self.block_len));
+        if self.generation.load(Ordering::Relaxed) != self.own_generation {
+            return
+        }
+        // The recorder only stops if it failed, and already said so.
+        let _ = self.sender.send(RecordedBlock {
+            channels: self.input.channels(),
+            sample_rate: self.input.sample_rate(),
+            samples,
+        });
+    }
+}
+
+impl Iterator for RecorderTap {
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+        self.block.push(sample);
+        if self.block.len() >= self.block_len {
+            self.send();
+        }
+        Some(sample)
+    }
+}
+
+impl Source for RecorderTap {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl Drop for RecorderTap {
+    fn drop(&mut self) {
+        if !self.block.is_empty() {
+            self.send();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    /// Send a mono sine at 48kHz to the recorder, in blocks, and read back the recording.
+    fn record_sine(path: &Path, frequency: f32, blocks: usize) -> Vec<i16> {
+        let writer = AudioFileWriter::create(path, DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE).unwrap();
+        let (sender, receiver) = crossbeam::channel::unbounded();
+        for block in 0..blocks {
+            let samples = (0..RECORDED_BLOCK_FRAMES)
+                .map(|i| {
+                    let t = (block * RECORDED_BLOCK_FRAMES + i) as f32 / 48000.0;
+                    (t * frequency * std::f32::consts::TAU).sin() * 0.5
+                })
+                .collect();
+            sender.send(RecordedBlock {channels: 1, sample_rate: 48000, samples}).unwrap();
+        }
+        drop(sender);
+        record(&receiver, writer, path);
+        hound::WavReader::open(path).unwrap()
+            .into_samples::<i16>()
+            .map(|sample| sample.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn converts_continuously() {
+        let dir = test_dir("recorder");
+        let blocks = 100;
+        let recorded = record_sine(&dir.join("sine.wav"), 440.0, blocks);
+        let frames = recorded.len() / 2;
+        let expected = blocks * RECORDED_BLOCK_FRAMES * 44100 / 48000;
+        assert!((frames as isize - expected as isize).abs() <= 2, "{} frames instead of {}", frames, expected);
+        // Both sides get the mono input.
+        assert!(recorded.chunks(2).all(|frame| frame[0] == frame[1]));
+        // No clicks or drift: the recording stays close to the sine, even between blocks.
+        // It may lag by a frame or so, as the conversion interpolates.
+        let max_step = 0.5 * 32767.0 * std::f32::consts::TAU * 440.0 / 44100.0;
+        for (i, frame) in recorded.chunks(2).enumerate() {
+            let t = i as f32 / 44100.0;
+            let expected = (t * 440.0 * std::f32::consts::TAU).sin() * 0.5 * 32767.0;
+            let error = (frame[0] as f32 - expected).abs();
+            assert!(error <= max_step * 2.0, "off by {} at frame {}", error, i);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
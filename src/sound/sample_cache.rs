@@ -0,0 +1,557 @@
+use super::*;
+use std::sync::Mutex;
+
+/// Default size of the sample cache, in megabytes.
+pub const DEFAULT_SAMPLE_CACHE_SIZE: usize = 64;
+/// Files bigger than this, in bytes, are never cached.
+const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024;
+/// Files longer than this, in miliseconds, are never cached.
+const MAX_DURATION: usize = 10_000;
+
+/// A sound decoded into memory.
+struct DecodedSound {
+    channels: u16,
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl DecodedSound {
+    /// Decode the whole file, unless it's longer than MAX_DURATION.
+    fn decode(path: &Path) -> Result<Option<Self>> {
+        let decoder = Decoder::new(File::open(path)?)?;
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let max_samples = MAX_DURATION * sample_rate as usize * channels as usize / 1000;
+        let mut samples = Vec::new();
+        for sample in decoder {
+            if samples.len() >= max_samples {
+                return Ok(None)
+            }
+            samples.push(sample);
+        }
+        samples.shrink_to_fit();
+        Ok(Some(Self {
+            channels,
+            sample_rate,
+            samples,
+        }))
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.samples.len() * std::mem::size_of::<i16>()
+    }
+}
+
+/// Source playing a sound from the cache.
+pub struct CachedSource {
+    sound: Arc<DecodedSound>,
+    pos: usize,
+}
+
+impl Iterator for CachedSource {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.sound.samples.get(self.pos).copied();
+        self.pos += 1;
+        sample
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.sound.samples.len().saturating_sub(self.pos);
+        (remaining, Some(remaining))
+    }
+}
+
+impl Source for CachedSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.sound.samples.len().saturating_sub(self.pos))
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.sound.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sound.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        let frames = self.sound.samples.len() / self.sound.channels.max(1) as usize;
+        Some(Duration::from_millis(frames as u64 * 1000 / self.sound.sample_rate.max(1) as u64))
+    }
+}
+
+/// Source decoding a file while playing it, keeping the samples.
+/// Once it ends, the samples are added to the cache, so the file is only decoded once.
+/// Files longer than MAX_DURATION stop being kept, and are never cached.
+struct CachingSource {
+    decoder: Decoder<File>,
+    path: PathBuf,
+    cache: SampleCache,
+    /// Samples decoded so far. None once the file is too long to be cached.
+    samples: Option<Vec<i16>>,
+    max_samples: usize,
+}
+
+impl CachingSource {
+    fn new(decoder: Decoder<File>, path: &Path, cache: SampleCache) -> Self {
+        let max_samples = MAX_DURATION * decoder.sample_rate() as usize * decoder.channels() as usize / 1000;
+        Self {
+            decoder,
+            path: path.to_path_buf(),
+            cache,
+            samples: Some(Vec::new()),
+            max_samples,
+        }
+    }
+}
+
+impl Iterator for CachingSource {
+    type Item = i16;
+
+    #[inline]
+    fn next(&mut self) -> Option<i16> {
+        match self.decoder.next() {
+            Some(sample) => {
+                if let Some(samples) = &mut self.samples {
+                    if samples.len() < self.max_samples {
+                        samples.push(sample);
+                    } else {
+                        self.samples = None;
+                        self.cache.mark_uncacheable(&self.path);
+                    }
+                }
+                Some(sample)
+            }
+            None => {
+                if let Some(mut samples) = self.samples.take() {
+                    samples.shrink_to_fit();
+                    self.cache.insert(&self.path, DecodedSound {
+                        channels: self.decoder.channels(),
+                        sample_rate: self.decoder.sample_rate(),
+                        samples,
+                    });
+                }
+                None
+            }
+        }
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.decoder.size_hint()
+    }
+}
+
+impl Source for CachingSource {
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.decoder.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.decoder.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.decoder.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.decoder.total_duration()
+    }
+}
+
+struct CacheEntry {
+    sound: Arc<DecodedSound>,
+    /// Value of the cache's clock when the entry was last used.
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: HashMap<PathBuf, CacheEntry>,
+    /// Paths of the entries, by the clock value they were last used at. The first is the least recently used.
+    by_last_use: BTreeMap<u64, PathBuf>,
+    /// Files that are too big or too long to be cached.
+    uncacheable: HashSet<PathBuf>,
+    /// Total size of the cached sounds, in bytes.
+    size: usize,
+    /// Maximum size of the cached sounds, in bytes.
+    capacity: usize,
+    /// Incremented every time an entry is used.
+    clock: u64,
+    hits: usize,
+    misses: usize,
+    evictions: usize,
+    /// Whether the statistics changed since they were last described.
+    changed: bool,
+}
+
+impl CacheState {
+    /// Remove the least recently used entries, until `size` more bytes fit.
+    fn make_room(&mut self, size: usize) {
+        while self.size + size > self.capacity {
+            let oldest = self.by_last_use.pop_first();
+            match oldest.and_then(|(_, path)| self.entries.remove(&path)) {
+                Some(entry) => {
+                    self.size -= entry.sound.size();
+                    self.evictions += 1;
+                }
+                None => return,
+            }
+        }
+    }
+
+    /// Advance the clock, and mark the entry as used now.
+    fn touch(&mut self, path: &Path) -> Option<Arc<DecodedSound>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(path)?;
+        let path = self.by_last_use.remove(&entry.last_used)?;
+        entry.last_used = clock;
+        self.by_last_use.insert(clock, path);
+        Some(entry.sound.clone())
+    }
+}
+
+struct SampleCacheInner {
+    state: Mutex<CacheState>,
+    /// Tells the pre-warming thread to stop.
+    cancelled: AtomicBool,
+}
+
+/// Bounded LRU cache of decoded short sounds, so frequent oneshots aren't decoded every time.
+/// Sounds are decoded while playing, and cached once they played to the end.
+/// Files too big or too long to be cached are always decoded while playing.
+#[derive(Clone)]
+pub struct SampleCache(Arc<SampleCacheInner>);
+
+impl SampleCache {
+    /// Create a cache holding up to `capacity` megabytes of samples. 0 disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_bytes(capacity * 1024 * 1024)
+    }
+
+    /// Create a cache holding up to `capacity` bytes of samples.
+    fn with_bytes(capacity: usize) -> Self {
+        Self(Arc::new(SampleCacheInner {
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                by_last_use: BTreeMap::new(),
+                uncacheable: HashSet::new(),
+                size: 0,
+                capacity,
+                clock: 0,
+                hits: 0,
+                misses: 0,
+                evictions: 0,
+                changed: false,
+            }),
+            cancelled: AtomicBool::new(false),
+        }))
+    }
+
+    /// Get a source playing the file at this path.
+    /// Returns None if the file can't be played.
+    pub fn get_source(&self, path: &Path) -> Option<Box<dyn Source<Item=i16> + Send>> {
+        if let Some(sound) = self.lookup(path) {
+            return Some(Box::new(CachedSource { sound, pos: 0 }))
+        }
+        let f = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Path {} is invalid: {}", path.display(), e);
+                warn!("Will ignore this source.");
+                return None
+            }
+        };
+        let cacheable = self.is_cacheable(path, &f);
+        match Decoder::new(f) {
+            Ok(source) if cacheable => Some(Box::new(CachingSource::new(source, path, self.clone()))),
+            Ok(source) => Some(Box::new(source)),
+            Err(e) => {
+                warn!("Failed to assert {}: {}", path.display(), e);
+                warn!("Will ignore this source.");
+                None
+            }
+        }
+    }
+
+    /// Decode the files in the background, until the cache is full.
+    pub fn prewarm(&self, paths: Vec<PathBuf>) {
+        if self.0.state.lock().unwrap().capacity == 0 {
+            return
+        }
+        info!("Pre-warming the sample cache with {} file(s).", paths.len());
+        let cache = self.clone();
+        std::thread::Builder::new()
+            .name("prewarm_thread".to_string())
+            .spawn(move || {
+                for path in paths.iter() {
+                    if cache.0.cancelled.load(Ordering::Relaxed) {
+                        return
+                    }
+                    {
+                        let state = cache.0.state.lock().unwrap();
+                        if state.entries.contains_key(path) || state.uncacheable.contains(path) {
+                            continue
+                        }
+                        // Don't evict sounds that were actually played.
+                        if state.size >= state.capacity {
+                            break
+                        }
+                    }
+                    if let Err(e) = cache.load(path) {
+                        warn!("Failed to pre-warm {}: {}", path.display(), e);
+                    }
+                }
+                info!("Finished pre-warming the sample cache.");
+            })
+            .unwrap();
+    }
+
+    /// Stop pre-warming.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Describe the cache's statistics for the UI, if they changed since the last call.
+    pub fn poll_stats(&self) -> Option<Vec<(Box<str>, Box<str>)>> {
+        let mut state = self.0.state.lock().unwrap();
+        if !state.changed {
+            return None
+        }
+        state.changed = false;
+        let lookups = state.hits + state.misses;
+        let hit_rate = (state.hits * 100).checked_div(lookups).unwrap_or(0);
+        Some(vec![
+            ("Cached sounds".into(), state.entries.len().to_string().into()),
+            ("Cache size".into(), format!(
+                "{:.1}/{} MB",
+                state.size as f32 / (1024.0 * 1024.0),
+                state.capacity / (1024 * 1024)
+            ).into()),
+            ("Hit rate".into(), format!("{}%", hit_rate).into()),
+            ("Evictions".into(), state.evictions.to_string().into()),
+        ])
+    }
+
+    /// Get a cached sound, marking it as used.
+    fn lookup(&self, path: &Path) -> Option<Arc<DecodedSound>> {
+        let mut state = self.0.state.lock().unwrap();
+        if state.capacity == 0 {
+            return None
+        }
+        let sound = state.touch(path);
+        if sound.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+        state.changed = true;
+        sound
+    }
+
+    /// Whether the file, opened to play it, can be cached. Files too big to be cached are remembered.
+    fn is_cacheable(&self, path: &Path, file: &File) -> bool {
+        let mut state = self.0.state.lock().unwrap();
+        if state.capacity == 0 || state.uncacheable.contains(path) {
+            return false
+        }
+        match file.metadata() {
+            Ok(metadata) if metadata.len() <= MAX_FILE_SIZE => true,
+            _ => {
+                trace!("{} is too big to be cached.", path.display());
+                state.uncacheable.insert(path.to_path_buf());
+                false
+            }
+        }
+    }
+
+    /// Remember that the file is too big or too long to be cached.
+    fn mark_uncacheable(&self, path: &Path) {
+        trace!("{} is too long to be cached.", path.display());
+        self.0.state.lock().unwrap().uncacheable.insert(path.to_path_buf());
+    }
+
+    /// Decode a file and add it to the cache.
+    /// Returns false if the file is too big or too long to be cached.
+    fn load(&self, path: &Path) -> Result<bool> {
+        {
+            let state = self.0.state.lock().unwrap();
+            if state.capacity == 0 || state.uncacheable.contains(path) {
+                return Ok(false)
+            }
+        }
+        let sound = if fs::metadata(path)?.len() > MAX_FILE_SIZE {
+            None
+        } else {
+            // Decoded without holding the lock, as this can take a while.
+            DecodedSound::decode(path)?
+        };
+        match sound {
+            Some(sound) => Ok(self.insert(path, sound)),
+            None => {
+                self.mark_uncacheable(path);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Add a decoded sound to the cache, evicting the least recently used ones to make room.
+    /// Returns false if the sound is bigger than the whole cache.
+    fn insert(&self, path: &Path, sound: DecodedSound) -> bool {
+        let mut state = self.0.state.lock().unwrap();
+        if sound.size() > state.capacity {
+            trace!("{} is too big to be cached.", path.display());
+            state.uncacheable.insert(path.to_path_buf());
+            return false
+        }
+        if state.entries.contains_key(path) {
+            // Cached by another source in the meantime.
+            return true
+        }
+        let sound = Arc::new(sound);
+        state.make_room(sound.size());
+        state.size += sound.size();
+        state.clock += 1;
+        let last_used = state.clock;
+        state.entries.insert(path.to_path_buf(), CacheEntry { sound, last_used });
+        state.by_last_use.insert(last_used, path.to_path_buf());
+        state.changed = true;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    /// Write a mono file of `frames` frames at 8kHz, which takes `2 * frames` bytes once cached.
+    fn write_sound(dir: &Path, name: &str, frames: usize) -> PathBuf {
+        let path = dir.join(name);
+        let mut writer = AudioFileWriter::create(&path, 1, 8000).unwrap();
+        let samples: Vec<f32> = (0..frames).map(|i| (i % 100) as f32 / 200.0).collect();
+        writer.write_samples(&samples).unwrap();
+        writer.finish().unwrap();
+        path
+    }
+
+    /// Play the whole file, returning its samples.
+    fn play(cache: &SampleCache, path: &Path) -> Vec<i16> {
+        cache.get_source(path).unwrap().collect()
+    }
+
+    fn is_cached(cache: &SampleCache, path: &Path) -> bool {
+        cache.0.state.lock().unwrap().entries.contains_key(path)
+    }
+
+    fn hits_and_misses(cache: &SampleCache) -> (usize, usize) {
+        let state = cache.0.state.lock().unwrap();
+        (state.hits, state.misses)
+    }
+
+    #[test]
+    fn cached_once_played() {
+        let dir = test_dir("sample-cache");
+        let path = write_sound(&dir, "a.wav", 1000);
+        let cache = SampleCache::with_bytes(10_000);
+        let streamed = play(&cache, &path);
+        assert_eq!(streamed.len(), 1000);
+        assert!(is_cached(&cache, &path));
+        assert_eq!(hits_and_misses(&cache), (0, 1));
+        assert_eq!(play(&cache, &path), streamed);
+        assert_eq!(hits_and_misses(&cache), (1, 1));
+        assert_eq!(cache.0.state.lock().unwrap().size, 2000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stopped_sounds_arent_cached() {
+        let dir = test_dir("sample-cache-stopped");
+        let path = write_sound(&dir, "a.wav", 1000);
+        let cache = SampleCache::with_bytes(10_000);
+        assert_eq!(cache.get_source(&path).unwrap().take(500).count(), 500);
+        assert!(!is_cached(&cache, &path));
+        play(&cache, &path);
+        assert!(is_cached(&cache, &path));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let dir = test_dir("sample-cache-lru");
+        let a = write_sound(&dir, "a.wav", 1000);
+        let b = write_sound(&dir, "b.wav", 1000);
+        let c = write_sound(&dir, "c.wav", 1000);
+        // Room for two sounds.
+        let cache = SampleCache::with_bytes(5000);
+        play(&cache, &a);
+        play(&cache, &b);
+        play(&cache, &a);
+        play(&cache, &c);
+        assert!(is_cached(&cache, &a));
+        assert!(!is_cached(&cache, &b));
+        assert!(is_cached(&cache, &c));
+        let state = cache.0.state.lock().unwrap();
+        assert_eq!(state.evictions, 1);
+        assert_eq!(state.size, 4000);
+        assert_eq!(state.by_last_use.len(), 2);
+        drop(state);
+        assert_eq!(hits_and_misses(&cache), (1, 3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn uncacheable() {
+        let dir = test_dir("sample-cache-uncacheable");
+        // Longer than MAX_DURATION, but still played whole.
+        let long = write_sound(&dir, "long.wav", 8 * MAX_DURATION + 10);
+        // Bigger than the whole cache.
+        let big = write_sound(&dir, "big.wav", 3000);
+        let cache = SampleCache::with_bytes(5000);
+        for path in [&long, &big].iter() {
+            let frames = play(&cache, path).len();
+            assert_eq!(play(&cache, path).len(), frames);
+            assert!(!is_cached(&cache, path));
+            assert!(cache.0.state.lock().unwrap().uncacheable.contains(*path));
+        }
+        assert_eq!(play(&cache, &long).len(), 8 * MAX_DURATION + 10);
+        assert_eq!(cache.0.state.lock().unwrap().size, 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disabled() {
+        let dir = test_dir("sample-cache-disabled");
+        let path = write_sound(&dir, "a.wav", 1000);
+        let cache = SampleCache::new(0);
+        assert_eq!(play(&cache, &path).len(), 1000);
+        assert!(!is_cached(&cache, &path));
+        assert_eq!(hits_and_misses(&cache), (0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prewarmed() {
+        let dir = test_dir("sample-cache-prewarm");
+        let path = write_sound(&dir, "a.wav", 1000);
+        let cache = SampleCache::with_bytes(10_000);
+        assert!(cache.load(&path).unwrap());
+        assert_eq!(play(&cache, &path).len(), 1000);
+        assert_eq!(hits_and_misses(&cache), (1, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,46 +1,74 @@
 use super::*;
-use source::Spatial;
 
 mod loop_player;
 mod oneshot_player;
+mod scheduler;
 
 use loop_player::LoopPlayer;
 use oneshot_player::OneshotPlayer;
+use scheduler::{Scheduler, Playback};
 
 /// Struct responsible for containing currently playing sounds.
 /// "music" and "weather" channels can play only one sound at a time.
+/// Sounds are mixed by the channel's bus, which applies its volume, ducking and pause.
 pub struct SoundChannel {
     looping: LoopPlayer,
     one_shots: OneshotPlayer,
     local_volume: VolumeLock,
-    delay: usize,
+    /// Volume while ducked by other channels.
+    duck_volume: VolumeLock,
+    /// Delayed sounds, and loop changes, waiting to be played.
+    scheduler: Scheduler,
     local_is_paused: IsPausedLock,
     threshold: u8,
     pub play_type: ChannelPlayType,
+    /// Length of the crossfade between loops, in miliseconds. 0 cuts the previous loop.
+    pub crossfade: usize,
+    /// How a new oneshot lowers the channel's other sounds.
+    pub duck: DuckSetting,
+    /// Channels lowered while this channel plays oneshots, and by how much.
+    pub ducks: Vec<(Box<str>, f32)>,
+    /// Gives the loudness normalization gain of each file.
+    normalizer: Normalizer,
+    /// Decoded short sounds, shared by every channel.
+    sample_cache: SampleCache,
+    /// Name of the output device the channel is routed to. None follows the manager's device.
+    device_name: Option<Box<str>>,
+    /// Master bus on the device the channel is routed to, if it was found.
+    /// Otherwise the channel plays on the manager's master bus.
+    routed: Option<MasterBus>,
+    /// Mixes the channel's sounds into a master bus.
+    bus: ChannelBus,
+    /// The channel's own effects, from its settings or the UI. Effect changes are made from them.
+    base_effects: EffectSettings,
 }
 
 impl SoundChannel {
     /// Create a new SoundChannel.
     #[inline]
-    pub fn new(device: &Device, name: &str, total_volume: VolumeLock, total_is_paused: IsPausedLock) -> Self {
+    pub fn new(
+        master: &MasterBus,
+        name: &str,
+        normalizer: Normalizer,
+        sample_cache: SampleCache,
+        voices: VoicePool,
+    ) -> Self {
         let local_volume = VolumeLock::new();
+        let duck_volume = VolumeLock::new();
         let local_is_paused = IsPausedLock::new();
+        let bus = ChannelBus::new(
+            master,
+            local_volume.clone(),
+            duck_volume.clone(),
+            local_is_paused.clone(),
+            DEFAULT_FADE_LENGTH,
+        );
         Self {
-            looping : LoopPlayer::new(
-                device,
-                local_volume.clone(),
-                total_volume.clone(),
-                local_is_paused.clone(),
-                total_is_paused.clone(),
-            ),
-            one_shots : OneshotPlayer::new(
-                local_volume.clone(),
-                total_volume,
-                local_is_paused.clone(),
-                total_is_paused,
-            ),
+            looping : LoopPlayer::new(&bus, normalizer.clone()),
+            one_shots : OneshotPlayer::new(voices, local_volume.clone()),
             local_volume,
-            delay : 0,
+            duck_volume,
+            scheduler: Scheduler::new(),
             play_type: {
                 if name == "weather" || name == "music" {
                     ChannelPlayType::SingleEager
@@ -51,32 +79,105 @@ impl SoundChannel {
             },
             local_is_paused,
             threshold: 4,
+            crossfade: 0,
+            duck: DuckSetting::default(),
+            ducks: Vec::new(),
+            normalizer,
+            sample_cache,
+            device_name: None,
+            routed: None,
+            bus,
+            base_effects: EffectSettings::default(),
         }
     }
 
     /// Maintain this channel.
-    /// Maintain looping player, tick down delay, cleanup oneshots.
-	pub fn maintain(&mut self, rng: &mut ThreadRng, dt: usize) {
-		let delay = self.delay.saturating_sub(dt);
-        self.delay = delay;
-        if self.delay > 0 {
-            self.one_shots.pause()
+    /// Play scheduled sounds that are due, maintain looping player, cleanup oneshots, duck sounds.
+    /// `ducked_to` is the volume other channels' ducking rules want this channel at.
+	pub fn maintain(&mut self, rng: &mut StdRng, dt: usize, variables: &Variables, ducked_to: f32) {
+        for (remaining, playback) in self.scheduler.advance(dt) {
+            self.start_playback(remaining, playback, rng, variables);
         }
         self.one_shots.maintain();
-        if self.one_shots.is_empty() && delay == 0
+        if !self.holds_loop()
             && !self.looping.is_stopped() {
             self.looping.play();
-            self.looping.set_volume(1.0);
         }
         else {
             self.looping.pause()
         }
-        self.looping.maintain(rng);
+        self.looping.maintain(rng, variables);
+        self.maintain_ducking(dt, ducked_to);
 	}
 
-    /// Change the loop.
+    /// Whether the loop is paused while oneshots play.
+    /// "singleEager" channels play one sound at a time, and a duck of 100% would silence it anyway.
+    /// Otherwise, the loop keeps playing, ducked.
+    #[inline]
+    fn holds_loop(&self) -> bool {
+        !self.one_shots.is_empty()
+            && (self.play_type == ChannelPlayType::SingleEager || self.duck.amount >= 1.0)
+    }
+
+    /// Move volumes towards their ducked or restored values.
+    /// Every oneshot but the newest is ducked, and so is the loop while oneshots play, unless it's paused.
+    fn maintain_ducking(&mut self, dt: usize, ducked_to: f32) {
+        let duck = self.duck;
+        let count = self.one_shots.len();
+        for idx in 0..count {
+            let target = if idx + 1 < count {1.0 - duck.amount} else {1.0};
+            let volume = duck.approach(self.one_shots.get_volume(idx), target, dt);
+            self.one_shots.set_volume(idx, volume);
+        }
+        let target = if count > 0 && !self.holds_loop() {1.0 - duck.amount} else {1.0};
+        self.looping.set_volume(duck.approach(self.looping.get_volume(), target, dt));
+        self.duck_volume.set(duck.approach(self.duck_volume.get(), ducked_to, dt));
+    }
+
+    /// Whether oneshots are playing, which makes the channel duck others.
+    #[inline]
+    pub fn is_ducking(&self) -> bool {
+        !self.one_shots.is_empty()
+    }
+
+    /// Start a scheduled playback. `remaining` is the time left until it's due, in miliseconds.
+    /// Oneshots are preceded by that much silence, so they start exactly on time.
+    fn start_playback(&mut self, remaining: usize, playback: Playback, rng: &mut StdRng, variables: &Variables) {
+        match playback {
+            Playback::Oneshot(source, volume, balance, priority, instance) => if self.prepare_oneshot(priority) {
+                let source = source.delay(Duration::from_millis(remaining as u64));
+                self.one_shots.add_source(&self.bus, source, volume, balance, priority, instance.instances());
+            }
+            Playback::LoopStart(files, crossfade) => self.start_loop(&files, crossfade, rng, variables),
+            Playback::LoopStop => self.looping.end(),
+        }
+    }
+
+    /// Change the loop, after `delay` miliseconds.
     /// If "music" or "weather", stop all oneshots.
-    pub fn change_loop(&mut self, device: &Device, files: &[SoundFile], delay: usize, rng: &mut ThreadRng) {
+    /// `crossfade` overrides the channel's crossfade length.
+    pub fn change_loop(
+        &mut self,
+        files: &[SoundFile],
+        delay: usize,
+        crossfade: Option<usize>,
+        rng: &mut StdRng,
+        variables: &Variables,
+    ) {
+        if delay > 0 {
+            self.scheduler.schedule(delay, Playback::LoopStart(files.to_vec(), crossfade));
+        } else {
+            self.start_loop(files, crossfade, rng, variables);
+        }
+    }
+
+    fn start_loop(
+        &mut self,
+        files: &[SoundFile],
+        crossfade: Option<usize>,
+        rng: &mut StdRng,
+        variables: &Variables,
+    ) {
         if self.play_type == ChannelPlayType::SingleLazy {
             if self.len() != 0 {
                 return
@@ -85,14 +186,18 @@ impl SoundChannel {
         else if self.play_type == ChannelPlayType::SingleEager {
             self.one_shots.stop();
         }
-        self.looping.change_loop(device, files, rng);
-        self.delay = delay;
-        self.maintain(rng, 0);
+        self.looping.change_loop(&self.bus, files, crossfade.unwrap_or(self.crossfade), rng, variables);
+        let ducked_to = self.duck_volume.get();
+        self.maintain(rng, 0, variables, ducked_to);
     }
 
+    /// Stop the loop, after `delay` miliseconds.
     pub fn stop_loop(&mut self, delay: usize) {
-        self.looping.stop();
-        self.delay = delay;
+        if delay > 0 {
+            self.scheduler.schedule(delay, Playback::LoopStop);
+        } else {
+            self.looping.end();
+        }
     }
 
     pub fn skip(&mut self) {
@@ -105,44 +210,187 @@ impl SoundChannel {
     }
 
     pub fn finish(&mut self) {
-        self.looping.stop();
+        self.scheduler.clear();
+        self.looping.finish();
+        self.one_shots.stop();
+        self.bus.finish();
+        if let Some(routed) = self.routed.take() {
+            routed.finish();
+        }
+    }
+
+    /// Move the channel to the manager's new master bus, unless it's routed to its own device.
+    /// Oneshots are stopped, and the loop's current sound starts over.
+    pub fn change_master(&mut self, master: &MasterBus, rng: &mut StdRng, variables: &Variables) {
+        if self.routed.is_none() {
+            self.move_to(master, rng, variables);
+        }
+    }
+
+    /// Route the channel to the named output device, playing on its `routed` master bus,
+    /// or back to the manager's `master` with None.
+    /// If the named device can't be found, `routed` is None and the channel plays on the manager's master bus.
+    pub fn route(
+        &mut self,
+        name: Option<&str>,
+        routed: Option<MasterBus>,
+        master: &MasterBus,
+        rng: &mut StdRng,
+        variables: &Variables,
+    ) {
+        self.device_name = name.map(Box::from);
+        self.move_to(routed.as_ref().unwrap_or(master), rng, variables);
+        if let Some(previous) = std::mem::replace(&mut self.routed, routed) {
+            previous.finish();
+        }
+    }
+
+    /// Replace the channel's bus with a new one, feeding `master`.
+    /// Oneshots are stopped, and the loop's current sound starts over.
+    fn move_to(&mut self, master: &MasterBus, rng: &mut StdRng, variables: &Variables) {
+        let bus = self.bus.sibling(master);
+        self.replace_bus(bus, rng, variables);
+    }
+
+    fn replace_bus(&mut self, bus: ChannelBus, rng: &mut StdRng, variables: &Variables) {
         self.one_shots.stop();
+        std::mem::replace(&mut self.bus, bus).finish();
+        self.looping.change_bus(&self.bus, rng, variables);
+    }
+
+    /// Limit the channel's peaks to a ceiling, in dBFS, or remove its limiter with None.
+    /// `master` is the manager's master bus, used unless the channel is routed to its own device.
+    pub fn set_limiter(&mut self, ceiling: Option<f32>, master: &MasterBus, rng: &mut StdRng, variables: &Variables) {
+        let bus = self.bus.with_limiter(self.routed.as_ref().unwrap_or(master), ceiling);
+        self.replace_bus(bus, rng, variables);
+    }
+
+    /// Name of the output device the channel is routed to.
+    #[inline]
+    pub fn device_name(&self) -> Option<&str> {
+        self.device_name.as_deref()
+    }
+
+    /// Check that the device the channel is routed to still plays it.
+    #[inline]
+    pub fn check_routed(&self) -> bool {
+        match &self.routed {
+            Some(routed) => routed.check_sink(),
+            None => true,
+        }
+    }
+
+    /// Whether the channel is routed to a device, but doesn't play on it:
+    /// the device was lost, or wasn't found.
+    #[inline]
+    pub fn is_routing_lost(&self) -> bool {
+        self.device_name.is_some()
+            && match &self.routed {
+                Some(routed) => routed.has_failed(),
+                None => true,
+            }
+    }
+
+    /// Set the length of the fades when pausing, skipping or stopping sounds.
+    #[inline]
+    pub fn set_fade_length(&mut self, fade_length: usize) {
+        self.looping.fade_length = fade_length;
+        self.one_shots.fade_length = fade_length;
+        self.bus.set_fade_length(fade_length);
+    }
+
+    /// Change the channel's own effects, moving to the new settings over `transition` miliseconds.
+    /// Replaces any effect change made by a sound.
+    #[inline]
+    pub fn set_effects(&mut self, settings: EffectSettings, transition: usize) {
+        self.base_effects = settings;
+        self.bus.set_effects(settings, transition);
     }
 
-    /// Play a oneshot.
-    /// Will make other oneshots 50% quieter.
-    /// If "music" or "weather", pauses loop and stops other oneshots. 
-    pub fn add_oneshot(&mut self, device: &Device, file: &SoundFile, delay: usize, rng: &mut ThreadRng) {
+    /// Apply a sound's effect change, on top of the channel's own effects.
+    #[inline]
+    pub fn change_effects(&self, change: &EffectChange) {
+        self.bus.set_effects(change.apply(self.base_effects), change.transition);
+    }
+
+    #[inline]
+    pub fn effects(&self) -> EffectSettings {
+        self.bus.effects()
+    }
+
+    /// Play a oneshot, after the sound's `delay` and the file's own delay, in miliseconds.
+    /// Other oneshots and the loop will be ducked while it plays.
+    /// If "music" or "weather", pauses loop and stops other oneshots of lower or equal `priority`.
+    /// The oneshot counts as one of the sound's `instances`, from now if delayed.
+    pub fn add_oneshot(
+        &mut self,
+        file: &SoundFile,
+        delay: usize,
+        priority: i32,
+        instances: &Instances,
+        rng: &mut StdRng,
+    ) {
+        let delay = delay + file.delay;
+        let (source, volume, balance) = match get_soundfile(file, rng, &self.normalizer, &self.sample_cache) {
+            Some(data) => data,
+            None => return,
+        };
+        let source: BoxedSource = Box::new(source.convert_samples::<f32>());
+        if delay > 0 {
+            self.scheduler.schedule(delay, Playback::Oneshot(source, volume, balance, priority, instances.schedule()));
+        } else if self.prepare_oneshot(priority) {
+            self.one_shots.add_source(&self.bus, source, volume, balance, priority, instances);
+        }
+    }
+
+    /// Play a sequence as a single oneshot, after `delay` miliseconds.
+    /// The steps are chosen now, and chained into one source, so the sequence
+    /// counts as one sound and is skipped as a whole.
+    /// A file's own delay adds to its step's gap.
+    pub fn add_sequence(
+        &mut self,
+        steps: &[SequenceStep],
+        delay: usize,
+        priority: i32,
+        instances: &Instances,
+        rng: &mut StdRng,
+        variables: &Variables,
+    ) {
+        let source = match chain_sequence(steps, rng, variables, &self.normalizer, &self.sample_cache) {
+            Some(source) => source,
+            None => return,
+        };
+        if delay > 0 {
+            self.scheduler.schedule(delay, Playback::Oneshot(source, 1.0, 0.0, priority, instances.schedule()));
+        } else if self.prepare_oneshot(priority) {
+            self.one_shots.add_source(&self.bus, source, 1.0, 0.0, priority, instances);
+        }
+    }
+
+    /// Make room for a new oneshot of this priority, according to the channel's play type,
+    /// then in the voice pool.
+    /// Returns false if the oneshot shouldn't be played.
+    fn prepare_oneshot(&mut self, priority: i32) -> bool {
         if self.play_type == ChannelPlayType::SingleLazy {
             if self.len() != 0 {
-                return
+                return false
             }
         }
         else if self.play_type == ChannelPlayType::SingleEager {
+            // Sounds of higher priority aren't interrupted.
+            if let Some(playing) = self.one_shots.max_priority().filter(|&p| p > priority) {
+                trace!("   can't play: a sound of priority {} is playing", playing);
+                return false
+            }
             self.looping.pause();
             self.one_shots.stop();
         }
+        if !self.one_shots.make_room(priority) {
+            return false
+        }
 
         self.one_shots.play();
-        for idx in 0..self.one_shots.len() {
-            let current_vol = self.one_shots.get_volume(idx);
-            self.one_shots.set_volume(idx, current_vol * 0.5);
-        }
-        self.looping.set_volume(0.25);
-        let mut data = get_soundfiles(file, rng);
-        match data.len() {
-            0 => (),
-            1 => {
-                let (source, volume, balance) = data.remove(0);
-                self.one_shots.add_source(device, source, volume, balance);
-            }
-            _ => {
-                let (source, volume, balance)
-                    = data.remove(rng.gen_range(0, data.len()));
-                self.one_shots.add_source(device, source, volume, balance);
-            }
-        }
-        self.delay = delay;
+        true
     }
 
     #[inline]
@@ -159,63 +407,203 @@ impl SoundChannel {
         self.threshold = threshold;
     }
     #[inline]
-    pub fn get_threshold(&mut self) -> u8 {
+    pub fn get_threshold(&self) -> u8 {
         self.threshold
     }
 
+    /// Number of sounds playing, counting the oneshots waiting for their delay.
     #[inline]
     pub fn len(&self) -> usize {
-        self.one_shots.len() + self.looping.len()
+        self.one_shots.len() + self.scheduler.oneshots() + self.looping.len()
+    }
+
+    /// Whether sounds are waiting for their delay to pass.
+    #[inline]
+    pub fn has_scheduled(&self) -> bool {
+        !self.scheduler.is_empty()
+    }
+
+    /// Get the name of the loop's current sound, if it changed since the last call.
+    /// An empty name means nothing is playing.
+    #[inline]
+    pub fn poll_now_playing(&mut self) -> Option<Box<str>> {
+        self.looping.poll_now_playing()
     }
 }
 
-/// Get a Vector of (source, volume, balance) from a SoundFile.
-/// Note that non-playlist files will just return a 1-length Vector.
-fn get_soundfiles(soundfile: &SoundFile, rng: &mut ThreadRng)
-    -> Vec<(rodio::decoder::Decoder<std::fs::File>, f32, f32)>
-{
-    let volume = soundfile.volume;
-    let balance = if soundfile.random_balance {
-        rng.gen_range(-1.0, 1.0)
-    } else {
-        soundfile.balance
-    };
-    match soundfile.r#type {
-        SoundFileType::IsPath(ref path) => {
-            if let Some(source) = get_source(path) {
-                return vec![ (source, volume, balance) ]
+/// Choose the steps of a sequence, and chain them into one source.
+/// Returns None if no step was chosen.
+fn chain_sequence(
+    steps: &[SequenceStep],
+    rng: &mut StdRng,
+    variables: &Variables,
+    normalizer: &Normalizer,
+    sample_cache: &SampleCache,
+) -> Option<BoxedSource> {
+    let mut parts: Vec<Box<dyn Source<Item=f32> + Send>> = Vec::with_capacity(steps.len() * 2);
+    for (i, step) in steps.iter().enumerate() {
+        if let Some(probability) = step.probability {
+            if probability <= rng.gen_range(0usize, 100usize) {
+                trace!("   step {}: failed probability roll", i);
+                continue;
             }
         }
-        SoundFileType::IsPlaylist(ref paths) => {
-            if let Some(source) = get_source(&paths.choose(rng).unwrap())
-            {
-                return vec![ (source, volume, balance) ]
+        let weights = step.weights.iter()
+            .zip(step.files.iter())
+            .map(|(&weight, file)| if file.is_available(variables) {weight} else {0.0});
+        let file = match WeightedIndex::new(weights) {
+            Ok(weight) => &step.files[weight.sample(rng)],
+            Err(e) => {
+                trace!("   step {}: no file to play: {}", i, e);
+                continue;
             }
+        };
+        let (source, volume, balance) = match get_soundfile(file, rng, normalizer, sample_cache) {
+            Some(data) => data,
+            None => continue,
+        };
+        let source = Panner::new(source.convert_samples::<f32>().amplify(volume), balance);
+        let gap = step.gap + file.delay;
+        if gap > 0 {
+            // Built by hand, as take_duration miscounts samples for stereo sources.
+            let channels = source.channels();
+            let sample_rate = source.sample_rate();
+            let frames = gap * sample_rate as usize / 1000;
+            let gap = buffer::SamplesBuffer::new(
+                channels,
+                sample_rate,
+                vec![0.0f32; frames * channels as usize],
+            );
+            parts.push(Box::new(gap));
         }
+        parts.push(Box::new(source));
     }
-    vec![]
+    if parts.is_empty() {
+        return None
+    }
+    Some(Box::new(source::from_iter(parts)))
 }
 
-/// Check if the file at the give path is a valid sound source.
-/// Otherwise, return a None. 
-fn get_source(path: &Path) -> Option<rodio::decoder::Decoder<std::fs::File>> {
-    let f = match fs::File::open(path) {
-        Ok(f) => f,
-        Err(e) => {
-            warn!("Path {} is invalid: {}", path.display(), e);
-            warn!("Will ignore this source.");
-            return None
-        }
+/// Get the (source, volume, balance) to play for a SoundFile.
+/// Playlists play one of their entries, chosen at random.
+/// The volume includes the file's loudness normalization gain.
+/// The pitch and volume are varied by the file's randomPitch and randomVolume.
+/// Returns None if the file can't be played.
+fn get_soundfile(soundfile: &SoundFile, rng: &mut StdRng, normalizer: &Normalizer, sample_cache: &SampleCache)
+    -> Option<(Box<dyn Source<Item=i16> + Send>, f32, f32)>
+{
+    let volume = soundfile.volume * soundfile.pick_gain(rng);
+    let pitch = soundfile.pick_pitch(rng);
+    let balance = soundfile.pick_balance(rng);
+    let (source, path) = match soundfile.r#type {
+        SoundFileType::IsPath(ref path) => (sample_cache.get_source(path)?, path),
+        SoundFileType::IsPlaylist(ref entries) => entries.choose(rng)
+            .and_then(|entry| sample_cache.get_source(&entry.path).map(|source| (source, &entry.path)))?,
     };
-    let source = Decoder::new(f);
-    match source {
-        Ok(source) => {
-            Some(source)
-        },
-        Err(e) => {
-            warn!("Failed to assert {}: {}", path.display(), e);
-            warn!("Will ignore this source.");
-            None
+    let source: Box<dyn Source<Item=i16> + Send> = if pitch == 1.0 {
+        source
+    } else {
+        Box::new(source.speed(pitch))
+    };
+    Some((source, volume * normalizer.gain(path), balance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    /// Write 100ms of a constant mono signal.
+    fn write_constant(path: &Path, value: f32) {
+        let mut writer = AudioFileWriter::create(path, 1, 44100).unwrap();
+        writer.write_samples(&[value; 4410]).unwrap();
+        writer.finish().unwrap();
+    }
+
+    /// The steps of the first sound's sequence, in a soundpack with these sounds.
+    /// "a.wav" plays 0.5, and "b.wav" plays 0.25.
+    fn sequence(dir: &Path, sounds: &str) -> Vec<SequenceStep> {
+        write_constant(&dir.join("a.wav"), 0.5);
+        write_constant(&dir.join("b.wav"), 0.25);
+        let path = dir.join("pack.xml");
+        fs::write(&path, format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<sounds defaultAnsiFormat="%s">
+{}
+</sounds>
+"#, sounds)).unwrap();
+        let pack = SoundpackDef::from_file(&path).unwrap();
+        pack.sounds[0].build(dir).unwrap().sequence
+    }
+
+    /// Play the sequence, returning the left side as runs of (value, length in miliseconds).
+    fn play(steps: &[SequenceStep], seed: u64) -> Vec<(f32, usize)> {
+        let source = match chain_sequence(
+            steps,
+            &mut StdRng::seed_from_u64(seed),
+            &Variables::default(),
+            &Normalizer::new(None),
+            &SampleCache::new(0),
+        ) {
+            Some(source) => source,
+            None => return vec![],
+        };
+        assert_eq!(source.channels(), 2);
+        let mut runs: Vec<(f32, usize)> = Vec::new();
+        for sample in source.step_by(2) {
+            let value = (sample * 100.0).round() / 100.0;
+            match runs.last_mut() {
+                Some((last, len)) if *last == value => *len += 1,
+                _ => runs.push((value, 1)),
+            }
         }
+        runs.into_iter().map(|(value, len)| (value, len * 1000 / 44100)).collect()
+    }
+
+    #[test]
+    fn gaps_and_delays() {
+        let dir = test_dir("sequence-gaps");
+        let steps = sequence(&dir, r#"<sound logPattern="^Horn">
+    <sequence>
+        <soundFile fileName="a.wav"/>
+        <step gap="50"><soundFile fileName="b.wav"/></step>
+        <step gap="20"><soundFile fileName="a.wav" delay="30"/></step>
+        <soundFile fileName="b.wav" delay="200"/>
+    </sequence>
+</sound>"#);
+        assert_eq!(play(&steps, 0), vec![(0.5, 100), (0.0, 50), (0.25, 100), (0.0, 50), (0.5, 100), (0.0, 200), (0.25, 100)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn step_probability() {
+        let dir = test_dir("sequence-probability");
+        let steps = sequence(&dir, r#"<sound logPattern="^Horn">
+    <sequence>
+        <step probability="0"><soundFile fileName="a.wav"/></step>
+        <step gap="50" probability="50"><soundFile fileName="b.wav"/></step>
+        <step probability="100"><soundFile fileName="a.wav"/></step>
+    </sequence>
+</sound>"#);
+        let mut played = 0;
+        for seed in 0..100 {
+            let runs = play(&steps, seed);
+            // A skipped step's gap is skipped too.
+            if runs.len() == 1 {
+                assert_eq!(runs, vec![(0.5, 100)]);
+            } else {
+                assert_eq!(runs, vec![(0.0, 50), (0.25, 100), (0.5, 100)]);
+                played += 1;
+            }
+        }
+        assert!(played > 25 && played < 75, "{} of 100", played);
+
+        // Nothing to play.
+        let steps = sequence(&dir, r#"<sound logPattern="^Horn">
+    <sequence>
+        <step probability="0"><soundFile fileName="a.wav"/></step>
+    </sequence>
+</sound>"#);
+        assert!(play(&steps, 0).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -15,46 +15,65 @@ pub struct LoopPlayer {
     paused: Arc<AtomicBool>,
     /// Whether current playing sound should be skipped.
     skipped: Arc<AtomicBool>,
+    /// Whether the current file should stop repeating its loop section.
+    released: Arc<AtomicBool>,
     /// LoopPlayer's volume.
-    /// This is different from local_volume. This is for dynamic volume changes.
+    /// This is different from the channel's volume, applied by its bus. This is for dynamic volume changes.
     volume: VolumeLock,
-    /// Channel's volume.
-    local_volume: VolumeLock,
-    /// Total volume (SoundManager's volume).
-    total_volume: VolumeLock,
-    /// Channel's is_paused.
-    local_is_paused: IsPausedLock,
-    /// Total is_paused (SoundManager's is_paused).
-    total_is_paused: IsPausedLock,
     /// Option for Receiver that checks if the current source has finished playing.
     sleep_until_end: Option<Receiver<()>>,
+    /// Option for Receiver that checks if the outro of a stopped loop has finished playing.
+    ending: Option<Receiver<()>>,
+    /// Whether the current file's intro should play before it.
+    play_intro: bool,
+    /// Gain, pitch and balance picked for the current file, reused by its outro.
+    current_mix: (f32, f32, f32),
     /// SoundFile deque.
     /// Whenever a source finishes playing, the first file will play, then the deque rotates.
     files: VecDeque<SoundFile>,
+    /// Position of the current entry, if the first file is a playlist.
+    playlist_pos: usize,
+    /// Name of the sound currently playing.
+    now_playing: Option<Box<str>>,
+    /// Whether now_playing changed since it was last polled.
+    now_playing_changed: bool,
+    /// Whether none of the files were available, and the loop waits until one is.
+    waiting: bool,
+    /// Fades the current queue in and out.
+    fade: FadeControl,
+    /// Fades of the previous queues, still fading out after a crossfade.
+    fading_out: Vec<FadeControl>,
+    /// Length of the fades when pausing, skipping or stopping, in miliseconds.
+    pub fade_length: usize,
+    /// Gives the loudness normalization gain of each file.
+    normalizer: Normalizer,
 }
 impl LoopPlayer {
     #[inline]
-    pub fn new(
-        device: &Device,
-        local_volume: VolumeLock,
-        total_volume: VolumeLock,
-        local_is_paused: IsPausedLock,
-        total_is_paused: IsPausedLock,
-    ) -> Self {
+    pub fn new(bus: &ChannelBus, normalizer: Normalizer) -> Self {
         let (queue_tx, queue_rx) = queue::queue(true);
-        play_raw(device, queue_rx);
+        let fade = FadeControl::new(1.0);
+        bus.add_voice(Fade::new(queue_rx, fade.clone()));
         Self {
             queue_tx,
-            local_volume,
-            total_volume,
-            local_is_paused,
-            total_is_paused,
             stopped: Arc::new(AtomicBool::new(false)),
             paused: Arc::new(AtomicBool::new(false)),
             skipped: Arc::new(AtomicBool::new(false)),
+            released: Arc::new(AtomicBool::new(false)),
             volume: VolumeLock::new(),
             sleep_until_end: None,
+            ending: None,
+            play_intro: false,
+            current_mix: (1.0, 1.0, 0.0),
             files: VecDeque::new(),
+            playlist_pos: 0,
+            now_playing: None,
+            now_playing_changed: false,
+            waiting: false,
+            fade,
+            fading_out: Vec::new(),
+            fade_length: DEFAULT_FADE_LENGTH,
+            normalizer,
         }
     }
 
@@ -75,8 +94,49 @@ impl LoopPlayer {
     }
 
     #[inline]
-    pub fn stop(&self) {
+    pub fn stop(&mut self) {
         self.stopped.store(true, Ordering::SeqCst);
+        self.cut_fading_out();
+        self.set_now_playing(None);
+    }
+
+    /// Stop the loop, playing the current file's outro if it has one.
+    /// A file with loop points finishes its current pass first. Other files fade out.
+    pub fn end(&mut self) {
+        let outro = match self.files.front() {
+            Some(file) if !self.is_stopped()
+                && !self.is_paused()
+                && !self.waiting
+                && self.sleep_until_end.is_some() => file.outro.clone(),
+            _ => None,
+        };
+        let outro = match outro {
+            Some(outro) => outro,
+            None => return self.stop(),
+        };
+        let file = self.files.front().unwrap();
+        if file.has_loop_points() && matches!(file.r#type, SoundFileType::IsPath(_)) {
+            self.released.store(true, Ordering::SeqCst);
+        } else {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+        let source = match decode(&outro) {
+            Some(source) => source,
+            None => return self.stop(),
+        };
+        trace!("Playing outro {}", outro.display());
+        // The outro gets its own controls, so stopping the loop doesn't stop it.
+        self.stopped = Arc::new(AtomicBool::new(false));
+        self.paused = Arc::new(AtomicBool::new(false));
+        let (gain, pitch, balance) = self.current_mix;
+        let volume = gain * self.normalizer.gain(&outro);
+        self.append_source(source.speed(pitch), volume, balance);
+        self.ending = self.sleep_until_end.take();
+        // The channel pauses stopped loops, which mustn't pause the outro.
+        self.stopped = Arc::new(AtomicBool::new(true));
+        self.paused = Arc::new(AtomicBool::new(false));
+        self.cut_fading_out();
+        self.set_now_playing(None);
     }
 
     #[inline]
@@ -85,8 +145,23 @@ impl LoopPlayer {
     }
 
     #[inline]
-    pub fn skip(&self) {
+    pub fn skip(&mut self) {
         self.skipped.store(true, Ordering::SeqCst);
+        self.cut_fading_out();
+    }
+
+    /// Stop the loop for good, fading out its queue so it ends.
+    pub fn finish(&mut self) {
+        self.stop();
+        self.fade.fade_out(self.fade_length);
+    }
+
+    /// End the previous loops that are still fading out.
+    #[inline]
+    fn cut_fading_out(&mut self) {
+        for fade in self.fading_out.drain(..) {
+            fade.fade_out(self.fade_length);
+        }
     }
 
     #[inline]
@@ -94,77 +169,215 @@ impl LoopPlayer {
         self.volume.set(volume);
     }
 
-    #[allow(dead_code)]
     #[inline]
     pub fn get_volume(&self) -> f32 {
         self.volume.get()
     }
 
     /// Number of sources currently playing. Will always be 0 or 1.
+    /// An outro still playing counts.
     #[inline]
     pub fn len(&self) -> usize {
-        !(self.is_paused() || self.is_stopped() || self.files.is_empty()) as usize
+        (self.ending.is_some()
+            || !(self.is_paused() || self.is_stopped() || self.waiting || self.files.is_empty())) as usize
     }
 
     /// Change the loop.
     /// Replaces the current set of files with another one.
+    /// If `crossfade` isn't 0 and a loop is playing, it fades out over that many miliseconds
+    /// while the new one fades in. Otherwise the current loop is cut.
     pub fn change_loop(
         &mut self,
-        device: &Device,
+        bus: &ChannelBus,
         files: &[SoundFile],
-        rng: &mut ThreadRng
+        crossfade: usize,
+        rng: &mut StdRng,
+        variables: &Variables,
     ) {
-        self.stop();
+        self.fading_out.retain(|fade| !fade.is_finished());
+        let crossfading = crossfade > 0
+            && !self.is_stopped()
+            && self.sleep_until_end.is_some();
+        if crossfading {
+            // If this loop was still fading in, it fades out from where it is.
+            trace!("Crossfading loops over {}ms", crossfade);
+            self.fade.fade_out(crossfade);
+            self.fading_out.push(self.fade.clone());
+        }
+        else {
+            self.finish();
+        }
         self.files = files.iter().cloned().collect();
+        self.playlist_pos = 0;
+        self.play_intro = true;
         let (front, back) = self.files.as_mut_slices();
         front.shuffle(rng); back.shuffle(rng);
+        let fade = if crossfading {
+            let fade = FadeControl::new(0.0);
+            fade.fade_to(1.0, crossfade);
+            fade
+        } else {
+            FadeControl::new(1.0)
+        };
+        self.open_queue(bus, fade);
+        self.append_file(rng, variables);
+    }
+
+    /// Move the loop to another bus.
+    /// The current file or playlist entry starts over on the new bus.
+    pub fn change_bus(&mut self, bus: &ChannelBus, rng: &mut StdRng, variables: &Variables) {
+        let stopped = self.is_stopped();
+        let paused = self.is_paused();
+        self.finish();
+        self.open_queue(bus, FadeControl::new(1.0));
+        self.sleep_until_end = None;
+        if stopped {
+            self.stop();
+        }
+        else if !self.files.is_empty() {
+            self.append_file(rng, variables);
+            if paused {
+                self.pause();
+            }
+        }
+    }
+
+    /// Play a new queue on the channel's bus, with new controls.
+    fn open_queue(&mut self, bus: &ChannelBus, fade: FadeControl) {
         let (queue_tx, queue_rx) = queue::queue(true);
-        play_raw(device, queue_rx);
+        self.fade = fade;
+        bus.add_voice(Fade::new(queue_rx, self.fade.clone()));
         let volume = self.volume.get();
         self.stopped = Arc::new(AtomicBool::new(false));
         self.paused = Arc::new(AtomicBool::new(false));
+        self.skipped = Arc::new(AtomicBool::new(false));
         self.volume = VolumeLock::new();
         self.volume.set(volume);
         self.queue_tx = queue_tx;
-        self.append_file(rng);
+        self.ending = None;
     }
 
-    /// Gets sound source(s) from the first file path, and append to the SourceQueue.
-    fn append_file(&mut self, rng: &mut ThreadRng) {
-        let file = self.files.front_mut().unwrap();
-        let files = match &file.r#type {
-            SoundFileType::IsPath(path) => vec![path.clone()],
-            SoundFileType::IsPlaylist(paths) => paths.to_vec(),
+    /// Gets a sound source from the current file or playlist entry, and append it to the SourceQueue.
+    /// If it can't be played, or isn't available right now, move on to the next one.
+    fn append_file(&mut self, rng: &mut StdRng, variables: &Variables) {
+        let entry_count: usize = self.files.iter()
+            .map(|file| match &file.r#type {
+                SoundFileType::IsPath(_) => 1,
+                SoundFileType::IsPlaylist(entries) => entries.len(),
+            })
+            .sum();
+        let mut all_unavailable = true;
+        self.waiting = false;
+        for _ in 0..entry_count {
+            if !self.files.front().unwrap().is_available(variables) {
+                self.playlist_pos = 0;
+                self.files.rotate_left(1);
+                self.play_intro |= self.files.len() > 1;
+                continue;
+            }
+            all_unavailable = false;
+            if self.append_current(rng) {
+                return
+            }
+            self.advance();
+        }
+        if entry_count > 0 {
+            if all_unavailable {
+                trace!("None of the loop's files are available, waiting.");
+                self.waiting = true;
+            } else {
+                warn!("None of the loop's files can be played.");
+            }
+        }
+        self.sleep_until_end = None;
+        self.set_now_playing(None);
+    }
+
+    /// Try to append the current file or playlist entry.
+    fn append_current(&mut self, rng: &mut StdRng) -> bool {
+        let file = self.files.front().unwrap();
+        let (path, name) = match &file.r#type {
+            SoundFileType::IsPath(path) => (path.clone(), display_name(path)),
+            SoundFileType::IsPlaylist(entries) => match entries.get(self.playlist_pos) {
+                Some(entry) => (entry.path.clone(), entry.display_name()),
+                None => return false,
+            }
         };
-        let volume = file.volume;
-        let balance = if file.random_balance {
-            None
+        let gain = file.volume * file.pick_gain(rng);
+        let pitch = file.pick_pitch(rng);
+        let balance = file.pick_balance(rng);
+        // Loop points only apply to single files. Playlist entries play through.
+        let loop_points = match &file.r#type {
+            SoundFileType::IsPath(_) if file.has_loop_points() => Some((file.loop_start, file.loop_end)),
+            _ => None,
+        };
+        let intro = if self.play_intro && self.playlist_pos == 0 {
+            file.intro.clone()
         } else {
-            Some(file.balance)
+            None
         };
-        for path in files.iter() {
-            let f = match fs::File::open(path) {
-                Ok(f) => f,
-                Err(e) => {
-                    warn!("Failed to open file {}: {}", path.display(), e);
-                    warn!("Will ignore this file.");
-                    continue
-                }
-            };
-            match Decoder::new(f) {
-                Ok(source) => {
-                    let balance = balance.unwrap_or_else(||rng.gen_range(-1.0, 1.0));
-                    self.append_source(source, volume, balance)
-                }
-                Err(e) => {
-                    warn!("Error while decoding {}: {}", path.display(), e);
-                    warn!("Will ignore this source.");
-                }
+        let source = match decode(&path) {
+            Some(source) => source,
+            None => return false,
+        };
+        self.play_intro = false;
+        if let Some(intro) = intro {
+            if let Some(intro_source) = decode(&intro) {
+                trace!("Playing intro {}", intro.display());
+                let volume = gain * self.normalizer.gain(&intro);
+                self.append_source(intro_source.speed(pitch), volume, balance);
             }
         }
+        let source: Box<dyn Source<Item=i16> + Send> = match loop_points {
+            Some((loop_start, loop_end)) => {
+                self.released = Arc::new(AtomicBool::new(false));
+                let reopen_path = path.clone();
+                let reopen: Reopen<Decoder<fs::File>> = Arc::new(move || decode(&reopen_path));
+                Box::new(LoopSection::new(source, loop_start, loop_end, self.released.clone(), reopen))
+            }
+            None => Box::new(source),
+        };
+        self.current_mix = (gain, pitch, balance);
+        self.append_source(source.speed(pitch), gain * self.normalizer.gain(&path), balance);
+        self.set_now_playing(Some(name.into_boxed_str()));
+        true
+    }
+
+    /// Move to the next playlist entry. At the end of a playlist, or for single files, rotate the files deque.
+    fn advance(&mut self) {
+        let entry_count = match self.files.front().map(|file| &file.r#type) {
+            Some(SoundFileType::IsPlaylist(entries)) => entries.len(),
+            _ => 1,
+        };
+        self.playlist_pos += 1;
+        if self.playlist_pos >= entry_count {
+            self.playlist_pos = 0;
+            self.files.rotate_left(1);
+            self.play_intro |= self.files.len() > 1;
+        }
+    }
+
+    #[inline]
+    fn set_now_playing(&mut self, name: Option<Box<str>>) {
+        if self.now_playing != name {
+            self.now_playing = name;
+            self.now_playing_changed = true;
+        }
+    }
+
+    /// Get the name of the current sound, if it changed since the last call.
+    /// An empty name means nothing is playing.
+    pub fn poll_now_playing(&mut self) -> Option<Box<str>> {
+        if self.now_playing_changed {
+            self.now_playing_changed = false;
+            Some(self.now_playing.clone().unwrap_or_default())
+        } else {
+            None
+        }
     }
 
     /// Wraps the source with the appropriate control wrappers, then adds it to the queue.
+    /// The channel's volume and pause are applied by its bus, not here.
     fn append_source<S>(&mut self, source: S, source_volume: f32, balance: f32)
     where
         S: Source + Send + 'static,
@@ -174,14 +387,7 @@ impl LoopPlayer {
         let paused = self.paused.clone();
         let skipped = self.skipped.clone();
         let volume = self.volume.clone();
-        let local_volume = self.local_volume.clone();
-        let total_volume = self.total_volume.clone();
-        let local_is_paused = self.local_is_paused.clone();
-        let total_is_paused = self.total_is_paused.clone();
-        let source = source
-            .pausable(false)
-            .amplify(1.0)
-            .stoppable()
+        let source = Ramp::new(source, self.fade_length)
             .periodic_access(Duration::from_millis(5),
                 move |src| {
                     if stopped.load(Ordering::Relaxed)
@@ -189,63 +395,73 @@ impl LoopPlayer {
                         src.stop();
                     }
                     else {
-                        src.inner_mut()
-                            .set_factor(
-                                source_volume
-                                * volume.get()
-                                * local_volume.get()
-                                * total_volume.get()
-                            );
-                        src.inner_mut()
-                            .inner_mut()
-                            .set_paused(
-                                paused.load(Ordering::Relaxed)
-                                || local_is_paused.get()
-                                || total_is_paused.get()
-                            );
+                        src.set_factor(source_volume * volume.get());
+                        src.set_paused(paused.load(Ordering::Relaxed));
                     }
                 }
             ).convert_samples::<f32>();
-        // If balance is equal, just append it to queue.
-        if balance == 0.0 {
-            self.sleep_until_end = Some(self.queue_tx.append_with_signal(source));
-        }
-        // If not, add a Spatial wrapper around the source, then append it to queue.
-        else {
-            let source = source.buffered();
-            let source = Spatial::new(
-                source,
-                [balance, 1.0, 0.0],
-                [-1.0, 0.0, 0.0],
-                [1.0, 0.0, 0.0],
-            );
-            self.sleep_until_end = Some(self.queue_tx.append_with_signal(source));
-        }
+        let source = Panner::new(source, balance);
+        self.sleep_until_end = Some(self.queue_tx.append_with_signal(source));
     }
 
     /// Maintain the loop.
-    pub fn maintain(&mut self, rng: &mut ThreadRng) {
+    pub fn maintain(&mut self, rng: &mut StdRng, variables: &Variables) {
         use std::sync::mpsc::TryRecvError;
+        if let Some(outro_end_receiver) = &self.ending {
+            if !matches!(outro_end_receiver.try_recv(), Err(TryRecvError::Empty)) {
+                trace!("Outro finished.");
+                self.ending = None;
+            }
+        }
         if self.stopped.load(Ordering::Relaxed) {return}
+        if self.waiting {
+            if self.files.iter().any(|file| file.is_available(variables)) {
+                self.append_file(rng, variables);
+            }
+            return
+        }
         if let Some(song_end_receiver) = &mut self.sleep_until_end {
             match song_end_receiver.try_recv() {
-                Ok(_) => self.on_source_end(rng),
+                Ok(_) => self.on_source_end(rng, variables),
                 Err(TryRecvError::Empty) => (),
-                Err(TryRecvError::Disconnected) =>
-                    panic!("TryRecvError::Disconnected on LoopPlayer maintain!"),
+                // The queue was dropped with the mixing graph, when its output device failed.
+                // The loop waits for the channel to move to a new bus, which opens a new queue.
+                Err(TryRecvError::Disconnected) => {
+                    trace!("Loop queue disconnected, waiting for a new bus.");
+                    self.sleep_until_end = None;
+                }
             }
         }
     }
 
     /// Triggerd when the current source ends.
-    /// If there are no more sources in queue, rotated the files deque, and appends the first file.
-    fn on_source_end(&mut self, rng: &mut ThreadRng) {
+    /// Moves on to the next playlist entry or file, and appends it.
+    fn on_source_end(&mut self, rng: &mut StdRng, variables: &Variables) {
         trace!("Song finished.");
         if !self.files.is_empty() && !self.stopped.load(Ordering::Relaxed)
         {
             trace!("  Playing next song.");
-            self.files.rotate_left(1);
-            self.append_file(rng);
+            self.advance();
+            self.append_file(rng, variables);
         }
     }
-}
\ No newline at end of file
+}
+/// Open and decode a file, warning if it can't be.
+fn decode(path: &Path) -> Option<Decoder<fs::File>> {
+    let f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            warn!("Failed to open file {}: {}", path.display(), e);
+            warn!("Will ignore this file.");
+            return None
+        }
+    };
+    match Decoder::new(f) {
+        Ok(source) => Some(source),
+        Err(e) => {
+            warn!("Error while decoding {}: {}", path.display(), e);
+            warn!("Will ignore this source.");
+            None
+        }
+    }
+}
@@ -2,47 +2,29 @@
 
 use super::*;
 
-/// Struct responsible for one playing oneshot source.
-struct Control {
-    /// This volume is independent from Channel's local_volume and SoundManager's total_volume. 
-    volume: VolumeLock,
-    /// Whether the source is stopped.
-    stopped: AtomicBool,
-    /// Marker to check whether the sound has stopped playing.
-    count: Arc<AtomicUsize>,
-}
-
 /// Struct responsible of playing oneshot sounds.
 pub struct OneshotPlayer {
     /// Whether the oneshot player is paused.
     paused: Arc<AtomicBool>,
     /// Vector of controls, each responsible for a different source.
-    controls: Vec<Arc<Control>>,
-    /// Channel's local_volume.
-    local_volume: VolumeLock,
-    /// SoundManager's total_volume.
-    total_volume: VolumeLock,
-    /// Channel's is_paused.
-    local_is_paused: IsPausedLock,
-    /// SoundManager's is_paused
-    total_is_paused: IsPausedLock,
+    controls: Vec<Arc<Voice>>,
+    /// Counts the voices of every channel, and steals them when there are too many.
+    voices: VoicePool,
+    /// Volume of the channel, telling how loud its voices are.
+    channel_volume: VolumeLock,
+    /// Length of the fades when pausing or stopping sources, in miliseconds.
+    pub fade_length: usize,
 }
 
 impl OneshotPlayer {
     #[inline]
-    pub fn new(
-        local_volume: VolumeLock,
-        total_volume: VolumeLock,
-        local_is_paused: IsPausedLock,
-        total_is_paused: IsPausedLock,
-    ) -> Self {
+    pub fn new(voices: VoicePool, channel_volume: VolumeLock) -> Self {
         Self {
             paused: Arc::new(AtomicBool::new(false)),
             controls: vec![],
-            local_volume,
-            total_volume,
-            local_is_paused,
-            total_is_paused,
+            voices,
+            channel_volume,
+            fade_length: DEFAULT_FADE_LENGTH,
         }
     }
 
@@ -61,7 +43,7 @@ impl OneshotPlayer {
         self.paused.load(Ordering::Relaxed)
     }
 
-    /// Make all playing sources stop. 
+    /// Make all playing sources fade out and stop. 
     #[inline]
     pub fn stop(&self) {
         for control in self.controls.iter() {
@@ -69,6 +51,19 @@ impl OneshotPlayer {
         }
     }
 
+    /// Highest priority of the playing sources, if any.
+    #[inline]
+    pub fn max_priority(&self) -> Option<i32> {
+        self.controls.iter().map(|c| c.priority()).max()
+    }
+
+    /// Make room for a source of this priority in the voice pool.
+    /// Returns false if it can't be played.
+    #[inline]
+    pub fn make_room(&self, priority: i32) -> bool {
+        self.voices.make_room(priority)
+    }
+
     #[inline]
     pub fn len(&self) -> usize {
         self.controls.len()
@@ -89,84 +84,46 @@ impl OneshotPlayer {
         self.controls[idx].volume.set(volume);
     }
 
-    /// Add a oneshot source.
-    /// Generate a control for the source.
-    /// Wraps the source in appropriate control wraps plays it.
+    /// Add a oneshot source, of a sound with this priority.
+    /// Generate a control for the source, counted as a voice, and as one of the sound's `instances`.
+    /// Wraps the source in appropriate control wraps, then plays it on the channel's bus.
     pub fn add_source<S>(
         &mut self,
-        device: &Device,
+        bus: &ChannelBus,
         source: S,
         source_volume: f32,
-        balance: f32
+        balance: f32,
+        priority: i32,
+        instances: &Instances,
     )
     where
         S: Source + Send + 'static,
         S::Item: Sample + Send
     {
-        let count = Arc::new(AtomicUsize::new(1));
-        let control = Arc::new(
-            Control {
-                volume: VolumeLock::new(),
-                stopped: AtomicBool::new(false),
-                count,
-            }
-        );
+        let control = self.voices.add_voice(priority, source_volume, self.channel_volume.clone());
+        instances.add(control.clone());
         let paused = self.paused.clone();
-        let local_volume = self.local_volume.clone();
-        let total_volume = self.total_volume.clone();
-        let local_is_paused = self.local_is_paused.clone();
-        let total_is_paused = self.total_is_paused.clone();
         let control_a = control.clone();
         let control_b = control.clone();
-        let source = source
-            .pausable(false)
-            .amplify(1.0)
-            .stoppable()
+        let source = Ramp::new(source, self.fade_length)
             .periodic_access(Duration::from_millis(5),
                 move |src| {
                     if control_a.stopped.load(Ordering::Relaxed) {
                         src.stop();
                     }
                     else {
-                        src.inner_mut()
-                            .set_factor(
-                                source_volume
-                                * control_a.volume.get()
-                                * local_volume.get()
-                                * total_volume.get()
-                                * if local_is_paused.get() {0.0} else {1.0}
-                                * if total_is_paused.get() {0.0} else {1.0}
-                            );
-                        src.inner_mut()
-                            .inner_mut()
-                            .set_paused(
-                                paused.load(Ordering::Relaxed)
-                            );
+                        src.set_factor(source_volume * control_a.volume.get());
+                        src.set_paused(paused.load(Ordering::Relaxed));
                     }
                 }
             ).convert_samples::<f32>();
         let source = source::Done::new(source, control_b.count.clone());
-        if balance == 0.0 {
-            play_raw(device, source);
-        }
-        else {
-            let source = source.buffered();
-            let source = Spatial::new(
-                source,
-                [balance, 1.0, 0.0],
-                [-1.0, 0.0, 0.0],
-                [1.0, 0.0, 0.0],
-            );
-            play_raw(device, source);
-        }
+        bus.add_voice(Panner::new(source, balance));
         self.controls.push(control);
     }
 
     /// Remove all controls if stopped, or if the source has finished playing.
     pub fn maintain(&mut self) {
-        self.controls.retain(|c| 
-            !c.stopped.load(Ordering::Relaxed)
-            && c.count.load(Ordering::Relaxed) == 1
-        );
+        self.controls.retain(|c| c.is_playing());
     }
 }
\ No newline at end of file
@@ -0,0 +1,125 @@
+use super::*;
+
+/// Playbacks due within this many miliseconds are started right away,
+/// preceded by silence until their exact time. The same as the sound thread's loop.
+pub const SCHEDULER_LOOKAHEAD: usize = 10;
+
+/// Something a channel plays, or stops, later.
+pub enum Playback {
+    /// A oneshot, or a sequence chained into one source, with its volume, balance, priority,
+    /// and the instance of the sound it counts as.
+    Oneshot(BoxedSource, f32, f32, i32, ScheduledInstance),
+    /// Start a loop with these files, and this crossfade length if given.
+    LoopStart(Vec<SoundFile>, Option<usize>),
+    /// Stop the loop.
+    LoopStop,
+}
+
+struct Scheduled {
+    /// Time until the playback is due, in miliseconds.
+    remaining: usize,
+    /// Whether it was scheduled since the last tick.
+    /// The time of that tick passed before it was scheduled, so it isn't counted.
+    fresh: bool,
+    playback: Playback,
+}
+
+/// Queues a channel's playbacks until their time comes.
+/// Delayed sounds wait here, without affecting the sounds already playing.
+pub struct Scheduler {
+    entries: Vec<Scheduled>,
+}
+
+impl Scheduler {
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Play `playback` in `delay` miliseconds.
+    pub fn schedule(&mut self, delay: usize, playback: Playback) {
+        self.entries.push(Scheduled {
+            remaining: delay,
+            fresh: true,
+            playback,
+        });
+    }
+
+    /// Move forward by `dt` miliseconds.
+    /// Returns the playbacks due within SCHEDULER_LOOKAHEAD, in order, with the time left until each is due.
+    pub fn advance(&mut self, dt: usize) -> Vec<(usize, Playback)> {
+        let mut due = Vec::new();
+        let mut idx = 0;
+        while idx < self.entries.len() {
+            let entry = &mut self.entries[idx];
+            if !entry.fresh {
+                entry.remaining = entry.remaining.saturating_sub(dt);
+            } else if dt > 0 {
+                entry.fresh = false;
+            }
+            if entry.remaining <= SCHEDULER_LOOKAHEAD {
+                let entry = self.entries.remove(idx);
+                due.push((entry.remaining, entry.playback));
+            } else {
+                idx += 1;
+            }
+        }
+        // Stable, so playbacks due at the same time keep the order they were scheduled in.
+        due.sort_by_key(|(remaining, _)| *remaining);
+        due
+    }
+
+    /// Number of oneshots waiting for their time.
+    pub fn oneshots(&self) -> usize {
+        self.entries.iter()
+            .filter(|entry| matches!(entry.playback, Playback::Oneshot(..)))
+            .count()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget every scheduled playback.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oneshot(instances: &Instances) -> Playback {
+        Playback::Oneshot(Box::new(source::Empty::new()), 1.0, 0.0, 0, instances.schedule())
+    }
+
+    #[test]
+    fn counts_waiting_oneshots() {
+        let instances = Instances::default();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(100, oneshot(&instances));
+        scheduler.schedule(500, oneshot(&instances));
+        scheduler.schedule(100, Playback::LoopStop);
+        assert_eq!(scheduler.oneshots(), 2);
+        assert_eq!(instances.count(), 2);
+        // The first tick after scheduling doesn't count.
+        assert!(scheduler.advance(10).is_empty());
+        let due = scheduler.advance(90);
+        assert_eq!(due.len(), 2);
+        assert!(due.iter().all(|(remaining, _)| *remaining == 10));
+        assert_eq!(scheduler.oneshots(), 1);
+        // Due playbacks still count until they're played or dropped.
+        assert_eq!(instances.count(), 2);
+        drop(due);
+        assert_eq!(instances.count(), 1);
+        scheduler.clear();
+        assert_eq!(scheduler.oneshots(), 0);
+        assert_eq!(instances.count(), 0);
+        assert!(scheduler.is_empty());
+    }
+}
@@ -1,5 +1,4 @@
 use super::*;
-use std::collections::HashMap;
 
 /// The struct that parses the log entries.
 /// Plays appropriate sounds on appropriate channels;
@@ -14,8 +13,16 @@ pub struct SoundManager {
     previous_log: String,
     /// The patterns that SoundManager shouldn't process.
     ignore_list: Vec<Regex>,
-    /// The sound device of the system.
-    device: Device,
+    /// Mixes the channels, and plays them on the audio output device.
+    master: MasterBus,
+    /// Where the master bus, and channels routed to their own device, are played.
+    backend: AudioBackend,
+    /// Name of the output device chosen by the user. None is the default device.
+    device: Option<Box<str>>,
+    /// Output devices found by the last check.
+    device_names: Vec<Box<str>>,
+    /// Time since the output devices were last checked, in miliseconds.
+    device_timer: usize,
     /// All the channels, sorted alphabetically.
     channels: BTreeMap<Box<str>, SoundChannel>,
     /// The total volume.
@@ -27,318 +34,137 @@ pub struct SoundManager {
     /// Sender for UIMessage sent to the UI.
     ui_sender: Sender<UIMessage>,
     /// RNG for probability and randomly choosing a soundfile from many.
-    rng: ThreadRng,
+    rng: StdRng,
+    /// Soundpack-declared variables, used by conditions.
+    variables: Variables,
+    /// Season, year, game mode and siege, tracked from the gamelog.
+    context: GameContext,
+    /// Loudness normalization of the soundpack's files.
+    normalizer: Normalizer,
+    /// Decoded short sounds.
+    sample_cache: SampleCache,
+    /// Time since the cache statistics were last sent to the UI, in miliseconds.
+    stats_timer: usize,
+    /// Time since the limiter's gain reduction was last sent to the UI, in miliseconds.
+    meter_timer: usize,
+    /// Gain reduction of the master limiter last sent to the UI, in tenths of decibels.
+    last_reduction: Option<u32>,
+    /// Output devices the user routed channels to, overriding the soundpack's.
+    /// Kept for every channel ever routed, so routings of other soundpacks are saved too.
+    channel_devices: HashMap<Box<str>, Option<Box<str>>>,
 }
 
 impl SoundManager {
-    /// Create a new manager.
+    /// Create a new manager, playing on the output device chosen in the options.
     /// A new manager is created every time the user reloads a soundpack.
+	pub fn new(sound_dir: &Path, ui_sender: Sender<UIMessage>, options: &SoundOptions) -> Result<Self> {
+        let master = open_master(options);
+        let mut manager = Self::with_master(sound_dir, ui_sender, options, master)?;
+        manager.load_user_config()?;
+        Ok(manager)
+    }
+
+    /// Create a new manager, mixing everything into the given master bus.
+    /// The user's default volumes and channel devices aren't applied.
     #[allow(clippy::cognitive_complexity)]
-	pub fn new(sound_dir: &Path, ui_sender: Sender<UIMessage>) -> Result<Self> {
-        let total_volume = VolumeLock::new();
-        let total_is_paused = IsPausedLock::new();
+    pub fn with_master(
+        sound_dir: &Path,
+        ui_sender: Sender<UIMessage>,
+        options: &SoundOptions,
+        master: MasterBus,
+    ) -> Result<Self> {
+        let total_volume = master.total_volume();
+        let normalizer = Normalizer::new(options.normalize);
+        let sample_cache = SampleCache::new(options.sample_cache);
+        let voices = VoicePool::new(options.max_voices);
+        let total_is_paused = master.total_is_paused();
         let mut sounds = Vec::new();
-        let mut channel_settings = None;
-        let device = default_output_device()
-            .ok_or("Failed to get default audio output device.")?;
+        let mut channel_settings = HashMap::new();
+        let mut variables = Variables::default();
+        let mut context = GameContext::new();
+        context.write_variables(&mut variables);
 		let mut channels : BTreeMap<Box<str>, SoundChannel> = BTreeMap::new();
 		channels.insert(
 			String::from("misc").into_boxed_str(),
 			SoundChannel::new(
-                &device,
+                &master,
                 "misc",
-                total_volume.clone(),
-                total_is_paused.clone()
+                normalizer.clone(),
+                sample_cache.clone(),
+                voices.clone(),
             )
 		);
 
-        /// Traverse the soundpack in DFS. Parses XML files.
-		fn visit_dir(dir: &Path, func: &mut dyn FnMut(&Path)->Result<()>) -> Result<()> {
-            trace!("Directory: {:?}", dir);
-            match fs::read_dir(dir) {
-                Ok(entries) => for entry in entries {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.is_dir() {
-                        visit_dir(&path, func)?;
-                    } else if path.is_file() && path.extension().map_or(false, |ext| ext=="xml") {
-                        func(&path)?;
+        // Parse a soundpack file, whether XML, TOML or JSON.
+        let mut func = |file_path: &Path, pack: SoundpackDef| -> Result<()> {
+            let dir = file_path.parent().unwrap();
+            for sound_def in pack.sounds.iter() {
+                let sound = sound_def.build(dir)?;
+                if let Some(channel_name) = &sound.channel {
+                    if !channels.contains_key(channel_name) {
+                        channels.insert(
+                            channel_name.clone(),
+                            SoundChannel::new(
+                                &master,
+                                channel_name,
+                                normalizer.clone(),
+                                sample_cache.clone(),
+                                voices.clone(),
+                            )
+                        );
                     }
                 }
-                Err(e) => {
-                    warn!("Error while visiting {}: {}", dir.display(), e);
-                    warn!("Will ignore this directory.");
+                sounds.push(sound);
+            }
+            for variable_def in pack.variables.iter() {
+                variables.declare(&variable_def.name, variable_def.default.unwrap_or(0));
+            }
+            for pattern_def in pack.context_patterns.iter() {
+                match ContextEvent::from_name(&pattern_def.event) {
+                    Some(event) => context.add_pattern(Regex::new(&pattern_def.log_pattern)?, event),
+                    None => {
+                        warn!("Unknown context event: {}", pattern_def.event);
+                        warn!("Will ignore this ContextPattern.");
+                    }
                 }
             }
+            for setting_def in pack.channel_settings.iter() {
+                channel_settings.insert(
+                    Box::from(setting_def.name.as_str()),
+                    setting_def.build()?,
+                );
+            }
             Ok(())
-		}
-
-        // Parse an XML file.
-        let mut func = |file_path: &Path| -> Result<()> {
-            use quick_xml::{Reader, events::Event};
-            trace!(" XML: {:?}", file_path);
-            let mut reader = Reader::from_file(file_path)?;
-            let mut current_sound : Option<SoundEntry> = None;
-            let buf = &mut Vec::new();
-            loop {
-                match reader.read_event(buf) {
-                    // <...> or <.../>
-                    Ok(Event::Start(ref data)) | Ok(Event::Empty(ref data)) => {
-                        let local_name = data.local_name();
-                        // <sound> or <sound/>
-                        if local_name == b"sound" {
-
-                            let mut pattern: Option<Regex> = None;
-                            let mut channel: Option<Box<str>> = None;
-                            let mut loop_attr: Option<bool> = None;
-                            let mut concurency: Option<usize> = None;
-                            let mut timeout: Option<usize> = None;
-                            let mut probability: Option<usize> = None;
-                            let mut delay: Option<usize> = None;
-                            let mut halt_on_match: bool = false;
-                            let mut random_balance: bool = false;
-                            #[allow(unused_mut)]
-                            let mut playback_threshold: u8 = 4;
-                            let files = Vec::new();
-                            let weights = Vec::new();
-
-                            for attr in data.attributes().with_checks(false) {
-                                let attr = attr?;
-                                // This value came from an XML file, so it must be utf8.
-                                let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
-                                match attr.key {
-                                    b"logPattern" => {
-                                        let processed = FAULTY_ESCAPE.replace_all(&attr_value, "$1");
-                                        let processed = EMPTY_EXPR.replace_all(&processed, ")?");
-                                        pattern = Some(Regex::new(&processed)?);
-                                    }
-                                    b"channel" => {
-                                        let channel_name : Box<str> = attr_value.into();
-                                        if !channels.contains_key(&channel_name) {
-                                            channels.insert(
-                                                channel_name.clone(),
-                                                SoundChannel::new(
-                                                    &device,
-                                                    &channel_name,
-                                                    total_volume.clone(),
-                                                    total_is_paused.clone(),
-                                                )
-                                            );
-                                        }
-                                        channel = Some(channel_name);
-                                    }
-                                    b"loop" => {
-                                        loop_attr.replace(attr_value == "start");
-                                    }
-                                    b"concurency" => {
-                                        concurency = Some( attr_value.parse()? );
-                                    }
-                                    b"timeout" => {
-                                        timeout = Some( attr_value.parse()? );
-                                    }
-                                    // Probability was mispelled...
-                                    b"propability" | b"probability" => {
-                                        probability = Some( attr_value.parse()? );
-                                    }
-                                    b"delay" => {
-                                        delay = Some( attr_value.parse()? );
-                                    }
-                                    b"haltOnMatch" => {
-                                        halt_on_match = attr_value == "true";
-                                    }
-                                    b"randomBalance" => {
-                                        random_balance = attr_value == "true" ;
-                                    }
-                                    b"playbackThreshhold" => {
-                                        playback_threshold = attr_value.parse()?;
-                                    }
-                                    b"ansiFormat" => (),
-                                    b"ansiPattern" => (),
-                                    _ => {
-                                        warn!(
-                                            "Unknown sound value: {}",
-                                            unsafe {std::str::from_utf8_unchecked(attr.key)}
-                                        );
-                                        warn!("Will ignore this value.");
-                                    }
-                                }
-                            }
-
-                            trace!("  SoundEntry");
-                            if let Some(pattern) = pattern {
-                                trace!("  -Pattern: {}", pattern);
-                                current_sound = Some(
-                                    SoundEntry{
-                                        pattern,
-                                        channel,
-                                        loop_attr,
-                                        concurency,
-                                        timeout,
-                                        probability,
-                                        delay,
-                                        halt_on_match,
-                                        random_balance,
-                                        playback_threshold,
-                                        files,
-                                        weights,
-                                        current_timeout: 0,
-                                        recent_call: 0,
-                                    }
-                                );
-                            }
-                            else {
-                                warn!("A SoundEntry in {:?} doesn't have a pattern!", file_path);
-                                warn!("Will ignore this SoundEntry.");
-                            }
-                        }
-                        
-                        // <soundFile> or <soundFile/>
-                        else if local_name == b"soundFile" {
-                            if current_sound.is_none() {
-                                warn!("A SoundFile in {:?} was declared outside of a valid Sound!", file_path);
-                                warn!("Will ignore this SoundFile.");
-                            }
-                            let mut path = PathBuf::from(file_path);
-                            let mut is_playlist = false;
-                            let mut weight: f32 = 100.0;
-                            let mut volume: f32 = 1.0;
-                            let mut random_balance: bool = false;
-                            let mut balance: f32 = 0.0;
-                            let mut delay: usize = 0;
-
-                            for attr in data.attributes() {
-                                let attr = attr?;
-                                let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
-                                match attr.key {
-                                    b"fileName" => path.set_file_name(attr_value),
-                                    b"weight" => {
-                                        weight = attr_value.parse()?;
-                                    }
-                                    b"volumeAdjustment" => {
-                                        // TODO: check if linear conversion from decibel to normal volume does work
-                                        volume = (attr_value.parse::<f32>()? + 40.0) / 40.0;
-                                    }
-                                    b"randomBalance" => {
-                                        random_balance = attr_value == "true";
-                                    }
-                                    b"balanceAdjustment" => {
-                                        balance = attr_value.parse()?;
-                                    }
-                                    b"delay" => {
-                                        delay = attr_value.parse()?;
-                                    }
-                                    b"playlist" => {
-                                        is_playlist = true;
-                                    }
-                                    _ => {
-                                        warn!(
-                                            "Unknown sound value: {}",
-                                            unsafe {std::str::from_utf8_unchecked(attr.key)}
-                                        );
-                                        warn!("Will ignore this value.");
-                                    }
-                                }
-                            }
-                            trace!("  --SoundFile: {:?}", path);
-                            let r#type = if is_playlist {
-                                let path_vec = parse_playlist(&path)?;
-                                SoundFileType::IsPlaylist(path_vec)
-                            } else {
-                                // test_file(&path);
-                                SoundFileType::IsPath(path)
-                            };
-                            let sound_file = SoundFile {
-                                r#type,
-                                weight,
-                                volume,
-                                random_balance,
-                                delay,
-                                balance,
-                            };
-                            let sound = current_sound.as_mut().unwrap();
-                            sound.files.push(sound_file);
-                            sound.weights.push(weight);
-                        }
-
-                        else if local_name == b"channelSettings" {
-                            trace!("  ChannelSettings");
-                            channel_settings = Some(
-                                HashMap::new()
-                            );
-                        }
-                        
-                        // <channelSetting/>
-                        else if local_name == b"channelSetting" {
-                            if channel_settings.is_none() {
-                                warn!("A ChannelSetting in {:?} was declared outside of ChannelSettings!", file_path);
-                                warn!("Will ignore this ChannelSetting.");
-                                continue;
-                            }
-                            trace!("  -ChannelSetting");
-                            let mut name: Option<Box<str>> = None;
-                            let mut play_type = ChannelPlayType::All;
-                            for attr in data.attributes() {
-                                let attr = attr?;
-                                let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
-                                match attr.key {
-                                    b"name" => {
-                                        trace!("  --name: {}", attr_value);
-                                        name.replace(Box::from(attr_value));
-                                    }
-                                    b"playType" => {
-                                        trace!("  --play_type: {}", attr_value);
-                                        match attr_value {
-                                            "singleEager" => play_type = ChannelPlayType::SingleEager,
-                                            "singleLazy" => play_type = ChannelPlayType::SingleLazy,
-                                            "all" => play_type = ChannelPlayType::All,
-                                            other => {
-                                                warn!("Unknown Channel PlayType: {}", other);
-                                                warn!("Will ignore this value.");
-                                            },
-                                        }
-                                    }
-                                    _ => ()
-                                }
-                            }
-                            if let Some(name) = name {
-                                let channel_setting = ChannelSetting {
-                                    play_type,
-                                };
-                                channel_settings.as_mut()
-                                    .unwrap()
-                                    .insert(name, channel_setting);
-                            }
-                            else {
-                                warn!("A ChannelSetting in {:?} didn't specify a channel name.", file_path);
-                                warn!("Will ignore this ChannelSetting.");
-                            }
-                        }
-                    },
-
-                    // </Sound>
-                    Ok(Event::End(data)) => {
-                        if current_sound.is_some() && data.local_name() == b"sound" {
-                            sounds.push( current_sound.take()
-                                .ok_or("Tried to finish a Sound, even though there is no Sound!")?
-                            );
-                        }
-                    },
-
-                    Ok(Event::Eof) => return Ok(()),
+        };
 
-                    Err(e) => {
-                        error!("Error parsing xml at position {}: {:?}", reader.buffer_position(), e);
-                        return Err(
-                            format!("Error parsing xml at position {}: {:?}", reader.buffer_position(), e).into()
-                        )
-                    },
+        visit_dir(sound_dir, &mut func)?; // Run the DFS!
 
-                    _ => () // Other Reader::Events aren't used, just ignore them.
+        // Declare variables that are used, but weren't declared.
+        for sound in sounds.iter() {
+            let names = sound.condition.iter()
+                .chain(sound.files.iter()
+                    .chain(sound.sequence.iter().flat_map(|step| step.files.iter()))
+                    .filter_map(|f| f.condition.as_ref()))
+                .flat_map(|condition| condition.variables())
+                .chain(sound.actions.iter().map(|action| action.name()));
+            for name in names {
+                if !variables.is_declared(name) {
+                    warn!("Variable {} is used by {}, but isn't declared.", name, sound.pattern.as_str());
+                    warn!("Will declare it with a default of 0.");
+                    variables.declare(name, 0);
                 }
             }
-        };
+        }
 
-        visit_dir(sound_dir, &mut func)?; // Run the DFS!
+        for sound in sounds.iter() {
+            for change in sound.effects.iter() {
+                if !channels.contains_key(&change.channel) {
+                    warn!("Sound {} changes the effects of channel {}, which isn't in the soundpack.",
+                        sound.pattern.as_str(), change.channel);
+                    warn!("Will ignore this Effect.");
+                }
+            }
+        }
 
         // Add "total" channel.
         let mut channel_names: Vec<Box<str>> = vec![
@@ -356,7 +182,16 @@ impl SoundManager {
         }
         // Add the "misc" channel last, so it comes last in the UI.
         channel_names.push("misc".into());
+        if options.normalize.is_some() {
+            // Analyse every file the soundpack can play, once.
+            normalizer.analyse(sound_paths(sounds.iter()));
+        }
+        if options.prewarm {
+            // Only oneshots are cached, loops are long and played once in a while.
+            sample_cache.prewarm(sound_paths(sounds.iter().filter(|sound| sound.loop_attr.is_none())));
+        }
         ui_sender.send(UIMessage::LoadedSoundpack(channel_names))?;
+        ui_sender.send(UIMessage::GameContextChanged(context.describe()))?;
 
         info!("Soundpack loaded!");
         let mut manager = Self {
@@ -364,20 +199,40 @@ impl SoundManager {
             recent: HashSet::new(),
             previous_log: String::new(),
             ignore_list: Vec::new(),
-            device,
+            master,
+            backend: options.backend,
+            device: options.device.clone(),
+            device_names: options.backend.device_names(),
+            device_timer: 0,
             channels,
             total_volume,
             total_is_paused,
             total_threshold: 4,
             ui_sender,
-            rng: thread_rng(),
+            rng: match options.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            },
+            variables,
+            context,
+            normalizer,
+            sample_cache,
+            stats_timer: 0,
+            meter_timer: 0,
+            last_reduction: None,
+            channel_devices: HashMap::new(),
         };
 
-        // Apply channels settings if it exists.
-        if let Some(channel_settings) = channel_settings {
-            manager.apply_channel_settings(channel_settings);
-        }
+        // Apply channels settings.
+        manager.apply_channel_settings(channel_settings);
+        manager.send_channel_devices()?;
+        manager.send_channel_effects()?;
+
+        Ok(manager)
+    }
 
+    /// Apply the default volumes and channel devices the user saved.
+    fn load_user_config(&mut self) -> Result<()> {
         let conf_path = dirs::config_dir()
             .map(|mut p| {
                 p.push("soundsense-rs/default-volumes.ini");
@@ -398,18 +253,65 @@ impl SoundManager {
         if let Some(conf_path) = conf_path { // Check if there are default volumes.
             let file = fs::File::open(conf_path)?;
             // Apply default volumes.
-            manager.get_default_volume(file)?;
+            self.get_default_volume(file)?;
         }
 
-        Ok(manager)
+        // Route channels to the output devices the user chose.
+        if let Some(conf_path) = channel_devices_path().filter(|p| p.is_file()) {
+            let file = fs::File::open(conf_path)?;
+            self.get_channel_devices(file)?;
+        }
+        self.send_channel_devices()?;
+        Ok(())
     }
 
     /// Apply ChannelSettings.
     fn apply_channel_settings(&mut self, channel_settings: HashMap<Box<str>, ChannelSetting>) {
-        for (name, setting) in channel_settings.iter() {
-            if let Some(channel) = self.channels.get_mut(name) {
+        for (name, setting) in channel_settings.into_iter() {
+            for (target, _) in setting.ducks.iter() {
+                if !self.channels.contains_key(target) {
+                    warn!("Channel {} ducks channel {}, which isn't in the soundpack.", name, target);
+                    warn!("Will ignore this rule.");
+                }
+            }
+            if let Some(channel) = self.channels.get_mut(&name) {
                 channel.play_type = setting.play_type;
+                channel.crossfade = setting.crossfade;
+                channel.set_fade_length(setting.fade);
+                channel.duck = setting.duck;
+                channel.ducks = setting.ducks;
+                channel.set_effects(setting.effects, 0);
+                if setting.limiter.is_some() {
+                    channel.set_limiter(setting.limiter, &self.master, &mut self.rng, &self.variables);
+                }
+            }
+            if setting.device.is_some() {
+                self.route_channel(&name, setting.device.as_deref());
+            }
+        }
+    }
+
+    /// Route a channel to the named output device, or back to the master bus with None.
+    /// If the device can't be found, or the backend has no devices, the channel plays on the master bus.
+    fn route_channel(&mut self, channel_name: &str, device_name: Option<&str>) {
+        let backend = self.backend;
+        let master = &self.master;
+        if let Some(channel) = self.channels.get_mut(channel_name) {
+            if channel.device_name() == device_name && !channel.is_routing_lost() {
+                return
             }
+            let routed = device_name
+                .filter(|_| backend.has_devices())
+                .and_then(|name| {
+                    let sink = backend.open_device(name);
+                    if sink.is_none() {
+                        warn!("Audio output device {} wasn't found.", name);
+                        warn!("Will play this channel on the default device instead.");
+                    }
+                    sink
+                })
+                .map(|sink| master.routed(&*sink));
+            channel.route(device_name, routed, master, &mut self.rng, &self.variables);
         }
     }
 
@@ -428,12 +330,135 @@ impl SoundManager {
 				timeout != 0
 			});
 		}
-		for chn in self.channels.values_mut() {
-			chn.maintain(&mut self.rng, dt);
+        // The volume each channel is ducked to by other channels. The strongest rule wins.
+        let mut ducked_to: HashMap<Box<str>, f32> = HashMap::new();
+        for chn in self.channels.values().filter(|chn| chn.is_ducking()) {
+            for (target, amount) in chn.ducks.iter() {
+                let volume = ducked_to.entry(target.clone()).or_insert(1.0);
+                *volume = volume.min(1.0 - amount);
+            }
+        }
+		for (name, chn) in self.channels.iter_mut() {
+			chn.maintain(&mut self.rng, dt, &self.variables, ducked_to.get(name).copied().unwrap_or(1.0));
+            if let Some(now_playing) = chn.poll_now_playing() {
+                self.ui_sender.send(UIMessage::NowPlaying(name.clone(), now_playing))?;
+            }
 		}
+        if let Some(variables) = self.variables.take_changed() {
+            self.ui_sender.send(UIMessage::VariablesChanged(variables))?;
+        }
+        self.stats_timer += dt;
+        if self.stats_timer >= 1000 {
+            self.stats_timer = 0;
+            if let Some(stats) = self.sample_cache.poll_stats() {
+                self.ui_sender.send(UIMessage::SampleCacheStats(stats))?;
+            }
+        }
+        self.meter_timer += dt;
+        if self.meter_timer >= 100 {
+            self.meter_timer = 0;
+            if let Some(meter) = self.master.limiter_meter() {
+                // Only sent when it changes by a tenth of a decibel or more.
+                let reduction = (meter.poll() * 10.0).round().max(0.0) as u32;
+                if self.last_reduction != Some(reduction) {
+                    self.last_reduction = Some(reduction);
+                    self.ui_sender.send(UIMessage::LimiterReduction(reduction as f32 * 0.1))?;
+                }
+            }
+        }
+        self.device_timer += dt;
+        if self.device_timer >= DEVICE_CHECK_INTERVAL && self.backend.has_devices() {
+            self.device_timer = 0;
+            self.check_devices()?;
+        }
         Ok(())
 	}
 
+    /// Check that the output devices still play, and follow devices being plugged in and out.
+    /// A lost device is replaced by the chosen one, or the default one if it's gone too.
+    /// The chosen device is switched back to when it's plugged in again.
+    fn check_devices(&mut self) -> Result<()> {
+        let names = self.backend.device_names();
+        let devices_changed = names != self.device_names;
+        if devices_changed {
+            debug!("Output devices changed: {:?}", names);
+            self.device_names = names;
+            self.send_output_devices()?;
+        }
+
+        // A device that failed before is only tried again when the devices change.
+        let was_lost = self.master.has_failed();
+        let lost = !self.master.check_sink();
+        let wanted = match &self.device {
+            Some(name) if self.device_names.contains(name) => Some(name.clone()),
+            _ => self.backend.default_device_name(),
+        };
+        let moved = wanted.is_some() && wanted.as_deref() != self.master.device_name();
+        if lost && (!was_lost || devices_changed) {
+            self.recover_device()?;
+        }
+        else if !lost && devices_changed && moved {
+            info!("Output device {} is available, moving sounds to it.", wanted.as_deref().unwrap_or_default());
+            match self.backend.open(self.device.as_deref()) {
+                Ok(sink) => self.move_to_sink(&*sink),
+                Err(e) => {
+                    warn!("Failed to open an audio output device: {}", e);
+                    warn!("Will keep playing on {}.", self.master.device_name().unwrap_or_default());
+                }
+            }
+        }
+
+        let mut rerouted = false;
+        let channel_names: Vec<Box<str>> = self.channels.keys().cloned().collect();
+        for name in channel_names {
+            let channel = &self.channels[&name];
+            if !channel.check_routed() || (devices_changed && channel.is_routing_lost()) {
+                let device_name = channel.device_name().map(Box::from);
+                debug!("Routing {} to {} again.", name, device_name.as_deref().unwrap_or("default"));
+                self.route_channel(&name, device_name.as_deref());
+                rerouted = true;
+            }
+        }
+        if rerouted {
+            self.send_channel_devices()?;
+        }
+        Ok(())
+    }
+
+    /// Move every sound to a new device, after the one playing them was lost,
+    /// or when there was no device to begin with.
+    /// If no device can be opened, sounds stay on the lost one until the devices change.
+    fn recover_device(&mut self) -> Result<()> {
+        let lost_name = self.master.device_name().map(Box::from);
+        match &lost_name {
+            Some(name) => {
+                warn!("Audio output device {} stopped playing.", name);
+                warn!("Will move sounds to another device.");
+            }
+            None => info!("Looking for an audio output device."),
+        }
+        match self.backend.open(self.device.as_deref()) {
+            Ok(sink) => self.move_to_sink(&*sink),
+            Err(e) => {
+                warn!("Failed to open an audio output device: {}", e);
+                warn!("Will try again when a device is plugged in.");
+            }
+        }
+        let current = self.master.device_name()
+            .filter(|_| !self.master.has_failed())
+            .map(Box::from);
+        if let Some(lost_name) = lost_name {
+            self.ui_sender.send(UIMessage::OutputDeviceLost(lost_name, current))?;
+        }
+        self.send_output_devices()
+    }
+
+    /// Tell the UI which output devices there are, and which one was chosen.
+    fn send_output_devices(&self) -> Result<()> {
+        self.ui_sender.send(UIMessage::OutputDevices(self.device_names.clone(), self.device.clone()))?;
+        Ok(())
+    }
+
     /// Set the volume of all, or specific channels.
     pub fn set_volume(&mut self, channel_name: &str, volume: f32) -> Result<()> {
         if channel_name == "total" {
@@ -489,10 +514,92 @@ impl SoundManager {
         Ok(())
     }
 
+    /// Play sounds on another output device, moving every channel to it.
+    /// Channels routed to their own device stay on it.
+    pub fn change_device(&mut self, name: Option<&str>) -> Result<()> {
+        let sink = self.backend.open(name)?;
+        self.device = name.map(Box::from);
+        self.move_to_sink(&*sink);
+        Ok(())
+    }
+
+    /// Play every channel that isn't routed to its own device on a new master bus, on the sink.
+    /// Oneshots are stopped, and loops start their current sound over.
+    fn move_to_sink(&mut self, sink: &dyn AudioSink) {
+        let master = self.master.on_sink(sink);
+        for channel in self.channels.values_mut() {
+            channel.change_master(&master, &mut self.rng, &self.variables);
+        }
+        std::mem::replace(&mut self.master, master).finish();
+    }
+
+    /// Change the stereo width, mono downmix and left/right swap of every device.
+    #[inline]
+    pub fn set_stereo(&self, settings: StereoSettings) {
+        self.master.set_stereo(settings);
+    }
+
+    /// Route a channel to the named output device, or back to the manager's device with None.
+    /// The choice is saved, and applied every time the channel is loaded.
+    pub fn set_channel_device(&mut self, channel_name: &str, device_name: Option<Box<str>>) -> Result<()> {
+        if self.channels.contains_key(channel_name) {
+            self.route_channel(channel_name, device_name.as_deref());
+            self.channel_devices.insert(channel_name.into(), device_name);
+            if let Some(conf_path) = channel_devices_path() {
+                if let Some(dir) = conf_path.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                self.set_channel_devices(File::create(conf_path)?)?;
+            }
+            self.send_channel_devices()?;
+        }
+        Ok(())
+    }
+
+    /// Tell the UI which output device each channel is routed to.
+    fn send_channel_devices(&self) -> Result<()> {
+        let entries = self.channels.iter()
+            .map(|(name, channel)| (name.clone(), channel.device_name().map(Box::from)))
+            .collect();
+        self.ui_sender.send(UIMessage::ChannelDevices(entries))?;
+        Ok(())
+    }
+
+    /// Change a channel's effects, with a short transition so the change doesn't click.
+    pub fn set_channel_effects(&mut self, channel_name: &str, settings: EffectSettings) -> Result<()> {
+        if let Some(channel) = self.channels.get_mut(channel_name) {
+            channel.set_effects(settings, DEFAULT_EFFECTS_TRANSITION);
+            self.send_channel_effects()?;
+        }
+        Ok(())
+    }
+
+    /// Tell the UI the effects of each channel.
+    fn send_channel_effects(&self) -> Result<()> {
+        let entries = self.channels.iter()
+            .map(|(name, channel)| (name.clone(), channel.effects()))
+            .collect();
+        self.ui_sender.send(UIMessage::ChannelEffects(entries))?;
+        Ok(())
+    }
+
+    /// Block until the soundpack's files are analysed for loudness normalization.
+    pub fn wait_for_analysis(&self) {
+        self.normalizer.wait();
+    }
+
+    /// Whether any channel is playing a sound.
+    pub fn is_playing(&self) -> bool {
+        self.channels.values().any(|channel| channel.len() > 0 || channel.has_scheduled())
+    }
+
     pub fn finish(mut self) {
+        self.normalizer.cancel();
+        self.sample_cache.cancel();
         for (_,channel) in self.channels.iter_mut() {
             channel.finish();
         }
+        self.master.finish();
     }
 
     /// Reload the ignore list.
@@ -520,6 +627,11 @@ impl SoundManager {
             self.previous_log = log.to_string();
         }
 
+        if self.context.process_log(log) {
+            self.context.write_variables(&mut self.variables);
+            self.ui_sender.send(UIMessage::GameContextChanged(self.context.describe()))?;
+        }
+
         for pattern in self.ignore_list.iter() {
             if pattern.is_match(log) {
                 return Ok(())
@@ -529,13 +641,37 @@ impl SoundManager {
         let rng = &mut self.rng;
         let sounds = &mut self.sounds;
         let recent = &mut self.recent;
+        let variables = &mut self.variables;
 
         for (i, sound) in sounds.iter_mut().enumerate() {
             // Activate the Sound if the log matches its pattern.
             if sound.pattern.is_match(log) {
+                // A Sound whose condition is false acts as if it didn't match.
+                if let Some(condition) = &sound.condition {
+                    if !condition.eval(variables) {
+                        trace!(" pattern: {} - condition false: {}", sound.pattern.as_str(), condition.as_str());
+                        continue;
+                    }
+                }
+                if !sound.context_filter.matches(variables) {
+                    trace!(" pattern: {} - wrong season or game mode", sound.pattern.as_str());
+                    continue;
+                }
                 trace!(" pattern: {}", sound.pattern.as_str());
                 recent.insert(i);
                 sound.recent_call += 1;
+                for action in sound.actions.iter() {
+                    variables.apply(action);
+                }
+                for change in sound.effects.iter() {
+                    if let Some(channel) = self.channels.get(&change.channel) {
+                        trace!("  effects of {} change over {}ms", change.channel, change.transition);
+                        channel.change_effects(change);
+                        self.ui_sender.send(UIMessage::ChannelEffects(
+                            vec![(change.channel.clone(), channel.effects())]
+                        ))?;
+                    }
+                }
 
                 let mut can_play = sound.current_timeout == 0;
                 if can_play {
@@ -561,18 +697,24 @@ impl SoundManager {
                     // Choose index.
                     // If there are more than one soundfiles,
                     //      and the sound doesn't loop, choose based on weighted distribution.
-                    // Else, 0.
-                    let idx : usize = if files.len() > 1 && !sound.loop_attr.unwrap_or(false) {
-                        match WeightedIndex::new(&sound.weights) {
-                            Ok(weight) => weight.sample(rng),
+                    // Else, the first file whose condition is true.
+                    let first_available = files.iter().position(|f| f.is_available(variables));
+                    let idx : Option<usize> = if files.len() > 1 && !sound.loop_attr.unwrap_or(false) {
+                        let weights = sound.weights.iter()
+                            .zip(files.iter())
+                            .map(|(&weight, file)| if file.is_available(variables) {weight} else {0.0});
+                        match WeightedIndex::new(weights) {
+                            Ok(weight) => Some(weight.sample(rng)),
                             Err(e) => {
                                 trace!("Error while weighing files: {}", e);
-                                0
+                                first_available
                             }
                         }
                     } else {
-                        0
+                        first_available
                     };
+                    // Sequences choose their own files, step by step.
+                    let has_sequence = !sound.sequence.is_empty();
 
                     // Play on a given channel.
                     if let Some(chn) = &sound.channel {
@@ -583,63 +725,47 @@ impl SoundManager {
                             trace!("   doesn't exist in current soundpack!");
                             continue;
                         };
-                        let chn_len = channel.len();
-                        let chn_threshold = channel.get_threshold();
-                        // Check if there are too many sounds playing on this channel (concurrency).
-                        if chn_len >= sound.concurency.unwrap_or(std::usize::MAX) {
-                            trace!("   can't play: at concurency limit: limit {}, channel {}",
-                                sound.concurency.unwrap(), chn_len);
-                        }
-                        // Check if the playback_threshold is higher than the channel threshold.
-                        else if chn_threshold < sound.playback_threshold {
-                            trace!("   can't play: at threshold limit - sound.playback_threshold: {}, channel_threshold: {}",
-                                sound.playback_threshold, chn_threshold);
-                        }
-                        else {
+                        if can_play_on(sound, channel) {
                             // Set current_timeout if the sound has a timeout value.
                             if let Some(timeout) = sound.timeout {
                                 sound.current_timeout = timeout;
                             }
-                            let device = &self.device;
-                            
                             // Check if the sound starts a loop
                             if let Some(is_loop_start) = sound.loop_attr {
                                 if is_loop_start {
                                     trace!("   loop=start");
-                                    channel.change_loop(device, sound.files.as_slice(), sound.delay.unwrap_or(0), rng);
+                                    channel.change_loop(files.as_slice(), sound.delay.unwrap_or(0), sound.crossfade, rng, variables);
                                 } else {
                                     // If loop=stop, add the sound to the oneshot player.
                                     trace!("   loop=stop");
                                     channel.stop_loop(sound.delay.unwrap_or(0));
-                                    if !sound.files.is_empty() {
-                                        channel.add_oneshot(device, &files[idx], sound.delay.unwrap_or(0), rng);
+                                    if let Some(idx) = idx {
+                                        channel.add_oneshot(&files[idx], sound.delay.unwrap_or(0), sound.priority, &sound.instances, rng);
                                     }
                                 }
                             }
                             // Otherwise, add to oneshot player.
-                            else if !sound.files.is_empty() && channel.len() <= sound.concurency.unwrap_or(std::usize::MAX) {
-                                channel.add_oneshot(device, &files[idx], sound.delay.unwrap_or(0), rng);
+                            else if has_sequence {
+                                channel.add_sequence(&sound.sequence, sound.delay.unwrap_or(0), sound.priority, &sound.instances, rng, variables);
+                            }
+                            else if let Some(idx) = idx {
+                                channel.add_oneshot(&files[idx], sound.delay.unwrap_or(0), sound.priority, &sound.instances, rng);
                             }
                         }
                     }
-                    else if !sound.files.is_empty() {
+                    else if has_sequence || idx.is_some() {
                         trace!("  channel: misc");
                         let channel = self.channels.get_mut("misc").unwrap();
-                        let chn_len = channel.len();
-                        let chn_threshold = channel.get_threshold();
-                        if chn_len >= sound.concurency.unwrap_or(std::usize::MAX) {
-                            trace!("   can't play: at concurency limit - limit {}, channel {}",
-                                sound.concurency.unwrap(), chn_len);
-                        }
-                        else if chn_threshold < sound.playback_threshold {
-                            trace!("   can't play: at threshold limit - sound.playback_threshold: {}, channel_threshold: {}",
-                                sound.playback_threshold, chn_threshold);
-                        }
-                        else {
+                        if can_play_on(sound, channel) {
                             if let Some(timeout) = sound.timeout {
                                 sound.current_timeout = timeout;
                             }
-                            channel.add_oneshot(&self.device, &files[idx], sound.delay.unwrap_or(0), rng);
+                            if has_sequence {
+                                channel.add_sequence(&sound.sequence, sound.delay.unwrap_or(0), sound.priority, &sound.instances, rng, variables);
+                            }
+                            else if let Some(idx) = idx {
+                                channel.add_oneshot(&files[idx], sound.delay.unwrap_or(0), sound.priority, &sound.instances, rng);
+                            }
                         }
                     }
                 }
@@ -662,6 +788,39 @@ impl SoundManager {
         Ok(())
     }
 
+    /// Store the channels' output devices in the soundsense-rs/channel-devices.ini file.
+    fn set_channel_devices(&self, mut file: File) -> Result<()> {
+        use std::io::Write;
+        for (channel_name, device_name) in self.channel_devices.iter() {
+            writeln!(&mut file, "{}={}", channel_name, device_name.as_deref().unwrap_or(""))?;
+        }
+        Ok(())
+    }
+
+    /// Get the channels' output devices from the soundsense-rs/channel-devices.ini file.
+    /// An empty device name routes the channel to the manager's device.
+    fn get_channel_devices(&mut self, mut file: File) -> Result<()> {
+        lazy_static! {
+            static ref INI_ENTRY: Regex = Regex::new("([[:word:]]+)=(.*)").unwrap();
+        }
+        let mut buf = String::new();
+        file.read_to_string(&mut buf)?;
+        for line in buf.lines() {
+            if let Some(cap) = INI_ENTRY.captures(line) {
+                let name = cap.get(1)
+                    .ok_or("Failed to parse .ini file.")?
+                    .as_str();
+                let device_name = cap.get(2)
+                    .map(|m| m.as_str().trim())
+                    .filter(|s| !s.is_empty())
+                    .map(Box::from);
+                self.route_channel(name, device_name.as_deref());
+                self.channel_devices.insert(name.into(), device_name);
+            }
+        }
+        Ok(())
+    }
+
     /// Get the volume from the soundsense-rs/default-volumes.ini file.
     fn get_default_volume(&mut self, mut file: File) -> Result<()> {
         lazy_static! {
@@ -695,56 +854,241 @@ impl SoundManager {
     }
 }
 
-/// Convert a playlist into a list or file paths.
-fn parse_playlist(path: &Path) -> Result<Vec<PathBuf>> {
-    let parent_path = path.parent().unwrap();
-
-    let mut path_vec = Vec::new();
-    let f = File::open(path)?;
-    let f = BufReader::new(f);
-    // Check if the path contains the m3u or pls extension.
-    // Else, error out.
-    let extension = path.extension()
-        .filter(|ext| *ext=="m3u" || *ext=="pls")
-        .ok_or_else(|| format!(
-            "Playlist {:?} is not valid! Playlist needs to have either .m3u or .pls extension.",
-            path
-        ))?;
-    if extension == "m3u" {
-        for line in f.lines()
-            .filter_map(|l| l.ok())
-        {
-            lazy_static! {
-                static ref M3U_PATTERN: Regex = Regex::new(
-                    r"#EXT.*"
-                ).unwrap();
-            }
+/// Open the device chosen in the options, and a master bus playing on it.
+/// Without any device, the bus plays nowhere until `check_devices` finds one.
+fn open_master(options: &SoundOptions) -> MasterBus {
+    let sink = options.backend.open(options.device.as_deref())
+        .unwrap_or_else(|e| {
+            warn!("Failed to open an audio output device: {}", e);
+            warn!("Will play sounds once a device is plugged in.");
+            Box::new(MissingSink)
+        });
+    MasterBus::new(&*sink, options.recorder.clone(), options.stereo, options.limiter)
+}
 
-            if !M3U_PATTERN.is_match(&line) {
-                let mut path = PathBuf::from(parent_path);
-                path.push(line);
-                trace!("   Playlist Entry: {:?}", path);
-                path_vec.push(path);
-            }
-        }
+/// Check the sound's limits on the channel it plays on: its concurency, its maxInstances,
+/// and the channel's threshold.
+fn can_play_on(sound: &SoundEntry, channel: &SoundChannel) -> bool {
+    let chn_len = channel.len();
+    let chn_threshold = channel.get_threshold();
+    let instances = sound.instances.count();
+    trace!("   playing: channel {}, instances {}", chn_len, instances);
+    // Check if there are too many sounds playing on this channel (concurrency).
+    if chn_len >= sound.concurency.unwrap_or(std::usize::MAX) {
+        trace!("   can't play: at concurency limit - limit {}, channel {}",
+            sound.concurency.unwrap(), chn_len);
+        false
     }
-    else if extension == "pls" {
-        for line in f.lines()
-            .filter_map(|l| l.ok())
-        {
-            lazy_static! {
-                static ref PLS_PATTERN: Regex = Regex::new(
-                    r"File.+=(.+)"
-                ).unwrap();
-            }
-            
-            if let Some(caps) = PLS_PATTERN.captures(&line) {
-                let mut path = PathBuf::from(parent_path);
-                path.push(&caps[0]);
-                trace!("   Playlist Entry: {:?}", path);
-                path_vec.push(path);
+    // Check if there are too many instances of this sound playing.
+    else if instances >= sound.max_instances.unwrap_or(std::usize::MAX) {
+        trace!("   can't play: at instance limit - limit {}, instances {}",
+            sound.max_instances.unwrap(), instances);
+        false
+    }
+    // Check if the playback_threshold is higher than the channel threshold.
+    else if chn_threshold < sound.playback_threshold {
+        trace!("   can't play: at threshold limit - sound.playback_threshold: {}, channel_threshold: {}",
+            sound.playback_threshold, chn_threshold);
+        false
+    }
+    else {
+        true
+    }
+}
+
+/// Path of the soundsense-rs/channel-devices.ini file, in the config directory.
+fn channel_devices_path() -> Option<PathBuf> {
+    dirs::config_dir()
+        .map(|mut p| {
+            p.push("soundsense-rs/channel-devices.ini");
+            p
+        })
+}
+
+/// Get the paths of every file the sounds can play, without duplicates.
+fn sound_paths<'a>(sounds: impl Iterator<Item=&'a SoundEntry>) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    let mut seen: HashSet<&Path> = HashSet::new();
+    let files = sounds
+        .flat_map(|sound| sound.files.iter()
+            .chain(sound.sequence.iter().flat_map(|step| step.files.iter())));
+    for file in files {
+        let mut file_paths: Vec<&Path> = match &file.r#type {
+            SoundFileType::IsPath(path) => vec![path],
+            SoundFileType::IsPlaylist(entries) => entries.iter().map(|e| e.path.as_path()).collect(),
+        };
+        file_paths.extend(file.intro.as_deref());
+        file_paths.extend(file.outro.as_deref());
+        for path in file_paths {
+            if seen.insert(path) {
+                paths.push(path.to_path_buf());
             }
         }
     }
-    Ok(path_vec)
-}
\ No newline at end of file
+    paths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    /// A soundpack with a loop on the weather channel, started by "It is raining.".
+    fn loop_pack(name: &str) -> PathBuf {
+        let dir = test_dir(name);
+        let mut writer = AudioFileWriter::create(&dir.join("loop.wav"), DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE).unwrap();
+        let samples: Vec<f32> = (0..DEFAULT_SAMPLE_RATE as usize)
+            .flat_map(|i| {
+                let sample = (i as f32 * 440.0 * 2.0 * std::f32::consts::PI / DEFAULT_SAMPLE_RATE as f32).sin() * 0.5;
+                vec![sample; DEFAULT_CHANNELS as usize]
+            })
+            .collect();
+        writer.write_samples(&samples).unwrap();
+        writer.finish().unwrap();
+        fs::write(dir.join("pack.xml"), r#"<?xml version="1.0" encoding="UTF-8"?>
+<sounds defaultAnsiFormat="%s">
+<sound logPattern="^It is raining\." channel="weather" loop="start"><soundFile fileName="loop.wav"/></sound>
+</sounds>
+"#).unwrap();
+        dir
+    }
+
+    /// A soundpack with a short oneshot on the weather channel, played a second after "Thunder.",
+    /// one instance at a time.
+    fn delayed_pack(name: &str) -> PathBuf {
+        let dir = test_dir(name);
+        let mut writer = AudioFileWriter::create(&dir.join("thunder.wav"), DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE).unwrap();
+        writer.write_samples(&vec![0.5; DEFAULT_SAMPLE_RATE as usize / 10 * DEFAULT_CHANNELS as usize]).unwrap();
+        writer.finish().unwrap();
+        fs::write(dir.join("pack.xml"), r#"<?xml version="1.0" encoding="UTF-8"?>
+<sounds defaultAnsiFormat="%s">
+<sound logPattern="^Thunder\." channel="weather" delay="1000" maxInstances="1"><soundFile fileName="thunder.wav"/></sound>
+</sounds>
+"#).unwrap();
+        dir
+    }
+
+    /// Whether the device plays something audible over the next half second.
+    fn is_heard(sink: &test_util::TestSink) -> bool {
+        sink.read(DEFAULT_SAMPLE_RATE as usize / 2).iter().any(|sample| sample.abs() > 0.1)
+    }
+
+    /// The devices lost since the last call, as told to the UI: the lost one, and the one sounds moved to.
+    fn lost_devices(ui_rx: &Receiver<UIMessage>) -> Vec<(Box<str>, Option<Box<str>>)> {
+        ui_rx.try_iter()
+            .filter_map(|message| match message {
+                UIMessage::OutputDeviceLost(lost, current) => Some((lost, current)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn follows_devices() {
+        let dir = loop_pack("follows-devices");
+        let speakers = test_util::plug("Speakers");
+        let headphones = test_util::plug("Headphones");
+        let options = SoundOptions {
+            backend: AudioBackend::Test,
+            device: Some("Headphones".into()),
+            ..SoundOptions::default()
+        };
+        let (ui_tx, ui_rx) = crossbeam::channel::unbounded();
+        let mut manager = SoundManager::with_master(&dir, ui_tx, &options, open_master(&options)).unwrap();
+        manager.process_log("It is raining.").unwrap();
+        manager.maintain(10).unwrap();
+        assert!(is_heard(&headphones));
+        assert!(!is_heard(&speakers));
+
+        // The chosen device is unplugged: sounds move to the default one.
+        test_util::unplug("Headphones");
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(lost_devices(&ui_rx), vec![("Headphones".into(), Some("Speakers".into()))]);
+        assert!(is_heard(&speakers));
+
+        // It's plugged back in: sounds move back to it.
+        let headphones = test_util::plug("Headphones");
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(manager.master.device_name(), Some("Headphones"));
+        assert!(is_heard(&headphones));
+        // What the speakers played fades out.
+        speakers.read(DEFAULT_SAMPLE_RATE as usize);
+        assert!(!is_heard(&speakers));
+
+        // Every device is unplugged: sounds wait, and aren't moved again until the devices change.
+        test_util::unplug("Headphones");
+        test_util::unplug("Speakers");
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(lost_devices(&ui_rx), vec![("Headphones".into(), None)]);
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert!(lost_devices(&ui_rx).is_empty());
+
+        // A device other than the chosen one is plugged in.
+        let speakers = test_util::plug("Speakers");
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(lost_devices(&ui_rx), vec![("Headphones".into(), Some("Speakers".into()))]);
+        assert_eq!(manager.master.device_name(), Some("Speakers"));
+        assert!(is_heard(&speakers));
+        // A device that stops reading is lost too.
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(lost_devices(&ui_rx), vec![("Speakers".into(), Some("Speakers".into()))]);
+        assert!(is_heard(&speakers));
+
+        manager.finish();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn waits_for_a_device() {
+        let dir = loop_pack("waits-for-a-device");
+        let options = SoundOptions {
+            backend: AudioBackend::Test,
+            ..SoundOptions::default()
+        };
+        let (ui_tx, ui_rx) = crossbeam::channel::unbounded();
+        // The soundpack loads without any device.
+        let mut manager = SoundManager::with_master(&dir, ui_tx, &options, open_master(&options)).unwrap();
+        manager.process_log("It is raining.").unwrap();
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(manager.master.device_name(), None);
+
+        let speakers = test_util::plug("Speakers");
+        manager.maintain(DEVICE_CHECK_INTERVAL).unwrap();
+        assert_eq!(manager.master.device_name(), Some("Speakers"));
+        assert!(is_heard(&speakers));
+        // Nothing was lost.
+        assert!(lost_devices(&ui_rx).is_empty());
+
+        manager.finish();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn delayed_oneshots_are_instances() {
+        let dir = delayed_pack("delayed-instances");
+        let speakers = test_util::plug("Speakers");
+        let options = SoundOptions {
+            backend: AudioBackend::Test,
+            ..SoundOptions::default()
+        };
+        let (ui_tx, _ui_rx) = crossbeam::channel::unbounded();
+        let mut manager = SoundManager::with_master(&dir, ui_tx, &options, open_master(&options)).unwrap();
+        manager.process_log("Thunder.").unwrap();
+        manager.maintain(10).unwrap();
+        assert_eq!(manager.channels["weather"].len(), 1);
+        // The waiting oneshot is the only instance allowed.
+        manager.process_log("Thunder.").unwrap();
+        assert_eq!(manager.channels["weather"].len(), 1);
+
+        manager.maintain(1000).unwrap();
+        assert!(is_heard(&speakers));
+        manager.maintain(10).unwrap();
+        assert_eq!(manager.channels["weather"].len(), 0);
+        manager.process_log("Thunder.").unwrap();
+        assert_eq!(manager.channels["weather"].len(), 1);
+
+        manager.finish();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
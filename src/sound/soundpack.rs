@@ -0,0 +1,1313 @@
+use super::*;
+use serde::{Serialize, Deserialize};
+
+/// The file formats a soundpack file can be written in.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PackFormat {
+    /// The original SoundSense XML dialect.
+    Xml,
+    /// TOML, mapping 1:1 onto the XML elements.
+    Toml,
+    /// JSON, same structure as TOML.
+    Json,
+}
+
+impl PackFormat {
+    /// Get the format from a file's extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "xml" => Some(PackFormat::Xml),
+            "toml" => Some(PackFormat::Toml),
+            "json" => Some(PackFormat::Json),
+            _ => None,
+        }
+    }
+
+    /// Get the format from its name, as used by the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "xml" => Some(PackFormat::Xml),
+            "toml" => Some(PackFormat::Toml),
+            "json" => Some(PackFormat::Json),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn extension(self) -> &'static str {
+        match self {
+            PackFormat::Xml => "xml",
+            PackFormat::Toml => "toml",
+            PackFormat::Json => "json",
+        }
+    }
+}
+
+/// The contents of one soundpack file, before being turned into SoundEntries.
+/// Every field maps onto an element or attribute of the XML dialect.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct SoundpackDef {
+    /// `<sound>` elements.
+    #[serde(rename="sound", default, skip_serializing_if="Vec::is_empty")]
+    pub sounds: Vec<SoundDef>,
+    /// `<channelSetting>` elements inside `<channelSettings>`.
+    #[serde(rename="channelSetting", default, skip_serializing_if="Vec::is_empty")]
+    pub channel_settings: Vec<ChannelSettingDef>,
+    /// `<variable>` elements.
+    #[serde(rename="variable", default, skip_serializing_if="Vec::is_empty")]
+    pub variables: Vec<VariableDef>,
+    /// `<contextPattern>` elements.
+    #[serde(rename="contextPattern", default, skip_serializing_if="Vec::is_empty")]
+    pub context_patterns: Vec<ContextPatternDef>,
+}
+
+/// Whether a `<sound>` starts or stops its channel's loop.
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq)]
+#[serde(rename_all="camelCase")]
+pub enum LoopDef {
+    Start,
+    Stop,
+}
+
+/// A `<sound>` element.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct SoundDef {
+    /// Regex pattern, in regex crate syntax.
+    /// Patterns read from XML have the Java compatibility fixes already applied.
+    pub log_pattern: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub r#loop: Option<LoopDef>,
+    #[serde(default, skip_serializing_if="Option::is_none", alias="concurrency")]
+    pub concurency: Option<usize>,
+    /// Instances of the sound allowed to play at once.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub max_instances: Option<usize>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub timeout: Option<usize>,
+    #[serde(default, skip_serializing_if="Option::is_none", alias="propability")]
+    pub probability: Option<usize>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub delay: Option<usize>,
+    /// Crossfade length when starting a loop, overriding the channel's.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub crossfade: Option<usize>,
+    /// Importance of the sound when the voice limit is reached. Higher wins.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub priority: Option<i32>,
+    #[serde(default, skip_serializing_if="is_false")]
+    pub halt_on_match: bool,
+    #[serde(default, skip_serializing_if="is_false")]
+    pub random_balance: bool,
+    /// Semitones the pitch of the sound's files varies by, either way.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub random_pitch: Option<f32>,
+    /// Decibels the volume of the sound's files varies by, either way.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub random_volume: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none", alias="playbackThreshhold")]
+    pub playback_threshold: Option<u8>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ansi_format: Option<String>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ansi_pattern: Option<String>,
+    /// Condition expression, the sound is ignored unless it's true.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub condition: Option<String>,
+    /// Variables to set on match, as `name=value` list.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub set: Option<String>,
+    /// Variables to set to 0 on match.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub clear: Option<String>,
+    /// Variables to increment on match, as `name` or `name=amount` list.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub increment: Option<String>,
+    /// Comma separated seasons the sound is restricted to.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub season: Option<String>,
+    /// Comma separated game modes the sound is restricted to.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub mode: Option<String>,
+    /// `<soundFile>` elements.
+    #[serde(rename="soundFile", default, skip_serializing_if="Vec::is_empty")]
+    pub files: Vec<SoundFileDef>,
+    /// `<step>` elements of the `<sequence>` element.
+    #[serde(default, skip_serializing_if="Vec::is_empty")]
+    pub sequence: Vec<StepDef>,
+    /// `<effect>` elements.
+    #[serde(rename="effect", default, skip_serializing_if="Vec::is_empty")]
+    pub effects: Vec<EffectDef>,
+}
+
+/// An `<effect>` element, changing a channel's effects when the sound matches.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct EffectDef {
+    /// Channel whose effects change. Defaults to the sound's channel.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub channel: Option<String>,
+    /// Cutoff of the low-pass filter, in Hz.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub lowpass: Option<f32>,
+    /// Cutoff of the high-pass filter, in Hz.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub highpass: Option<f32>,
+    /// Gains of the equalizer's low, mid and high bands, in decibels.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eq_low: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eq_mid: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eq_high: Option<f32>,
+    /// Percentage of the channel sent to its reverb.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub reverb: Option<f32>,
+    /// Time to move to the new effects, in miliseconds.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub transition: Option<usize>,
+}
+
+/// A `<step>` element, inside a `<sequence>`.
+/// A `<soundFile>` directly inside a `<sequence>` is a step with only that file.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct StepDef {
+    /// Miliseconds of silence before the step.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub gap: Option<usize>,
+    #[serde(default, skip_serializing_if="Option::is_none", alias="propability")]
+    pub probability: Option<usize>,
+    /// `<soundFile>` elements, one of which is chosen by weight.
+    #[serde(rename="soundFile", default, skip_serializing_if="Vec::is_empty")]
+    pub files: Vec<SoundFileDef>,
+}
+
+/// A `<soundFile>` element.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct SoundFileDef {
+    /// Path relative to the soundpack file that declared it.
+    pub file_name: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub weight: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub volume_adjustment: Option<f32>,
+    #[serde(default, skip_serializing_if="is_false")]
+    pub random_balance: bool,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub balance_adjustment: Option<f32>,
+    /// Semitones the pitch varies by, either way. Overrides the sound's.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub random_pitch: Option<f32>,
+    /// Decibels the volume varies by, either way. Overrides the sound's.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub random_volume: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub delay: Option<usize>,
+    #[serde(default, skip_serializing_if="is_false")]
+    pub playlist: bool,
+    /// Start of the section a loop repeats.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub loop_start: Option<LoopPointDef>,
+    /// End of the section a loop repeats.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub loop_end: Option<LoopPointDef>,
+    /// File played once before the loop, relative to the file that declared it.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub intro: Option<String>,
+    /// File played when the loop is stopped, relative to the file that declared it.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub outro: Option<String>,
+    /// Condition expression, the file is never chosen unless it's true.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub condition: Option<String>,
+    /// Comma separated seasons the file is restricted to.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub season: Option<String>,
+    /// Comma separated game modes the file is restricted to.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub mode: Option<String>,
+}
+
+/// A `loopStart` or `loopEnd` value: a frame offset, or text like `"1500ms"`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum LoopPointDef {
+    Frames(usize),
+    Text(String),
+}
+
+impl LoopPointDef {
+    pub fn build(&self) -> Result<LoopPoint> {
+        match self {
+            Self::Frames(frames) => Ok(LoopPoint::Frames(*frames)),
+            Self::Text(text) => LoopPoint::parse(text),
+        }
+    }
+}
+
+/// A `<channelSetting>` element.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct ChannelSettingDef {
+    pub name: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub play_type: Option<ChannelPlayType>,
+    /// Crossfade length between loops, in miliseconds.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub crossfade: Option<usize>,
+    /// Fade length when pausing, skipping or stopping, in miliseconds.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub fade: Option<usize>,
+    /// Percentage the channel's other sounds are lowered by while a oneshot plays.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub duck: Option<f32>,
+    /// Time to duck a sound, in miliseconds.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub duck_attack: Option<usize>,
+    /// Time to restore a ducked sound, in miliseconds.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub duck_release: Option<usize>,
+    /// Other channels ducked while this one plays oneshots, like `"music=60, weather=30"`.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub ducks: Option<String>,
+    /// Name of the output device the channel plays on, unless the user chose another one.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub device: Option<String>,
+    /// Ceiling of the channel's limiter, in dBFS.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub limiter: Option<f32>,
+    /// Cutoff of the low-pass filter, in Hz.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub lowpass: Option<f32>,
+    /// Cutoff of the high-pass filter, in Hz.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub highpass: Option<f32>,
+    /// Gains of the equalizer's low, mid and high bands, in decibels.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eq_low: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eq_mid: Option<f32>,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub eq_high: Option<f32>,
+    /// Percentage of the channel sent to its reverb.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub reverb: Option<f32>,
+}
+
+/// A `<variable>` element.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct VariableDef {
+    pub name: String,
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub default: Option<i64>,
+}
+
+/// A `<contextPattern>` element, adding a pattern to the game context tracker.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all="camelCase", deny_unknown_fields)]
+pub struct ContextPatternDef {
+    pub event: String,
+    pub log_pattern: String,
+}
+
+#[inline]
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+impl SoundpackDef {
+    /// Read a soundpack file, using its extension to determine its format.
+    pub fn from_file(file_path: &Path) -> Result<Self> {
+        match PackFormat::from_path(file_path) {
+            Some(PackFormat::Xml) => Self::from_xml(file_path),
+            Some(PackFormat::Toml) => {
+                let text = fs::read_to_string(file_path)?;
+                toml::from_str(&text)
+                    .map_err(|e| format!("Error parsing toml {}: {}", file_path.display(), e).into())
+            }
+            Some(PackFormat::Json) => {
+                let text = fs::read_to_string(file_path)?;
+                serde_json::from_str(&text)
+                    .map_err(|e| format!("Error parsing json {}: {}", file_path.display(), e).into())
+            }
+            None => Err(format!("{} is not a soundpack file.", file_path.display()).into()),
+        }
+    }
+
+    /// Parse an XML file.
+    #[allow(clippy::cognitive_complexity)]
+    fn from_xml(file_path: &Path) -> Result<Self> {
+        use quick_xml::{Reader, events::Event};
+        trace!(" XML: {:?}", file_path);
+        let mut reader = Reader::from_file(file_path)?;
+        let mut pack = Self::default();
+        let mut current_sound : Option<SoundDef> = None;
+        let mut in_sequence = false;
+        let mut in_step = false;
+        let mut in_channel_settings = false;
+        let buf = &mut Vec::new();
+        loop {
+            let event = reader.read_event(buf);
+            match event {
+                // <...> or <.../>
+                Ok(Event::Start(ref data)) | Ok(Event::Empty(ref data)) => {
+                    let self_closing = matches!(event, Ok(Event::Empty(_)));
+                    let local_name = data.local_name();
+                    // <sound> or <sound/>
+                    if local_name == b"sound" {
+                        let mut log_pattern: Option<String> = None;
+                        let mut sound = SoundDef {
+                            log_pattern: String::new(),
+                            channel: None,
+                            r#loop: None,
+                            concurency: None,
+                            max_instances: None,
+                            timeout: None,
+                            probability: None,
+                            delay: None,
+                            crossfade: None,
+                            priority: None,
+                            halt_on_match: false,
+                            random_balance: false,
+                            random_pitch: None,
+                            random_volume: None,
+                            playback_threshold: None,
+                            ansi_format: None,
+                            ansi_pattern: None,
+                            condition: None,
+                            set: None,
+                            clear: None,
+                            increment: None,
+                            season: None,
+                            mode: None,
+                            files: Vec::new(),
+                            sequence: Vec::new(),
+                            effects: Vec::new(),
+                        };
+
+                        for attr in data.attributes().with_checks(false) {
+                            let attr = attr?;
+                            // This value came from an XML file, so it must be utf8.
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"logPattern" => {
+                                    let processed = FAULTY_ESCAPE.replace_all(attr_value, "$1");
+                                    let processed = EMPTY_EXPR.replace_all(&processed, ")?");
+                                    log_pattern = Some(processed.into_owned());
+                                }
+                                b"channel" => {
+                                    sound.channel = Some(attr_value.to_string());
+                                }
+                                b"loop" => {
+                                    sound.r#loop = Some(
+                                        if attr_value == "start" {LoopDef::Start} else {LoopDef::Stop}
+                                    );
+                                }
+                                b"concurency" => {
+                                    sound.concurency = Some( attr_value.parse()? );
+                                }
+                                b"maxInstances" => {
+                                    sound.max_instances = Some( attr_value.parse()? );
+                                }
+                                b"timeout" => {
+                                    sound.timeout = Some( attr_value.parse()? );
+                                }
+                                // Probability was mispelled...
+                                b"propability" | b"probability" => {
+                                    sound.probability = Some( attr_value.parse()? );
+                                }
+                                b"delay" => {
+                                    sound.delay = Some( attr_value.parse()? );
+                                }
+                                b"crossfade" => {
+                                    sound.crossfade = Some( attr_value.parse()? );
+                                }
+                                b"priority" => {
+                                    sound.priority = Some( attr_value.parse()? );
+                                }
+                                b"haltOnMatch" => {
+                                    sound.halt_on_match = attr_value == "true";
+                                }
+                                b"randomBalance" => {
+                                    sound.random_balance = attr_value == "true" ;
+                                }
+                                b"randomPitch" => {
+                                    sound.random_pitch = Some( attr_value.parse()? );
+                                }
+                                b"randomVolume" => {
+                                    sound.random_volume = Some( attr_value.parse()? );
+                                }
+                                b"playbackThreshhold" => {
+                                    sound.playback_threshold = Some( attr_value.parse()? );
+                                }
+                                b"ansiFormat" => {
+                                    sound.ansi_format = Some(attr_value.to_string());
+                                }
+                                b"ansiPattern" => {
+                                    sound.ansi_pattern = Some(attr_value.to_string());
+                                }
+                                b"condition" => {
+                                    sound.condition = Some(unescaped_value(&attr)?);
+                                }
+                                b"set" => {
+                                    sound.set = Some(attr_value.to_string());
+                                }
+                                b"clear" => {
+                                    sound.clear = Some(attr_value.to_string());
+                                }
+                                b"increment" => {
+                                    sound.increment = Some(attr_value.to_string());
+                                }
+                                b"season" => {
+                                    sound.season = Some(attr_value.to_string());
+                                }
+                                b"mode" => {
+                                    sound.mode = Some(attr_value.to_string());
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown sound value: {}",
+                                        unsafe {std::str::from_utf8_unchecked(attr.key)}
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+
+                        trace!("  SoundEntry");
+                        if let Some(log_pattern) = log_pattern {
+                            trace!("  -Pattern: {}", log_pattern);
+                            sound.log_pattern = log_pattern;
+                            // <sound/> has no </sound> to finish it.
+                            if self_closing {
+                                pack.sounds.push(sound);
+                            }
+                            else {
+                                current_sound = Some(sound);
+                            }
+                        }
+                        else {
+                            warn!("A SoundEntry in {:?} doesn't have a pattern!", file_path);
+                            warn!("Will ignore this SoundEntry.");
+                        }
+                    }
+
+                    // <sequence>
+                    else if local_name == b"sequence" {
+                        if current_sound.is_none() {
+                            warn!("A Sequence in {:?} was declared outside of a valid Sound!", file_path);
+                            warn!("Will ignore this Sequence.");
+                            continue;
+                        }
+                        trace!("  -Sequence");
+                        in_sequence = !self_closing;
+                    }
+
+                    // <step> or <step/>
+                    else if local_name == b"step" {
+                        let sound = match current_sound.as_mut() {
+                            Some(sound) if in_sequence => sound,
+                            _ => {
+                                warn!("A Step in {:?} was declared outside of a valid Sequence!", file_path);
+                                warn!("Will ignore this Step.");
+                                continue;
+                            }
+                        };
+                        let mut step = StepDef::default();
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"gap" => {
+                                    step.gap = Some( attr_value.parse()? );
+                                }
+                                b"propability" | b"probability" => {
+                                    step.probability = Some( attr_value.parse()? );
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown step value: {}",
+                                        unsafe {std::str::from_utf8_unchecked(attr.key)}
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+                        trace!("  --Step");
+                        sound.sequence.push(step);
+                        in_step = !self_closing;
+                    }
+
+                    // <soundFile> or <soundFile/>
+                    else if local_name == b"soundFile" {
+                        let sound = if let Some(sound) = current_sound.as_mut() {
+                            sound
+                        } else {
+                            warn!("A SoundFile in {:?} was declared outside of a valid Sound!", file_path);
+                            warn!("Will ignore this SoundFile.");
+                            continue;
+                        };
+                        let mut file = SoundFileDef {
+                            file_name: String::new(),
+                            weight: None,
+                            volume_adjustment: None,
+                            random_balance: false,
+                            balance_adjustment: None,
+                            random_pitch: None,
+                            random_volume: None,
+                            delay: None,
+                            playlist: false,
+                            loop_start: None,
+                            loop_end: None,
+                            intro: None,
+                            outro: None,
+                            condition: None,
+                            season: None,
+                            mode: None,
+                        };
+
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"fileName" => file.file_name = attr_value.to_string(),
+                                b"weight" => {
+                                    file.weight = Some( attr_value.parse()? );
+                                }
+                                b"volumeAdjustment" => {
+                                    file.volume_adjustment = Some( attr_value.parse()? );
+                                }
+                                b"randomBalance" => {
+                                    file.random_balance = attr_value == "true";
+                                }
+                                b"balanceAdjustment" => {
+                                    file.balance_adjustment = Some( attr_value.parse()? );
+                                }
+                                b"randomPitch" => {
+                                    file.random_pitch = Some( attr_value.parse()? );
+                                }
+                                b"randomVolume" => {
+                                    file.random_volume = Some( attr_value.parse()? );
+                                }
+                                b"delay" => {
+                                    file.delay = Some( attr_value.parse()? );
+                                }
+                                b"playlist" => {
+                                    file.playlist = true;
+                                }
+                                b"loopStart" => {
+                                    file.loop_start = Some(LoopPointDef::Text(attr_value.to_string()));
+                                }
+                                b"loopEnd" => {
+                                    file.loop_end = Some(LoopPointDef::Text(attr_value.to_string()));
+                                }
+                                b"intro" => {
+                                    file.intro = Some(attr_value.to_string());
+                                }
+                                b"outro" => {
+                                    file.outro = Some(attr_value.to_string());
+                                }
+                                b"condition" => {
+                                    file.condition = Some(unescaped_value(&attr)?);
+                                }
+                                b"season" => {
+                                    file.season = Some(attr_value.to_string());
+                                }
+                                b"mode" => {
+                                    file.mode = Some(attr_value.to_string());
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown sound value: {}",
+                                        unsafe {std::str::from_utf8_unchecked(attr.key)}
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+                        trace!("  --SoundFile: {:?}", file.file_name);
+                        if in_step {
+                            // A step is always pushed before its files.
+                            sound.sequence.last_mut().unwrap().files.push(file);
+                        }
+                        else if in_sequence {
+                            sound.sequence.push(StepDef {
+                                files: vec![file],
+                                ..StepDef::default()
+                            });
+                        }
+                        else {
+                            sound.files.push(file);
+                        }
+                    }
+
+                    // <effect/>
+                    else if local_name == b"effect" {
+                        let sound = if let Some(sound) = current_sound.as_mut() {
+                            sound
+                        } else {
+                            warn!("An Effect in {:?} was declared outside of a valid Sound!", file_path);
+                            warn!("Will ignore this Effect.");
+                            continue;
+                        };
+                        let mut effect = EffectDef::default();
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"channel" => {
+                                    effect.channel = Some(attr_value.to_string());
+                                }
+                                b"lowpass" => {
+                                    effect.lowpass = Some( attr_value.parse()? );
+                                }
+                                b"highpass" => {
+                                    effect.highpass = Some( attr_value.parse()? );
+                                }
+                                b"eqLow" => {
+                                    effect.eq_low = Some( attr_value.parse()? );
+                                }
+                                b"eqMid" => {
+                                    effect.eq_mid = Some( attr_value.parse()? );
+                                }
+                                b"eqHigh" => {
+                                    effect.eq_high = Some( attr_value.parse()? );
+                                }
+                                b"reverb" => {
+                                    effect.reverb = Some( attr_value.parse()? );
+                                }
+                                b"transition" => {
+                                    effect.transition = Some( attr_value.parse()? );
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown effect value: {}",
+                                        unsafe {std::str::from_utf8_unchecked(attr.key)}
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+                        trace!("  --Effect: {:?}", effect.channel);
+                        sound.effects.push(effect);
+                    }
+
+                    // <variable/>
+                    else if local_name == b"variable" {
+                        let mut name: Option<String> = None;
+                        let mut default: Option<i64> = None;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"name" => {
+                                    name.replace(attr_value.to_string());
+                                }
+                                b"default" => {
+                                    default = Some( attr_value.parse()? );
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown variable value: {}",
+                                        unsafe {std::str::from_utf8_unchecked(attr.key)}
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+                        trace!("  Variable: {:?}", name);
+                        if let Some(name) = name {
+                            pack.variables.push(VariableDef { name, default });
+                        }
+                        else {
+                            warn!("A Variable in {:?} didn't specify a name.", file_path);
+                            warn!("Will ignore this Variable.");
+                        }
+                    }
+
+                    // <contextPattern/>
+                    else if local_name == b"contextPattern" {
+                        let mut event: Option<String> = None;
+                        let mut log_pattern: Option<String> = None;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"event" => {
+                                    event.replace(attr_value.to_string());
+                                }
+                                b"logPattern" => {
+                                    log_pattern.replace(unescaped_value(&attr)?);
+                                }
+                                _ => {
+                                    warn!(
+                                        "Unknown contextPattern value: {}",
+                                        unsafe {std::str::from_utf8_unchecked(attr.key)}
+                                    );
+                                    warn!("Will ignore this value.");
+                                }
+                            }
+                        }
+                        if let (Some(event), Some(log_pattern)) = (event, log_pattern) {
+                            pack.context_patterns.push(ContextPatternDef { event, log_pattern });
+                        }
+                        else {
+                            warn!("A ContextPattern in {:?} needs both an event and a logPattern.", file_path);
+                            warn!("Will ignore this ContextPattern.");
+                        }
+                    }
+
+                    else if local_name == b"channelSettings" {
+                        trace!("  ChannelSettings");
+                        in_channel_settings = true;
+                    }
+
+                    // <channelSetting/>
+                    else if local_name == b"channelSetting" {
+                        if !in_channel_settings {
+                            warn!("A ChannelSetting in {:?} was declared outside of ChannelSettings!", file_path);
+                            warn!("Will ignore this ChannelSetting.");
+                            continue;
+                        }
+                        trace!("  -ChannelSetting");
+                        let mut name: Option<String> = None;
+                        let mut play_type = None;
+                        let mut crossfade = None;
+                        let mut fade = None;
+                        let mut duck = None;
+                        let mut duck_attack = None;
+                        let mut duck_release = None;
+                        let mut ducks = None;
+                        let mut device = None;
+                        let mut limiter = None;
+                        let mut lowpass = None;
+                        let mut highpass = None;
+                        let mut eq_low = None;
+                        let mut eq_mid = None;
+                        let mut eq_high = None;
+                        let mut reverb = None;
+                        for attr in data.attributes() {
+                            let attr = attr?;
+                            let attr_value = unsafe {std::str::from_utf8_unchecked(&attr.value)};
+                            match attr.key {
+                                b"name" => {
+                                    trace!("  --name: {}", attr_value);
+                                    name.replace(attr_value.to_string());
+                                }
+                                b"playType" => {
+                                    trace!("  --play_type: {}", attr_value);
+                                    match attr_value {
+                                        "singleEager" => play_type = Some(ChannelPlayType::SingleEager),
+                                        "singleLazy" => play_type = Some(ChannelPlayType::SingleLazy),
+                                        "all" => play_type = Some(ChannelPlayType::All),
+                                        other => {
+                                            warn!("Unknown Channel PlayType: {}", other);
+                                            warn!("Will ignore this value.");
+                                        },
+                                    }
+                                }
+                                b"crossfade" => {
+                                    trace!("  --crossfade: {}", attr_value);
+                                    crossfade = Some( attr_value.parse()? );
+                                }
+                                b"fade" => {
+                                    trace!("  --fade: {}", attr_value);
+                                    fade = Some( attr_value.parse()? );
+                                }
+                                b"duck" => {
+                                    trace!("  --duck: {}", attr_value);
+                                    duck = Some( attr_value.parse()? );
+                                }
+                                b"duckAttack" => {
+                                    trace!("  --duck_attack: {}", attr_value);
+                                    duck_attack = Some( attr_value.parse()? );
+                                }
+                                b"duckRelease" => {
+                                    trace!("  --duck_release: {}", attr_value);
+                                    duck_release = Some( attr_value.parse()? );
+                                }
+                                b"ducks" => {
+                                    trace!("  --ducks: {}", attr_value);
+                                    ducks = Some(attr_value.to_string());
+                                }
+                                b"device" => {
+                                    trace!("  --device: {}", attr_value);
+                                    device = Some(unescaped_value(&attr)?);
+                                }
+                                b"limiter" => {
+                                    trace!("  --limiter: {}", attr_value);
+                                    limiter = Some( attr_value.parse()? );
+                                }
+                                b"lowpass" => {
+                                    trace!("  --lowpass: {}", attr_value);
+                                    lowpass = Some( attr_value.parse()? );
+                                }
+                                b"highpass" => {
+                                    trace!("  --highpass: {}", attr_value);
+                                    highpass = Some( attr_value.parse()? );
+                                }
+                                b"eqLow" => {
+                                    trace!("  --eq_low: {}", attr_value);
+                                    eq_low = Some( attr_value.parse()? );
+                                }
+                                b"eqMid" => {
+                                    trace!("  --eq_mid: {}", attr_value);
+                                    eq_mid = Some( attr_value.parse()? );
+                                }
+                                b"eqHigh" => {
+                                    trace!("  --eq_high: {}", attr_value);
+                                    eq_high = Some( attr_value.parse()? );
+                                }
+                                b"reverb" => {
+                                    trace!("  --reverb: {}", attr_value);
+                                    reverb = Some( attr_value.parse()? );
+                                }
+                                _ => ()
+                            }
+                        }
+                        if let Some(name) = name {
+                            pack.channel_settings.push(
+                                ChannelSettingDef {
+                                    name,
+                                    play_type,
+                                    crossfade,
+                                    fade,
+                                    duck,
+                                    duck_attack,
+                                    duck_release,
+                                    ducks,
+                                    device,
+                                    limiter,
+                                    lowpass,
+                                    highpass,
+                                    eq_low,
+                                    eq_mid,
+                                    eq_high,
+                                    reverb,
+                                }
+                            );
+                        }
+                        else {
+                            warn!("A ChannelSetting in {:?} didn't specify a channel name.", file_path);
+                            warn!("Will ignore this ChannelSetting.");
+                        }
+                    }
+                },
+
+                // </sound>, </sequence>, </step> or </channelSettings>
+                Ok(Event::End(data)) => {
+                    if data.local_name() == b"step" {
+                        in_step = false;
+                    }
+                    else if data.local_name() == b"sequence" {
+                        in_sequence = false;
+                    }
+                    else if current_sound.is_some() && data.local_name() == b"sound" {
+                        in_sequence = false;
+                        in_step = false;
+                        pack.sounds.push( current_sound.take()
+                            .ok_or("Tried to finish a Sound, even though there is no Sound!")?
+                        );
+                    }
+                    else if data.local_name() == b"channelSettings" {
+                        in_channel_settings = false;
+                    }
+                },
+
+                Ok(Event::Eof) => return Ok(pack),
+
+                Err(e) => {
+                    error!("Error parsing xml at position {}: {:?}", reader.buffer_position(), e);
+                    return Err(
+                        format!("Error parsing xml at position {}: {:?}", reader.buffer_position(), e).into()
+                    )
+                },
+
+                _ => () // Other Reader::Events aren't used, just ignore them.
+            }
+        }
+    }
+
+    /// Serialize into the given format.
+    pub fn to_string(&self, format: PackFormat) -> Result<String> {
+        match format {
+            PackFormat::Toml => Ok(toml::to_string_pretty(self)?),
+            PackFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            PackFormat::Xml => Err("Exporting to XML is not supported.".into()),
+        }
+    }
+}
+
+impl SoundDef {
+    /// Build a SoundEntry. `dir` is the directory of the file that declared this sound.
+    pub fn build(&self, dir: &Path) -> Result<SoundEntry> {
+        let pattern = Regex::new(&self.log_pattern)?;
+        let condition = self.condition.as_deref()
+            .map(Condition::parse)
+            .transpose()?;
+        let context_filter = ContextFilter::parse(
+            self.season.as_deref(),
+            self.mode.as_deref(),
+        )?;
+        let actions = VarAction::parse_all(
+            self.set.as_deref(),
+            self.clear.as_deref(),
+            self.increment.as_deref(),
+        )?;
+        let mut files = Vec::with_capacity(self.files.len());
+        let mut weights = Vec::with_capacity(self.files.len());
+        for file_def in self.files.iter() {
+            let file = self.build_file(file_def, dir)?;
+            weights.push(file.weight);
+            files.push(file);
+        }
+        let sequence = self.sequence.iter()
+            .map(|step_def| step_def.build(self, dir))
+            .collect::<Result<Vec<_>>>()?;
+        let mut effects = Vec::with_capacity(self.effects.len());
+        for effect_def in self.effects.iter() {
+            match effect_def.channel.as_ref().or(self.channel.as_ref()) {
+                Some(channel) => effects.push(effect_def.build(channel)),
+                None => {
+                    warn!("An Effect of sound {} doesn't have a channel.", self.log_pattern);
+                    warn!("Will ignore this Effect.");
+                }
+            }
+        }
+        if !sequence.is_empty() && self.r#loop == Some(LoopDef::Start) {
+            warn!("Sound {} has a sequence, but starts a loop.", self.log_pattern);
+            warn!("Will loop its soundFiles, and ignore the sequence.");
+        }
+        Ok(SoundEntry {
+            pattern,
+            channel: self.channel.as_deref().map(Box::from),
+            loop_attr: self.r#loop.map(|l| l == LoopDef::Start),
+            concurency: self.concurency,
+            max_instances: self.max_instances,
+            timeout: self.timeout,
+            probability: self.probability,
+            delay: self.delay,
+            crossfade: self.crossfade,
+            priority: self.priority.unwrap_or(0),
+            halt_on_match: self.halt_on_match,
+            random_balance: self.random_balance,
+            playback_threshold: self.playback_threshold.unwrap_or(4),
+            condition,
+            context_filter,
+            actions,
+            effects,
+            files,
+            weights,
+            sequence,
+            current_timeout: 0,
+            recent_call: 0,
+            instances: Instances::default(),
+        })
+    }
+
+    /// Build one of the sound's files, with the sound's randomBalance, randomPitch and randomVolume.
+    fn build_file(&self, file_def: &SoundFileDef, dir: &Path) -> Result<SoundFile> {
+        let mut file = file_def.build(dir)?;
+        file.random_balance |= self.random_balance;
+        if file_def.random_pitch.is_none() {
+            file.random_pitch = self.random_pitch.unwrap_or(0.0).abs();
+        }
+        if file_def.random_volume.is_none() {
+            file.random_volume = self.random_volume.unwrap_or(0.0).abs();
+        }
+        Ok(file)
+    }
+}
+
+impl SoundFileDef {
+    /// Build a SoundFile. `dir` is the directory of the file that declared this sound file.
+    pub fn build(&self, dir: &Path) -> Result<SoundFile> {
+        let path = dir.join(&self.file_name);
+        let r#type = if self.playlist {
+            let path_vec = parse_playlist(&path)?;
+            SoundFileType::IsPlaylist(path_vec)
+        } else {
+            SoundFileType::IsPath(path)
+        };
+        let mut loop_start = self.loop_start.as_ref().map(LoopPointDef::build).transpose()?;
+        let mut loop_end = self.loop_end.as_ref().map(LoopPointDef::build).transpose()?;
+        let empty_section = match (loop_start, loop_end) {
+            (Some(LoopPoint::Frames(start)), Some(LoopPoint::Frames(end)))
+            | (Some(LoopPoint::Millis(start)), Some(LoopPoint::Millis(end))) => start >= end,
+            _ => false,
+        };
+        if empty_section {
+            warn!("SoundFile {}'s loopStart isn't before its loopEnd.", self.file_name);
+            warn!("Will ignore its loop points.");
+            loop_start = None;
+            loop_end = None;
+        }
+        Ok(SoundFile {
+            r#type,
+            weight: self.weight.unwrap_or(100.0),
+            volume: db_to_gain(self.volume_adjustment.unwrap_or(0.0)),
+            random_balance: self.random_balance,
+            delay: self.delay.unwrap_or(0),
+            balance: self.balance_adjustment.unwrap_or(0.0),
+            random_pitch: self.random_pitch.unwrap_or(0.0).abs(),
+            random_volume: self.random_volume.unwrap_or(0.0).abs(),
+            loop_start,
+            loop_end,
+            intro: self.intro.as_ref().map(|intro| dir.join(intro)),
+            outro: self.outro.as_ref().map(|outro| dir.join(outro)),
+            condition: self.condition.as_deref()
+                .map(Condition::parse)
+                .transpose()?,
+            context_filter: ContextFilter::parse(
+                self.season.as_deref(),
+                self.mode.as_deref(),
+            )?,
+        })
+    }
+}
+
+impl StepDef {
+    /// Build a SequenceStep of `sound`. `dir` is the directory of the file that declared this step.
+    pub fn build(&self, sound: &SoundDef, dir: &Path) -> Result<SequenceStep> {
+        let files = self.files.iter()
+            .map(|file_def| sound.build_file(file_def, dir))
+            .collect::<Result<Vec<_>>>()?;
+        if files.is_empty() {
+            warn!("A Step doesn't have any soundFile.");
+            warn!("Will skip this Step.");
+        }
+        Ok(SequenceStep {
+            gap: self.gap.unwrap_or(0),
+            probability: self.probability,
+            weights: files.iter().map(|file| file.weight).collect(),
+            files,
+        })
+    }
+}
+
+impl EffectDef {
+    pub fn build(&self, channel: &str) -> EffectChange {
+        EffectChange {
+            channel: Box::from(channel),
+            lowpass: self.lowpass,
+            highpass: self.highpass,
+            eq_low: self.eq_low,
+            eq_mid: self.eq_mid,
+            eq_high: self.eq_high,
+            reverb: self.reverb.map(|reverb| (reverb / 100.0).clamp(0.0, 1.0)),
+            transition: self.transition.unwrap_or(DEFAULT_EFFECT_CHANGE_TRANSITION),
+        }
+    }
+}
+
+impl ChannelSettingDef {
+    pub fn build(&self) -> Result<ChannelSetting> {
+        Ok(ChannelSetting {
+            play_type: self.play_type.unwrap_or(ChannelPlayType::All),
+            crossfade: self.crossfade.unwrap_or(0),
+            fade: self.fade.unwrap_or(DEFAULT_FADE_LENGTH),
+            duck: DuckSetting {
+                amount: self.duck
+                    .map_or(DEFAULT_DUCK_AMOUNT, |duck| (duck / 100.0).clamp(0.0, 1.0)),
+                attack: self.duck_attack.unwrap_or(DEFAULT_DUCK_ATTACK),
+                release: self.duck_release.unwrap_or(DEFAULT_DUCK_RELEASE),
+            },
+            ducks: match &self.ducks {
+                Some(ducks) => parse_duck_rules(ducks)?,
+                None => Vec::new(),
+            },
+            device: self.device.as_deref().map(Box::from),
+            limiter: self.limiter,
+            effects: EffectSettings {
+                lowpass: self.lowpass,
+                highpass: self.highpass,
+                eq_low: self.eq_low.unwrap_or(0.0),
+                eq_mid: self.eq_mid.unwrap_or(0.0),
+                eq_high: self.eq_high.unwrap_or(0.0),
+                reverb: self.reverb.map_or(0.0, |reverb| (reverb / 100.0).clamp(0.0, 1.0)),
+            },
+        })
+    }
+}
+
+/// Get an attribute's value with XML escapes (`&lt;`, `&amp;`...) resolved.
+fn unescaped_value(attr: &quick_xml::events::attributes::Attribute) -> Result<String> {
+    Ok(String::from_utf8_lossy(&attr.unescaped_value()?).into_owned())
+}
+
+/// Traverse the soundpack in DFS, calling `func` on every soundpack file, once parsed.
+/// If a TOML or JSON file shares its name with an XML file in the same directory,
+/// the XML file is skipped, so packs can be migrated one file at a time.
+/// TOML and JSON files that aren't soundpack files, like an editor's config, are skipped.
+pub fn visit_dir(dir: &Path, func: &mut dyn FnMut(&Path, SoundpackDef)->Result<()>) -> Result<()> {
+    trace!("Directory: {:?}", dir);
+    match fs::read_dir(dir) {
+        Ok(entries) => for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if path.is_dir() {
+                visit_dir(&path, func)?;
+            } else if path.is_file() {
+                match PackFormat::from_path(&path) {
+                    Some(PackFormat::Xml) => {
+                        if let Some(other) = [PackFormat::Toml, PackFormat::Json].iter()
+                            .map(|f| path.with_extension(f.extension()))
+                            .find(|p| p.is_file())
+                        {
+                            info!("{} overrides {}, skipping it.", other.display(), path.display());
+                            continue;
+                        }
+                        func(&path, SoundpackDef::from_file(&path)?)?;
+                    }
+                    Some(_) => match SoundpackDef::from_file(&path) {
+                        Ok(pack) => func(&path, pack)?,
+                        Err(e) => {
+                            warn!("{}", e);
+                            warn!("Will ignore this file.");
+                        }
+                    }
+                    None => (),
+                }
+            }
+        }
+        Err(e) => {
+            warn!("Error while visiting {}: {}", dir.display(), e);
+            warn!("Will ignore this directory.");
+        }
+    }
+    Ok(())
+}
+
+/// Convert every XML file in the soundpack into the given format.
+/// The converted files are written next to the originals, and take precedence over them when loading.
+/// Files that already have a converted counterpart are left untouched.
+pub fn export_soundpack(sound_dir: &Path, format: PackFormat) -> Result<usize> {
+    fn visit_xml(dir: &Path, format: PackFormat, count: &mut usize) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                visit_xml(&path, format, count)?;
+            }
+            else if PackFormat::from_path(&path) == Some(PackFormat::Xml) {
+                let out_path = path.with_extension(format.extension());
+                if out_path.exists() {
+                    warn!("{} already exists.", out_path.display());
+                    warn!("Will not overwrite it.");
+                    continue;
+                }
+                let pack = SoundpackDef::from_xml(&path)?;
+                if pack.sounds.is_empty() && pack.channel_settings.is_empty()
+                && pack.variables.is_empty() && pack.context_patterns.is_empty() {
+                    trace!("{} is empty, skipping it.", path.display());
+                    continue;
+                }
+                fs::write(&out_path, pack.to_string(format)?)?;
+                info!("Exported {} to {}", path.display(), out_path.display());
+                *count += 1;
+            }
+        }
+        Ok(())
+    }
+    if format == PackFormat::Xml {
+        return Err("Exporting to XML is not supported.".into());
+    }
+    let mut count = 0;
+    visit_xml(sound_dir, format, &mut count)?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::test_util::test_dir;
+
+    const PACK_XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<sounds defaultAnsiFormat="%s">
+<channelSettings>
+    <channelSetting name="weather" playType="singleEager" crossfade="1500" duck="40" lowpass="8000" reverb="20"/>
+</channelSettings>
+<variable name="raining" default="1"/>
+<contextPattern event="siegeStart" logPattern="^An ambush!"/>
+<sound logPattern="(.+) cancels (.+): Interrupted by (.+)\." channel="misc" concurency="2" maxInstances="1"
+    timeout="500" probability="50" delay="100" priority="3" randomPitch="2" condition="raining == 1" set="wet=1">
+    <soundFile fileName="a.ogg" weight="2" volumeAdjustment="-5" balanceAdjustment="0.5"/>
+    <soundFile fileName="b.ogg" season="winter"/>
+    <effect lowpass="500" transition="2000"/>
+</sound>
+<sound logPattern="^It is raining\." channel="weather" loop="start" crossfade="3000">
+    <soundFile fileName="rain.ogg" loopStart="200ms" intro="rain_intro.ogg"/>
+</sound>
+<sound logPattern="^A sequence">
+    <sequence>
+        <step gap="250"><soundFile fileName="c.ogg"/></step>
+        <step probability="40"><soundFile fileName="d.ogg"/></step>
+    </sequence>
+</sound>
+</sounds>
+"#;
+
+    fn to_value(pack: &SoundpackDef) -> serde_json::Value {
+        serde_json::to_value(pack).unwrap()
+    }
+
+    #[test]
+    fn export_round_trip() {
+        for &format in [PackFormat::Toml, PackFormat::Json].iter() {
+            let dir = test_dir(&format!("export-{}", format.extension()));
+            let xml_path = dir.join("pack.xml");
+            fs::write(&xml_path, PACK_XML).unwrap();
+            let from_xml = SoundpackDef::from_file(&xml_path).unwrap();
+            assert_eq!(from_xml.sounds.len(), 3);
+
+            assert_eq!(export_soundpack(&dir, format).unwrap(), 1);
+            let exported = SoundpackDef::from_file(&xml_path.with_extension(format.extension())).unwrap();
+            assert_eq!(to_value(&exported), to_value(&from_xml));
+            // Exporting again doesn't overwrite the exported file.
+            assert_eq!(export_soundpack(&dir, format).unwrap(), 0);
+
+            // The exported file overrides the XML file when loading.
+            let mut loaded = Vec::new();
+            visit_dir(&dir, &mut |path, pack| {
+                loaded.push((path.to_path_buf(), to_value(&pack)));
+                Ok(())
+            }).unwrap();
+            assert_eq!(loaded, vec![(xml_path.with_extension(format.extension()), to_value(&from_xml))]);
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn unrelated_files_are_skipped() {
+        let dir = test_dir("unrelated");
+        fs::write(dir.join("pack.xml"), PACK_XML).unwrap();
+        fs::write(dir.join("package.json"), r#"{"name": "my-pack", "version": "1.0.0"}"#).unwrap();
+        fs::create_dir(dir.join(".vscode")).unwrap();
+        fs::write(dir.join(".vscode").join("settings.json"), "{ not json").unwrap();
+        fs::write(dir.join("rustfmt.toml"), "max_width = 100\n").unwrap();
+        fs::write(dir.join("readme.txt"), "Not a soundpack file.").unwrap();
+
+        let mut loaded = Vec::new();
+        visit_dir(&dir, &mut |path, pack| {
+            loaded.push((path.file_name().unwrap().to_owned(), pack.sounds.len()));
+            Ok(())
+        }).unwrap();
+        assert_eq!(loaded, vec![(std::ffi::OsString::from("pack.xml"), 3)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_xml_fails() {
+        let dir = test_dir("invalid-xml");
+        fs::write(dir.join("pack.xml"), "<sounds><sound logPattern=\"a\" timeout=\"soon\"/></sounds>").unwrap();
+        assert!(visit_dir(&dir, &mut |_, _| Ok(())).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,512 @@
+use super::*;
+
+/// Soundpack-declared variables, set by SoundEntries and read by conditions.
+/// Undeclared variables read as 0.
+#[derive(Default)]
+pub struct Variables {
+    /// Current values, sorted by name for the UI.
+    values: BTreeMap<Box<str>, i64>,
+    /// Whether a value changed since the last call to `take_changed`.
+    changed: bool,
+}
+
+impl Variables {
+    /// Declare a variable with its default value.
+    pub fn declare(&mut self, name: &str, default: i64) {
+        self.values.insert(Box::from(name), default);
+        self.changed = true;
+    }
+
+    #[inline]
+    pub fn is_declared(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    #[inline]
+    pub fn get(&self, name: &str) -> i64 {
+        self.values.get(name).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, name: &str, value: i64) {
+        match self.values.get_mut(name) {
+            Some(current) => if *current != value {
+                *current = value;
+                self.changed = true;
+            }
+            None => self.declare(name, value),
+        }
+    }
+
+    /// Apply an action triggered by a SoundEntry.
+    pub fn apply(&mut self, action: &VarAction) {
+        match action {
+            VarAction::Set(name, value) => {
+                trace!("  set {}={}", name, value);
+                self.set(name, *value);
+            }
+            VarAction::Increment(name, amount) => {
+                trace!("  increment {} by {}", name, amount);
+                let value = self.get(name).saturating_add(*amount);
+                self.set(name, value);
+            }
+        }
+    }
+
+    /// All the soundpack's variables and their values, if any changed since the last call.
+    /// The `game.*` variables are left out, the GameContext reports them itself.
+    pub fn take_changed(&mut self) -> Option<Vec<(Box<str>, i64)>> {
+        if self.changed {
+            self.changed = false;
+            Some(self.values.iter()
+                .filter(|(name, _)| !name.starts_with("game."))
+                .map(|(name, value)| (name.clone(), *value))
+                .collect())
+        } else {
+            None
+        }
+    }
+}
+
+/// What a SoundEntry does to a variable when it matches.
+#[derive(Clone, Debug)]
+pub enum VarAction {
+    /// `set="name=value"`, and `clear="name"` (sets to 0).
+    Set(Box<str>, i64),
+    /// `increment="name"` or `increment="name=amount"`.
+    Increment(Box<str>, i64),
+}
+
+impl VarAction {
+    #[inline]
+    pub fn name(&self) -> &str {
+        match self {
+            VarAction::Set(name, _) | VarAction::Increment(name, _) => name,
+        }
+    }
+
+    /// Parse `set`, `clear` and `increment` attributes. Each is a comma separated list.
+    pub fn parse_all(
+        set: Option<&str>,
+        clear: Option<&str>,
+        increment: Option<&str>,
+    ) -> Result<Vec<Self>> {
+        let mut actions = Vec::new();
+        for item in list_items(set) {
+            let (name, value) = split_assignment(item)?;
+            let value = value
+                .ok_or_else(|| format!("set=\"{}\" needs a value, like \"{}=1\".", item, name))?;
+            actions.push(VarAction::Set(name, value));
+        }
+        for item in list_items(clear) {
+            actions.push(VarAction::Set(check_name(item)?, 0));
+        }
+        for item in list_items(increment) {
+            let (name, amount) = split_assignment(item)?;
+            actions.push(VarAction::Increment(name, amount.unwrap_or(1)));
+        }
+        Ok(actions)
+    }
+}
+
+fn list_items(list: Option<&str>) -> impl Iterator<Item=&str> {
+    list.unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Split `name=value` or `name`.
+fn split_assignment(item: &str) -> Result<(Box<str>, Option<i64>)> {
+    let mut parts = item.splitn(2, '=');
+    let name = check_name(parts.next().unwrap_or(""))?;
+    let value = match parts.next() {
+        Some(value) => Some(value.trim().parse()
+            .map_err(|e| format!("Invalid value in \"{}\": {}", item, e))?),
+        None => None,
+    };
+    Ok((name, value))
+}
+
+fn check_name(name: &str) -> Result<Box<str>> {
+    let name = name.trim();
+    if !name.is_empty()
+    && !name.starts_with(|c: char| c.is_ascii_digit())
+    && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    {
+        Ok(Box::from(name))
+    } else {
+        Err(format!("Invalid variable name: \"{}\"", name).into())
+    }
+}
+
+/// A condition expression, parsed from a `condition` attribute.
+///
+/// Values are integers, and anything other than 0 counts as true.
+/// Supports variable names, integer literals, `true`/`false`, parentheses,
+/// comparisons (`== != < <= > >=`), `!`/`not`, `&&`/`and` and `||`/`or`.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    source: Box<str>,
+    expr: Expr,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Value(i64),
+    Variable(Box<str>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Box<Expr>, CompareOp, Box<Expr>),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum CompareOp {
+    Eq, Ne, Lt, Le, Gt, Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Int(i64),
+    Ident(Box<str>),
+    Compare(CompareOp),
+    Not, Neg, And, Or,
+    Open, Close,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self> {
+        let tokens = tokenize(source)
+            .map_err(|e| format!("Invalid condition \"{}\": {}", source, e))?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let expr = parser.parse_or()
+            .map_err(|e| format!("Invalid condition \"{}\": {}", source, e))?;
+        if parser.pos != tokens.len() {
+            return Err(format!("Invalid condition \"{}\": unexpected {:?}", source, tokens[parser.pos]).into())
+        }
+        Ok(Self {
+            source: Box::from(source),
+            expr,
+        })
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.source
+    }
+
+    #[inline]
+    pub fn eval(&self, variables: &Variables) -> bool {
+        self.expr.eval(variables) != 0
+    }
+
+    /// Names of every variable the condition reads.
+    pub fn variables(&self) -> Vec<&str> {
+        fn visit<'a>(expr: &'a Expr, names: &mut Vec<&'a str>) {
+            match expr {
+                Expr::Value(_) => (),
+                Expr::Variable(name) => names.push(name),
+                Expr::Not(e) | Expr::Neg(e) => visit(e, names),
+                Expr::And(a, b) | Expr::Or(a, b) | Expr::Compare(a, _, b) => {
+                    visit(a, names);
+                    visit(b, names);
+                }
+            }
+        }
+        let mut names = Vec::new();
+        visit(&self.expr, &mut names);
+        names
+    }
+}
+
+impl Expr {
+    fn eval(&self, variables: &Variables) -> i64 {
+        match self {
+            Expr::Value(value) => *value,
+            Expr::Variable(name) => variables.get(name),
+            Expr::Not(e) => (e.eval(variables) == 0) as i64,
+            Expr::Neg(e) => e.eval(variables).wrapping_neg(),
+            Expr::And(a, b) => (a.eval(variables) != 0 && b.eval(variables) != 0) as i64,
+            Expr::Or(a, b) => (a.eval(variables) != 0 || b.eval(variables) != 0) as i64,
+            Expr::Compare(a, op, b) => {
+                let (a, b) = (a.eval(variables), b.eval(variables));
+                let result = match op {
+                    CompareOp::Eq => a == b,
+                    CompareOp::Ne => a != b,
+                    CompareOp::Lt => a < b,
+                    CompareOp::Le => a <= b,
+                    CompareOp::Gt => a > b,
+                    CompareOp::Ge => a >= b,
+                };
+                result as i64
+            }
+        }
+    }
+}
+
+fn tokenize(source: &str) -> std::result::Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i+1).copied();
+        let (token, len) = match c {
+            ' ' | '\t' | '\n' | '\r' => { i += 1; continue }
+            '(' => (Token::Open, 1),
+            ')' => (Token::Close, 1),
+            '&' if next == Some('&') => (Token::And, 2),
+            '|' if next == Some('|') => (Token::Or, 2),
+            '=' if next == Some('=') => (Token::Compare(CompareOp::Eq), 2),
+            '!' if next == Some('=') => (Token::Compare(CompareOp::Ne), 2),
+            '<' if next == Some('=') => (Token::Compare(CompareOp::Le), 2),
+            '>' if next == Some('=') => (Token::Compare(CompareOp::Ge), 2),
+            '!' => (Token::Not, 1),
+            '-' => (Token::Neg, 1),
+            '<' => (Token::Compare(CompareOp::Lt), 1),
+            '>' => (Token::Compare(CompareOp::Gt), 1),
+            c if c.is_ascii_digit() => {
+                let len = chars[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+                let text: String = chars[i..i+len].iter().collect();
+                if let Some(&c) = chars.get(i+len).filter(|c| c.is_ascii_alphabetic() || **c == '_' || **c == '.') {
+                    return Err(format!("unexpected character '{}' after number {}", c, text))
+                }
+                let value = text.parse().map_err(|e| format!("number {} {}", text, e))?;
+                (Token::Int(value), len)
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let len = chars[i..].iter()
+                    .take_while(|c| c.is_ascii_alphanumeric() || **c == '_' || **c == '.')
+                    .count();
+                let word: String = chars[i..i+len].iter().collect();
+                let token = match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    "true" => Token::Int(1),
+                    "false" => Token::Int(0),
+                    _ => Token::Ident(word.into_boxed_str()),
+                };
+                (token, len)
+            }
+            other => return Err(format!("unexpected character '{}'", other)),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the tokens.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    #[inline]
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn parse_or(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> std::result::Result<Expr, String> {
+        let mut expr = self.parse_compare()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_compare()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_compare(&mut self) -> std::result::Result<Expr, String> {
+        let expr = self.parse_unary()?;
+        if let Some(Token::Compare(op)) = self.peek() {
+            self.pos += 1;
+            let rhs = self.parse_unary()?;
+            return Ok(Expr::Compare(Box::new(expr), *op, Box::new(rhs)))
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> std::result::Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::Neg) => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.parse_unary()?)))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> std::result::Result<Expr, String> {
+        let token = self.peek().ok_or("unexpected end of condition")?;
+        self.pos += 1;
+        match token {
+            Token::Int(value) => Ok(Expr::Value(*value)),
+            Token::Ident(name) => Ok(Expr::Variable(name.clone())),
+            Token::Open => {
+                let expr = self.parse_or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("missing ')'".to_string())
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            other => Err(format!("unexpected {:?}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables(values: &[(&str, i64)]) -> Variables {
+        let mut variables = Variables::default();
+        for &(name, value) in values {
+            variables.declare(name, value);
+        }
+        variables
+    }
+
+    fn eval(source: &str, values: &[(&str, i64)]) -> i64 {
+        Condition::parse(source).unwrap().expr.eval(&variables(values))
+    }
+
+    fn parse_error(source: &str) -> String {
+        Condition::parse(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn precedence() {
+        // && binds tighter than ||.
+        assert_eq!(eval("a || b && c", &[("a", 1), ("b", 0), ("c", 0)]), 1);
+        assert_eq!(eval("(a || b) && c", &[("a", 1), ("b", 0), ("c", 0)]), 0);
+        assert_eq!(eval("a && b || c", &[("a", 0), ("b", 1), ("c", 1)]), 1);
+        // ! binds tighter than comparisons: (!a) == b.
+        assert_eq!(eval("!a == b", &[("a", 0), ("b", 1)]), 1);
+        assert_eq!(eval("!a == b", &[("a", 2), ("b", 0)]), 1);
+        assert_eq!(eval("!(a == b)", &[("a", 2), ("b", 0)]), 1);
+        assert_eq!(eval("!!a", &[("a", 5)]), 1);
+        // Comparisons bind tighter than && and ||.
+        assert_eq!(eval("a < 3 && b >= 2 || c != 0", &[("a", 2), ("b", 2), ("c", 0)]), 1);
+        assert_eq!(eval("a <= 1 || b > 2", &[("a", 2), ("b", 2)]), 0);
+    }
+
+    #[test]
+    fn unary_minus() {
+        assert_eq!(eval("-a", &[("a", 3)]), -3);
+        assert_eq!(eval("--a", &[("a", 3)]), 3);
+        assert_eq!(eval("a > -2", &[("a", -1)]), 1);
+        assert_eq!(eval("-a < 0", &[("a", 1)]), 1);
+        assert_eq!(eval("-(a)", &[("a", i64::MIN)]), i64::MIN);
+    }
+
+    #[test]
+    fn keywords() {
+        assert_eq!(eval("a and b", &[("a", 1), ("b", 2)]), 1);
+        assert_eq!(eval("a and not b", &[("a", 1), ("b", 2)]), 0);
+        assert_eq!(eval("a or b", &[("a", 0), ("b", 0)]), 0);
+        assert_eq!(eval("true", &[]), 1);
+        assert_eq!(eval("false or not false", &[]), 1);
+        assert_eq!(eval("a == true", &[("a", 1)]), 1);
+        // Keywords are whole words only.
+        assert_eq!(eval("android", &[("android", 4)]), 4);
+        assert_eq!(eval("nothing || order", &[("nothing", 0), ("order", 1)]), 1);
+    }
+
+    #[test]
+    fn undeclared_variables() {
+        assert_eq!(eval("missing", &[]), 0);
+        assert_eq!(eval("missing == 0 && !game.season", &[]), 1);
+        let condition = Condition::parse("raining && (game.season == 4 || snow_level > 2)").unwrap();
+        assert_eq!(condition.variables(), vec!["raining", "game.season", "snow_level"]);
+        assert!(!condition.eval(&Variables::default()));
+        assert!(condition.eval(&variables(&[("raining", 1), ("snow_level", 3)])));
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(eval("9223372036854775807", &[]), i64::MAX);
+        assert_eq!(eval("-9223372036854775807", &[]), -i64::MAX);
+        assert!(parse_error("9223372036854775808").contains("number 9223372036854775808"));
+        assert!(parse_error("a > 99999999999999999999").contains("number"));
+        assert_eq!(eval("007 == 7", &[]), 1);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse_error("a ==").contains("unexpected end of condition"));
+        assert!(parse_error("(a").contains("missing ')'"));
+        assert!(parse_error("a)").contains("unexpected Close"));
+        assert!(parse_error("a b").contains("unexpected Ident"));
+        assert!(parse_error("1abc").contains("unexpected character 'a' after number 1"));
+        assert!(parse_error("a = 1").contains("unexpected character '='"));
+        assert!(parse_error("a & b").contains("unexpected character '&'"));
+        assert!(parse_error("a < b < c").contains("unexpected Compare"));
+        assert!(parse_error("").contains("unexpected end of condition"));
+        assert!(parse_error("()").contains("unexpected Close"));
+        assert!(parse_error("&& a").contains("unexpected And"));
+    }
+
+    #[test]
+    fn actions() {
+        let actions = VarAction::parse_all(
+            Some("raining=1, depth = -3,"),
+            Some(" snow ,"),
+            Some("kills, wave=2"),
+        ).unwrap();
+        let mut variables = variables(&[("kills", i64::MAX - 1)]);
+        for action in actions.iter() {
+            variables.apply(action);
+        }
+        assert_eq!(variables.get("raining"), 1);
+        assert_eq!(variables.get("depth"), -3);
+        assert_eq!(variables.get("snow"), 0);
+        assert!(variables.is_declared("snow"));
+        assert_eq!(variables.get("kills"), i64::MAX);
+        assert_eq!(variables.get("wave"), 2);
+        // Incrementing saturates.
+        variables.apply(&actions[3]);
+        assert_eq!(variables.get("kills"), i64::MAX);
+        assert_eq!(
+            actions.iter().map(VarAction::name).collect::<Vec<_>>(),
+            vec!["raining", "depth", "snow", "kills", "wave"]
+        );
+        assert!(VarAction::parse_all(None, Some(""), Some(" , ")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn action_errors() {
+        let error = |set, clear, increment| VarAction::parse_all(set, clear, increment)
+            .unwrap_err()
+            .to_string();
+        // Missing values.
+        assert!(error(Some("raining"), None, None).contains("needs a value"));
+        assert!(error(Some("raining="), None, None).contains("Invalid value"));
+        assert!(error(None, None, Some("kills=")).contains("Invalid value"));
+        assert!(error(Some("raining=yes"), None, None).contains("Invalid value"));
+        assert!(error(Some("a=99999999999999999999"), None, None).contains("Invalid value"));
+        // Bad names.
+        assert!(error(Some("1a=1"), None, None).contains("Invalid variable name"));
+        assert!(error(Some("=1"), None, None).contains("Invalid variable name"));
+        assert!(error(Some("my var=1"), None, None).contains("Invalid variable name"));
+        assert!(error(None, Some("a=1"), None).contains("Invalid variable name"));
+        assert!(error(None, Some("a-b"), None).contains("Invalid variable name"));
+        assert!(error(None, None, Some("wave!")).contains("Invalid variable name"));
+    }
+}
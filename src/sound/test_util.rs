@@ -0,0 +1,124 @@
+//! Helpers shared by the tests.
+use super::*;
+use std::sync::Mutex;
+use std::cell::RefCell;
+
+/// A new, empty directory for a test.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("soundsense-rs-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+thread_local! {
+    /// Output devices of the Test backend, plugged in by this thread's test. The first one is the default.
+    static DEVICES: RefCell<Vec<TestSink>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An output device that only reads its sources when the test tells it to.
+#[derive(Clone)]
+pub struct TestSink {
+    name: Box<str>,
+    state: Arc<Mutex<TestSinkState>>,
+}
+
+#[derive(Default)]
+struct TestSinkState {
+    sources: Vec<(BoxedSource, SinkStatus)>,
+    /// Whether the device was unplugged. Sources played on it fail at once.
+    failed: bool,
+}
+
+impl TestSink {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            state: Arc::default(),
+        }
+    }
+
+    /// Read `frames` frames from every source, as the device would, and return their mix.
+    /// Sources that end are dropped.
+    pub fn read(&self, frames: usize) -> Vec<f32> {
+        let len = frames * DEFAULT_CHANNELS as usize;
+        let mut mix = vec![0.0; len];
+        self.state.lock().unwrap().sources.retain_mut(|(source, status)| {
+            status.mark_read();
+            let mut read = 0;
+            for (out, sample) in mix.iter_mut().zip(source.by_ref()) {
+                *out += sample;
+                read += 1;
+            }
+            read == len
+        });
+        mix
+    }
+
+    /// Drop every source and report the failure, as a device being unplugged.
+    pub fn fail(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.failed = true;
+        for (_, status) in state.sources.drain(..) {
+            status.fail();
+        }
+    }
+}
+
+impl AudioSink for TestSink {
+    fn format(&self) -> (u16, u32) {
+        (DEFAULT_CHANNELS, DEFAULT_SAMPLE_RATE)
+    }
+
+    fn device_name(&self) -> Option<Box<str>> {
+        Some(self.name.clone())
+    }
+
+    fn play(&self, source: BoxedSource) -> SinkStatus {
+        let status = SinkStatus::new();
+        let mut state = self.state.lock().unwrap();
+        if state.failed {
+            status.fail();
+        } else {
+            state.sources.push((source, status.clone()));
+        }
+        status
+    }
+}
+
+/// Plug in a device of the Test backend, returning it.
+pub fn plug(name: &str) -> TestSink {
+    let sink = TestSink::new(name);
+    DEVICES.with(|devices| devices.borrow_mut().push(sink.clone()));
+    sink
+}
+
+/// Unplug a device of the Test backend. Everything it played is dropped.
+pub fn unplug(name: &str) {
+    DEVICES.with(|devices| devices.borrow_mut().retain(|sink| {
+        if sink.name.as_ref() == name {
+            sink.fail();
+        }
+        sink.name.as_ref() != name
+    }));
+}
+
+pub fn device_names() -> Vec<Box<str>> {
+    DEVICES.with(|devices| devices.borrow().iter().map(|sink| sink.name.clone()).collect())
+}
+
+pub fn default_device_name() -> Option<Box<str>> {
+    DEVICES.with(|devices| devices.borrow().first().map(|sink| sink.name.clone()))
+}
+
+pub fn find_device(name: &str) -> Option<TestSink> {
+    DEVICES.with(|devices| devices.borrow().iter().find(|sink| sink.name.as_ref() == name).cloned())
+}
+
+/// Find the named device, or the default one, as `find_output_device` does.
+pub fn find_device_or_default(name: Option<&str>) -> Result<TestSink> {
+    name.and_then(find_device)
+        .or_else(|| DEVICES.with(|devices| devices.borrow().first().cloned()))
+        .ok_or_else(|| "Failed to find any audio output device.".into())
+}
@@ -0,0 +1,251 @@
+use super::*;
+use std::sync::Mutex;
+use std::cmp::Ordering as CmpOrdering;
+
+/// Default number of oneshots playing at once, over every channel.
+pub const DEFAULT_MAX_VOICES: usize = 32;
+
+/// Struct responsible for one playing oneshot source.
+pub struct Voice {
+    /// This volume is independent from the channel's and total volumes, applied by the buses.
+    pub volume: VolumeLock,
+    /// Whether the source is stopped.
+    pub stopped: AtomicBool,
+    /// Marker to check whether the sound has stopped playing.
+    pub count: Arc<AtomicUsize>,
+    /// Priority of the sound that played it. Higher is kept longer.
+    priority: i32,
+    /// When the voice started, compared to the others. Lower is older.
+    serial: u64,
+    /// Volume of the source, with its loudness normalization.
+    gain: f32,
+    /// Volume of the channel playing it.
+    channel_volume: VolumeLock,
+}
+
+impl Voice {
+    #[inline]
+    pub fn is_playing(&self) -> bool {
+        !self.stopped.load(Ordering::Relaxed)
+            && self.count.load(Ordering::Relaxed) == 1
+    }
+
+    #[inline]
+    pub fn priority(&self) -> i32 {
+        self.priority
+    }
+
+    /// How loud the voice plays, before the total volume.
+    #[inline]
+    fn loudness(&self) -> f32 {
+        self.gain * self.volume.get() * self.channel_volume.get()
+    }
+
+    /// Order in which voices are stolen: lowest priority, then quietest, then oldest.
+    fn steal_order(&self, other: &Self) -> CmpOrdering {
+        self.priority.cmp(&other.priority)
+            .then_with(|| self.loudness().partial_cmp(&other.loudness()).unwrap_or(CmpOrdering::Equal))
+            .then_with(|| self.serial.cmp(&other.serial))
+    }
+}
+
+struct PoolState {
+    /// Voices playing, over every channel.
+    voices: Vec<Arc<Voice>>,
+    /// Maximum number of voices. 0 is unlimited.
+    max_voices: usize,
+    /// Serial of the next voice.
+    next_serial: u64,
+}
+
+/// Keeps count of the oneshots playing on every channel, and stops the least important ones
+/// when there are too many. Loops don't count, and are never stopped.
+#[derive(Clone)]
+pub struct VoicePool(Arc<Mutex<PoolState>>);
+
+impl VoicePool {
+    /// Create a pool of `max_voices` voices. 0 is unlimited.
+    pub fn new(max_voices: usize) -> Self {
+        Self(Arc::new(Mutex::new(PoolState {
+            voices: Vec::new(),
+            max_voices,
+            next_serial: 0,
+        })))
+    }
+
+    /// Make room for a voice of this priority, stealing one if all voices are used.
+    /// The stolen voice fades out, as if skipped.
+    /// Returns false if every voice belongs to a sound of higher priority.
+    pub fn make_room(&self, priority: i32) -> bool {
+        let mut state = self.0.lock().unwrap();
+        state.voices.retain(|voice| voice.is_playing());
+        if state.max_voices == 0 || state.voices.len() < state.max_voices {
+            return true
+        }
+        let victim = state.voices.iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.steal_order(b))
+            .map(|(idx, voice)| (idx, voice.priority));
+        match victim {
+            Some((idx, victim_priority)) if victim_priority <= priority => {
+                trace!("   voice limit of {} reached: stealing a voice of priority {}",
+                    state.max_voices, victim_priority);
+                let voice = state.voices.swap_remove(idx);
+                voice.stopped.store(true, Ordering::SeqCst);
+                true
+            }
+            Some((_, victim_priority)) => {
+                trace!("   can't play: voice limit of {} reached, lowest priority playing {}",
+                    state.max_voices, victim_priority);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Create a voice, counted by the pool until it stops.
+    pub fn add_voice(&self, priority: i32, gain: f32, channel_volume: VolumeLock) -> Arc<Voice> {
+        let mut state = self.0.lock().unwrap();
+        let voice = Arc::new(Voice {
+            volume: VolumeLock::new(),
+            stopped: AtomicBool::new(false),
+            count: Arc::new(AtomicUsize::new(1)),
+            priority,
+            serial: state.next_serial,
+            gain,
+            channel_volume,
+        });
+        state.next_serial += 1;
+        state.voices.push(voice.clone());
+        voice
+    }
+}
+
+#[derive(Default)]
+struct InstancesState {
+    /// Voices of the sound, playing or stopped.
+    voices: Vec<Arc<Voice>>,
+    /// Number of oneshots of the sound waiting for their delay.
+    scheduled: usize,
+}
+
+/// Voices played by one sound, telling how many instances of it are playing.
+/// Oneshots waiting for their delay count as instances too.
+#[derive(Clone, Default)]
+pub struct Instances(Arc<Mutex<InstancesState>>);
+
+impl Instances {
+    /// Number of the sound's voices still playing, or waiting to play.
+    pub fn count(&self) -> usize {
+        let mut state = self.0.lock().unwrap();
+        state.voices.retain(|voice| voice.is_playing());
+        state.voices.len() + state.scheduled
+    }
+
+    #[inline]
+    pub fn add(&self, voice: Arc<Voice>) {
+        self.0.lock().unwrap().voices.push(voice);
+    }
+
+    /// Count an instance waiting for its delay, until the returned instance is dropped.
+    pub fn schedule(&self) -> ScheduledInstance {
+        self.0.lock().unwrap().scheduled += 1;
+        ScheduledInstance(self.clone())
+    }
+}
+
+/// An instance of a sound waiting for its delay.
+/// It stops counting once dropped, whether it was played or not.
+pub struct ScheduledInstance(Instances);
+
+impl ScheduledInstance {
+    #[inline]
+    pub fn instances(&self) -> &Instances {
+        &self.0
+    }
+}
+
+impl Drop for ScheduledInstance {
+    fn drop(&mut self) {
+        (self.0).0.lock().unwrap().scheduled -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(pool: &VoicePool, priority: i32, gain: f32) -> Arc<Voice> {
+        pool.add_voice(priority, gain, VolumeLock::new())
+    }
+
+    #[test]
+    fn steals_lowest_priority_then_quietest_then_oldest() {
+        let pool = VoicePool::new(3);
+        let loud = add(&pool, 0, 1.0);
+        let important = add(&pool, 1, 0.1);
+        let quiet = add(&pool, 0, 0.5);
+        assert!(pool.make_room(0));
+        assert!(!quiet.is_playing());
+        assert!(loud.is_playing() && important.is_playing());
+
+        let newer = add(&pool, 0, 1.0);
+        assert!(pool.make_room(0));
+        assert!(!loud.is_playing());
+        assert!(newer.is_playing() && important.is_playing());
+
+        // The voice's own volume counts, as well as its channel's.
+        newer.volume.set(0.01);
+        let _louder = add(&pool, 0, 1.0);
+        assert!(pool.make_room(1));
+        assert!(!newer.is_playing());
+    }
+
+    #[test]
+    fn refuses_when_every_voice_is_more_important() {
+        let pool = VoicePool::new(2);
+        let voices = [add(&pool, 5, 1.0), add(&pool, 5, 1.0)];
+        assert!(!pool.make_room(1));
+        assert!(voices.iter().all(|voice| voice.is_playing()));
+        // Equal priorities can be stolen.
+        assert!(pool.make_room(5));
+        assert!(!voices[0].is_playing());
+        assert!(voices[1].is_playing());
+    }
+
+    #[test]
+    fn ended_voices_free_their_place() {
+        let pool = VoicePool::new(1);
+        let voice = add(&pool, 5, 1.0);
+        assert!(!pool.make_room(0));
+        // As source::Done does when the source ends.
+        voice.count.fetch_sub(1, Ordering::SeqCst);
+        assert!(pool.make_room(0));
+    }
+
+    #[test]
+    fn unlimited() {
+        let pool = VoicePool::new(0);
+        let voices: Vec<Arc<Voice>> = (0..100).map(|i| add(&pool, i, 1.0)).collect();
+        assert!(pool.make_room(-1));
+        assert!(voices.iter().all(|voice| voice.is_playing()));
+    }
+
+    #[test]
+    fn instances_count_playing_and_scheduled() {
+        let pool = VoicePool::new(0);
+        let instances = Instances::default();
+        let voice = add(&pool, 0, 1.0);
+        instances.add(voice.clone());
+        let scheduled = instances.schedule();
+        let other = instances.schedule();
+        assert_eq!(instances.count(), 3);
+        // A scheduled oneshot dropped before playing releases its slot.
+        drop(other);
+        assert_eq!(instances.count(), 2);
+        voice.stopped.store(true, Ordering::SeqCst);
+        assert_eq!(instances.count(), 1);
+        drop(scheduled);
+        assert_eq!(instances.count(), 0);
+    }
+}
@@ -5,6 +5,7 @@ use std::sync::Mutex;
 use crossbeam::channel::{Sender, Receiver};
 use web_view::*;
 use crate::message::{SoundMessage, UIMessage};
+use crate::sound::{StereoSettings, EffectSettings};
 use tinyfiledialogs as tfd;
 
 /// The UI thread function.
@@ -183,6 +184,60 @@ r"A sound-engine utility for Dwarf Fortress, written in Rust
                 other => {
                     let parts: Vec<&str> = other.split(':').collect();
                     match parts[0] {
+                        "change_device" => {
+                            // Device names can contain ':', so take everything after the first one.
+                            let device_name = &other["change_device:".len()..];
+                            let device_name: Option<Box<str>> = if device_name.is_empty() {
+                                None
+                            } else {
+                                Some(device_name.into())
+                            };
+                            let mut conf_path = dirs::config_dir()
+                                .expect("Failed to get configuration directory.");
+                            conf_path.push("soundsense-rs");
+                            if !conf_path.is_dir() {
+                                fs::create_dir(&conf_path)
+                                    .expect("Failed to create soundsense-rs config directory.");
+                            }
+                            conf_path.push("default-device.ini");
+                            if let Some(name) = &device_name {
+                                let mut conf_file = fs::File::create(conf_path)
+                                    .expect("Failed to create default-device.ini file.");
+                                writeln!(conf_file, "device={}", name).unwrap();
+                            }
+                            else if conf_path.is_file() {
+                                fs::remove_file(conf_path)
+                                    .expect("Failed to delete default-device.ini file.");
+                            }
+                            sound_tx.send(
+                                SoundMessage::ChangeDevice(device_name)
+                            ).unwrap();
+                        }
+                        "change_stereo" => {
+                            let width: f32 = parts[1].parse().unwrap();
+                            let mono = parts[2] == "true";
+                            let swap = parts[3] == "true";
+                            let mut conf_path = dirs::config_dir()
+                                .expect("Failed to get configuration directory.");
+                            conf_path.push("soundsense-rs");
+                            if !conf_path.is_dir() {
+                                fs::create_dir(&conf_path)
+                                    .expect("Failed to create soundsense-rs config directory.");
+                            }
+                            conf_path.push("stereo.ini");
+                            let mut conf_file = fs::File::create(conf_path)
+                                .expect("Failed to create stereo.ini file.");
+                            writeln!(conf_file, "width={}", width).unwrap();
+                            writeln!(conf_file, "mono={}", mono).unwrap();
+                            writeln!(conf_file, "swap={}", swap).unwrap();
+                            sound_tx.send(
+                                SoundMessage::ChangeStereo(StereoSettings {
+                                    width: width * 0.01,
+                                    mono,
+                                    swap,
+                                })
+                            ).unwrap();
+                        }
                         "change_volume" => {
                             let channel_name: Box<str> = parts[1].into();
                             let channel_volume: f32 = parts[2].parse().unwrap();
@@ -197,6 +252,34 @@ r"A sound-engine utility for Dwarf Fortress, written in Rust
                                 SoundMessage::ThresholdChange(channel_name, channel_threshold)
                             ).unwrap();
                         }
+                        "change_channel_device" => {
+                            let channel_name: Box<str> = parts[1].into();
+                            // Device names can contain ':', so take everything after the channel name.
+                            let device_name = other.splitn(3, ':').nth(2).unwrap_or("");
+                            let device_name: Option<Box<str>> = if device_name.is_empty() {
+                                None
+                            } else {
+                                Some(device_name.into())
+                            };
+                            sound_tx.send(
+                                SoundMessage::ChangeChannelDevice(channel_name, device_name)
+                            ).unwrap();
+                        }
+                        "change_effects" => {
+                            let channel_name: Box<str> = parts[1].into();
+                            // Filters are empty when they're off.
+                            let cutoff = |part: &str| part.parse::<f32>().ok();
+                            sound_tx.send(
+                                SoundMessage::ChangeChannelEffects(channel_name, EffectSettings {
+                                    lowpass: cutoff(parts[2]),
+                                    highpass: cutoff(parts[3]),
+                                    eq_low: parts[4].parse().unwrap(),
+                                    eq_mid: parts[5].parse().unwrap(),
+                                    eq_high: parts[6].parse().unwrap(),
+                                    reverb: parts[7].parse::<f32>().unwrap() * 0.01,
+                                })
+                            ).unwrap();
+                        }
                         "skip_current_sound" => {
                             let channel_name: Box<str> = parts[1].into();
                             sound_tx.send(
@@ -236,6 +319,9 @@ r"A sound-engine utility for Dwarf Fortress, written in Rust
             match ui_message {
                 UIMessage::LoadedSoundpack(channel_names) => {
                     clear_sliders(&mut webview);
+                    set_variables(&mut webview, &[]);
+                    set_game_context(&mut webview, &[]);
+                    set_cache_stats(&mut webview, &[]);
                     for name in channel_names.iter() {
                         add_slider(&mut webview, name);
                     }
@@ -258,6 +344,36 @@ r"A sound-engine utility for Dwarf Fortress, written in Rust
                 UIMessage::ChannelWasPlayPaused(name, is_paused) => {
                     set_slider_head(&mut webview, &name, is_paused);
                 }
+                UIMessage::NowPlaying(name, title) => {
+                    set_now_playing(&mut webview, &name, &title);
+                }
+                UIMessage::GameContextChanged(entries) => {
+                    set_game_context(&mut webview, &entries);
+                }
+                UIMessage::VariablesChanged(variables) => {
+                    set_variables(&mut webview, &variables);
+                }
+                UIMessage::OutputDevices(names, current) => {
+                    set_devices(&mut webview, &names, current.as_deref());
+                }
+                UIMessage::OutputDeviceLost(lost, current) => {
+                    device_lost(&mut webview, &lost, current.as_deref());
+                }
+                UIMessage::ChannelDevices(entries) => {
+                    set_channel_devices(&mut webview, &entries);
+                }
+                UIMessage::ChannelEffects(entries) => {
+                    set_channel_effects(&mut webview, &entries);
+                }
+                UIMessage::SampleCacheStats(entries) => {
+                    set_cache_stats(&mut webview, &entries);
+                }
+                UIMessage::StereoChanged(settings) => {
+                    set_stereo(&mut webview, settings);
+                }
+                UIMessage::LimiterReduction(db) => {
+                    set_limiter_reduction(&mut webview, db);
+                }
                 UIMessage::SoundThreadPanicked(name, text) => {
                     clear_sliders(&mut webview);
                     add_error(&mut webview, &name, &text);
@@ -299,6 +415,79 @@ fn set_slider_head(webview: &mut WebView<()>, channel_name: &str, is_paused: boo
         )).unwrap();
     }
 }
+/// show the title of the sound the channel's loop is playing.
+fn set_now_playing(webview: &mut WebView<()>, channel_name: &str, title: &str) {
+    // Titles come from playlists, so they need to be escaped.
+    webview.eval(&format!(
+        r#"setNowPlaying("{}", {})"#,
+        channel_name,
+        serde_json::to_string(title).unwrap()
+    )).unwrap();
+}
+/// show the soundpack's variables and their values.
+fn set_variables(webview: &mut WebView<()>, variables: &[(Box<str>, i64)]) {
+    webview.eval(&format!(
+        "setVariables({})",
+        serde_json::to_string(variables).unwrap()
+    )).unwrap();
+}
+/// show the season, year, game mode and siege.
+fn set_game_context(webview: &mut WebView<()>, entries: &[(Box<str>, Box<str>)]) {
+    webview.eval(&format!(
+        "setGameContext({})",
+        serde_json::to_string(entries).unwrap()
+    )).unwrap();
+}
+/// show the audio output devices, and which one is chosen.
+fn set_devices(webview: &mut WebView<()>, names: &[Box<str>], current: Option<&str>) {
+    webview.eval(&format!(
+        "setDevices({}, {})",
+        serde_json::to_string(names).unwrap(),
+        serde_json::to_string(&current).unwrap()
+    )).unwrap();
+}
+/// tell the user the output device was lost, and which one is used instead.
+fn device_lost(webview: &mut WebView<()>, lost: &str, current: Option<&str>) {
+    webview.eval(&format!(
+        "deviceLost({}, {})",
+        serde_json::to_string(lost).unwrap(),
+        serde_json::to_string(&current).unwrap()
+    )).unwrap();
+}
+/// show the output device each channel is routed to.
+fn set_channel_devices(webview: &mut WebView<()>, entries: &[(Box<str>, Option<Box<str>>)]) {
+    webview.eval(&format!(
+        "setChannelDevices({})",
+        serde_json::to_string(entries).unwrap()
+    )).unwrap();
+}
+/// show the sample cache's statistics.
+fn set_cache_stats(webview: &mut WebView<()>, entries: &[(Box<str>, Box<str>)]) {
+    webview.eval(&format!(
+        "setCacheStats({})",
+        serde_json::to_string(entries).unwrap()
+    )).unwrap();
+}
+/// show the filters, equalizer and reverb of each channel.
+fn set_channel_effects(webview: &mut WebView<()>, entries: &[(Box<str>, EffectSettings)]) {
+    webview.eval(&format!(
+        "setChannelEffects({})",
+        serde_json::to_string(entries).unwrap()
+    )).unwrap();
+}
+/// show the stereo width, in percent, and whether mono and swap are on.
+fn set_stereo(webview: &mut WebView<()>, settings: StereoSettings) {
+    webview.eval(&format!(
+        "setStereo({}, {}, {})",
+        (settings.width * 100.0).round(),
+        settings.mono,
+        settings.swap
+    )).unwrap();
+}
+/// show the gain reduction of the master limiter, in decibels.
+fn set_limiter_reduction(webview: &mut WebView<()>, db: f32) {
+    webview.eval(&format!("setLimiterReduction({})", db)).unwrap();
+}
 /// display a notice for the user.
 fn add_alert(webview: &mut WebView<()>, name: &str, color: &str, text: &str) {
     webview.eval(&format!(
@@ -25,6 +25,35 @@ function addSlider(channel_name) {
             },
             false
         );
+    let device_selector = document.getElementById(channel_name+"_device_selector");
+    if (device_selector != null) {
+        fillDeviceSelector(device_selector);
+        device_selector.addEventListener('change',function(){
+                external.invoke("change_channel_device:"+channel_name+":"+this.value);
+            },
+            false
+        );
+    }
+    if (channel_name != "total") {
+        channels.insertAdjacentElement(
+            'beforeend',
+            createEffects(channel_name)
+        );
+        for (let i = 0; i < effect_names.length; i++)
+            document.getElementById(channel_name+"_"+effect_names[i])
+                .addEventListener(is_windows?'change':'input',function(){
+                        sendEffects(channel_name);
+                    },
+                    false
+                );
+        document.getElementById(channel_name+"_effects_button")
+            .addEventListener('click',function(){
+                    let effects = document.getElementById(channel_name+"_effects");
+                    effects.style.display = (effects.style.display == "none") ? "" : "none";
+                },
+                false
+            );
+    }
 }
 function createSlider(channel_name) {
     let slider = document.createElement("div");
@@ -42,10 +71,15 @@ function createSlider(channel_name) {
                     "<option value='1'>Critical</option>"+
                     "<option value='0'>Nothing</option>"+
                 "</select>"+
+                ((channel_name != "total") ?
+                    "<br><span>Device</span><br>"+
+                    "<select id='"+channel_name+"_device_selector' style='max-width:100%'></select>"
+                    : "")+
             "</div>"+
             "<h4>"+channel_name+"</h4>"+
         "</div>"+
         "<div class='w3-cell w3-cell-middle w3-rest w3-container w3-padding-small'>"+
+            "<div class='w3-tiny w3-text-grey now-playing' id='"+channel_name+"_now_playing'></div>"+
             "<input type='range' id='"+channel_name+"_slider'"+
                 "min='0' max='100' value='100'>"+
         "</div>"+
@@ -60,14 +94,105 @@ function createSlider(channel_name) {
                 "id='"+channel_name+"_play_pause_button'>"+
                 "&#x23EF;"+
             "</div>"+
+            ((channel_name != "total") ?
+                "<div class='w3-button w3-block w3-round w3-small w3-padding-small'"+
+                    "title='Effects of "+channel_name+"'"+
+                    "id='"+channel_name+"_effects_button'>"+
+                    "&#x1F39A;"+
+                "</div>"
+                : "")+
         "</div>"
     );
     return slider;
 }
+
+// Effect sliders, in the order of the change_effects message.
+let effect_names = ["lowpass", "highpass", "eq_low", "eq_mid", "eq_high", "reverb"];
+function createEffects(channel_name) {
+    let effects = document.createElement("div");
+    effects.className="w3-cell-row w3-border-bottom w3-small";
+    effects.id = channel_name+"_effects";
+    effects.style.display = "none";
+    let cell = function(name, min, max, value) {
+        return "<div class='w3-cell w3-cell-middle w3-padding-small'>"+
+            "<span id='"+channel_name+"_"+name+"_label'></span>"+
+            "<input type='range' id='"+channel_name+"_"+name+"'"+
+                "min='"+min+"' max='"+max+"' value='"+value+"'>"+
+        "</div>";
+    };
+    effects.insertAdjacentHTML(
+        'afterbegin',
+        cell("lowpass", 0, 100, 100)+
+        cell("highpass", 0, 100, 0)+
+        cell("eq_low", -24, 12, 0)+
+        cell("eq_mid", -24, 12, 0)+
+        cell("eq_high", -24, 12, 0)+
+        cell("reverb", 0, 100, 0)
+    );
+    return effects;
+}
+// Cutoff sliders go from 20Hz to 20kHz, evenly spread in octaves.
+function sliderToCutoff(value) {
+    return Math.round(20 * Math.pow(1000, value / 100));
+}
+function cutoffToSlider(cutoff) {
+    return Math.round(100 * Math.log(cutoff / 20) / Math.log(1000));
+}
+function effectValues(channel_name) {
+    let values = [];
+    for (let i = 0; i < effect_names.length; i++)
+        values.push(Number(document.getElementById(channel_name+"_"+effect_names[i]).value));
+    // The low-pass filter is off at the top of its slider, and the high-pass filter at the bottom.
+    values[0] = (values[0] < 100) ? sliderToCutoff(values[0]) : "";
+    values[1] = (values[1] > 0) ? sliderToCutoff(values[1]) : "";
+    return values;
+}
+function setEffectLabels(channel_name) {
+    let values = effectValues(channel_name);
+    let cutoff = function(value) { return (value === "") ? "off" : value+" Hz"; };
+    let gain = function(value) { return ((value > 0) ? "+" : "")+value+" dB"; };
+    let label = function(name, text) {
+        document.getElementById(channel_name+"_"+name+"_label").textContent = text;
+    };
+    label("lowpass", "Low-pass: "+cutoff(values[0]));
+    label("highpass", "High-pass: "+cutoff(values[1]));
+    label("eq_low", "Low: "+gain(values[2]));
+    label("eq_mid", "Mid: "+gain(values[3]));
+    label("eq_high", "High: "+gain(values[4]));
+    label("reverb", "Reverb: "+values[5]+"%");
+}
+function sendEffects(channel_name) {
+    setEffectLabels(channel_name);
+    external.invoke("change_effects:"+channel_name+":"+effectValues(channel_name).join(":"));
+}
+function setChannelEffects(entries) {
+    for (let i = 0; i < entries.length; i++) {
+        let channel_name = entries[i][0];
+        let settings = entries[i][1];
+        if (document.getElementById(channel_name+"_effects") == null) continue;
+        let set = function(name, value) {
+            document.getElementById(channel_name+"_"+name).value = value;
+        };
+        set("lowpass", (settings.lowpass != null) ? cutoffToSlider(settings.lowpass) : 100);
+        set("highpass", (settings.highpass != null) ? cutoffToSlider(settings.highpass) : 0);
+        set("eq_low", settings.eqLow);
+        set("eq_mid", settings.eqMid);
+        set("eq_high", settings.eqHigh);
+        set("reverb", Math.round(settings.reverb * 100));
+        setEffectLabels(channel_name);
+    }
+}
 function setSliderValue(channel_name, value) {
     let slider = document.getElementById(channel_name+"_slider");
     if (slider != null) slider.value = value;
 }
+function setNowPlaying(channel_name, title) {
+    let now_playing = document.getElementById(channel_name+"_now_playing");
+    if (now_playing != null) {
+        now_playing.textContent = (title.length > 0) ? "\u266B "+title : "";
+        now_playing.title = title;
+    }
+}
 function clearSliders() {
     while (channels.firstChild)
         channels.removeChild(channels.firstChild);
@@ -85,6 +210,127 @@ function setSliderHead(channel_name, is_paused) {
     }
 }
 
+let variables = null;
+function setVariables(entries) {
+    while (variables.firstChild)
+        variables.removeChild(variables.firstChild);
+    for (let i = 0; i < entries.length; i++) {
+        let name = entries[i][0];
+        let value = entries[i][1];
+        let tag = document.createElement("span");
+        tag.className = "w3-tag w3-round w3-margin-right " + (value != 0 ? "w3-teal" : "w3-light-grey");
+        tag.textContent = name + " = " + value;
+        variables.insertAdjacentElement('beforeend', tag);
+    }
+}
+
+let game_context = null;
+function setGameContext(entries) {
+    while (game_context.firstChild)
+        game_context.removeChild(game_context.firstChild);
+    for (let i = 0; i < entries.length; i++) {
+        let tag = document.createElement("span");
+        tag.className = "w3-tag w3-round w3-margin-right w3-blue-grey";
+        tag.textContent = entries[i][0] + ": " + entries[i][1];
+        game_context.insertAdjacentElement('beforeend', tag);
+    }
+}
+
+let cache_stats = null;
+function setCacheStats(entries) {
+    while (cache_stats.firstChild)
+        cache_stats.removeChild(cache_stats.firstChild);
+    for (let i = 0; i < entries.length; i++) {
+        let tag = document.createElement("span");
+        tag.className = "w3-tag w3-round w3-margin-right w3-light-grey";
+        tag.textContent = entries[i][0] + ": " + entries[i][1];
+        cache_stats.insertAdjacentElement('beforeend', tag);
+    }
+}
+
+let devices = null;
+let device_names = [];
+function setDevices(names, current) {
+    device_names = names;
+    while (devices.firstChild)
+        devices.removeChild(devices.firstChild);
+    devices.insertAdjacentElement('beforeend', createDeviceButton("", "Default device", current == null));
+    for (let i = 0; i < names.length; i++)
+        devices.insertAdjacentElement('beforeend', createDeviceButton(names[i], names[i], names[i] == current));
+    let selectors = channels.getElementsByTagName("select");
+    for (let i = 0; i < selectors.length; i++)
+        if (/_device_selector$/.test(selectors[i].id))
+            fillDeviceSelector(selectors[i]);
+}
+function fillDeviceSelector(selector) {
+    let value = selector.value;
+    while (selector.firstChild)
+        selector.removeChild(selector.firstChild);
+    selector.insertAdjacentElement('beforeend', createDeviceOption("", "Same as total"));
+    for (let i = 0; i < device_names.length; i++)
+        selector.insertAdjacentElement('beforeend', createDeviceOption(device_names[i], device_names[i]));
+    selector.value = value;
+}
+function createDeviceOption(name, label) {
+    let option = document.createElement("option");
+    option.value = name;
+    option.textContent = label;
+    return option;
+}
+function setChannelDevices(entries) {
+    for (let i = 0; i < entries.length; i++) {
+        let selector = document.getElementById(entries[i][0]+"_device_selector");
+        if (selector == null) continue;
+        let name = (entries[i][1] != null) ? entries[i][1] : "";
+        // A device that isn't connected is still shown, so the choice isn't lost.
+        let found = false;
+        for (let j = 0; j < selector.options.length; j++)
+            if (selector.options[j].value == name) found = true;
+        if (!found)
+            selector.insertAdjacentElement('beforeend', createDeviceOption(name, name+" (missing)"));
+        selector.value = name;
+    }
+}
+function deviceLost(lost, current) {
+    let text = (current != null)
+        ? "Lost "+lost+", now playing on "+current+"."
+        : "Lost "+lost+", waiting for a device.";
+    let escaped = document.createElement("span");
+    escaped.textContent = text;
+    addAlert("device_lost", "orange", "&#x26A0; "+escaped.innerHTML);
+}
+function createDeviceButton(name, label, is_current) {
+    let button = document.createElement("button");
+    button.className = "w3-bar-item w3-button";
+    button.textContent = (is_current ? "\u2714 " : "") + label;
+    button.addEventListener('click', function(){
+            external.invoke("change_device:"+name);
+        },
+        false
+    );
+    return button;
+}
+
+let stereo_width = null;
+let stereo_mono = null;
+let stereo_swap = null;
+function setStereo(width, mono, swap) {
+    stereo_width.value = width;
+    stereo_mono.checked = mono;
+    stereo_swap.checked = swap;
+    document.getElementById('stereo_width_label').textContent = "Width: "+width+"%";
+}
+function sendStereo() {
+    external.invoke("change_stereo:"+stereo_width.value+":"+stereo_mono.checked+":"+stereo_swap.checked);
+}
+
+let limiter_meter = null;
+function setLimiterReduction(db) {
+    limiter_meter.style.display = "";
+    limiter_meter.textContent = "Limiter: -"+db.toFixed(1)+" dB";
+    limiter_meter.style.color = (db > 0) ? "orange" : "";
+}
+
 let alerts_footer = null;
 let alerts = null;
 function addAlert(name, color, text) {
@@ -156,7 +402,18 @@ function thresholdSelect(channel_name, value) {
 
 function main() {
     channels = document.getElementById('channels');
+    variables = document.getElementById('variables');
+    game_context = document.getElementById('game_context');
+    devices = document.getElementById('devices');
+    cache_stats = document.getElementById('cache_stats');
     is_windows = /MSIE|Trident|Edge/.test(window.navigator.userAgent);
+    stereo_width = document.getElementById('stereo_width');
+    stereo_mono = document.getElementById('stereo_mono');
+    stereo_swap = document.getElementById('stereo_swap');
+    stereo_width.addEventListener(is_windows?'change':'input', sendStereo, false);
+    stereo_mono.addEventListener('change', sendStereo, false);
+    stereo_swap.addEventListener('change', sendStereo, false);
+    limiter_meter = document.getElementById('limiter_meter');
     alerts_footer = document.getElementById('alerts');
     error_footer = document.getElementById('errors');
     alerts = new Map();